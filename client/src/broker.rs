@@ -1,5 +1,14 @@
-use convos::{decode_server_question, encode_client_question, ClientQuestion};
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use convos::{
+  decode_server_question, encode_client_question, state::State, Attachment, ClientQuestion,
+  Moderation, ProfileChange, Reaction, SearchFilter, Target, UnreadCount,
+};
+use sha2::{Digest, Sha256};
 use tokio::{
+  fs,
   io::{AsyncReadExt, AsyncWriteExt},
   net::{
     tcp::{OwnedReadHalf, OwnedWriteHalf},
@@ -10,8 +19,13 @@ use tokio::{
 };
 
 pub struct BrokerHandle {
-  pub from_broker: mpsc::Receiver<String>,
+  pub from_broker: mpsc::Receiver<Event>,
   pub to_broker: mpsc::Sender<String>,
+
+  // whether the user is typing a message (not a command) into the input box
+  pub is_typing: watch::Sender<bool>,
+  // names of everyone typing into the current room or dm
+  pub typing: watch::Receiver<Vec<String>>,
 }
 
 struct Workers {
@@ -23,11 +37,112 @@ struct Workers {
 /// mediates the conversion of inputted commands to ClientQuestions,
 /// and the communictaions between the client interface and the server
 pub struct Broker {
-  // the uid of this connection and current surver
-  my_id: u64,
-  to_handle: mpsc::Sender<String>,
+  // where the current connection is in its lifecycle,
+  // Closing whenever there is no connection at all
+  state: State,
+  // the name last signed in with, only meaningful while authenticated
+  username: Option<String>,
+  // the name asked to be renamed to, which becomes the username once the server agrees
+  renaming: Option<String>,
+  // the last recovery codes shown, kept around only so /savecodes can write them out
+  recovery_codes: Option<Vec<String>>,
+  to_handle: mpsc::Sender<Event>,
   from_handle: mpsc::Receiver<String>,
   workers: Option<Workers>,
+
+  // where plain messages go, the last room joined or user dm'd
+  target: Option<Target>,
+  // names of the rooms joined during this connection, for display
+  rooms: HashMap<u64, String>,
+  // the oldest message loaded so far in each target, where /history picks up from
+  oldest: HashMap<Target, u64>,
+  // where each thread opened so far is, so replies can be sent there from anywhere
+  threads: HashMap<u64, Target>,
+  // how far behind the user is in each of their rooms
+  unread: HashMap<u64, UnreadCount>,
+  // the last search asked, so /more can ask for its next page
+  search: Option<(String, SearchFilter, u32)>,
+
+  // the file being uploaded, one at a time
+  upload: Option<OutgoingUpload>,
+  // finished uploads waiting to go out with the next message
+  attachments: Vec<u64>,
+  // names of the attachments seen so far, to save downloads under
+  attachment_names: HashMap<u64, String>,
+  // downloads in progress, by attachment id
+  downloads: HashMap<u64, Vec<u8>>,
+
+  is_typing: watch::Receiver<bool>,
+  // everyone typing anywhere, by uid
+  typing: HashMap<Target, HashMap<u64, String>>,
+  to_typing: watch::Sender<Vec<String>>,
+}
+
+struct OutgoingUpload {
+  // given out by the server once it is ready for chunks
+  id: Option<u64>,
+  name: String,
+  data: Vec<u8>,
+  // how much of it the server has acknowledged
+  received: u64,
+}
+
+/// broker->interface update
+#[derive(Debug)]
+pub enum Event {
+  Line(String),
+  // a chat message, which may later be edited or deleted in place
+  Message {
+    id: u64,
+    prefix: String,
+    content: String,
+    edited: bool,
+    deleted: bool,
+    reactions: Vec<Reaction>,
+    reply_to: Option<u64>,
+    replies: u64,
+    mentions_me: bool,
+    attachments: Vec<Attachment>,
+  },
+  Edited {
+    id: u64,
+    content: String,
+  },
+  Deleted {
+    id: u64,
+  },
+  // someone replied in the thread under a message
+  Replied {
+    id: u64,
+  },
+  // a reply in the thread under a message was deleted
+  ReplyDeleted {
+    id: u64,
+  },
+  // a thread was loaded and should be shown
+  Thread {
+    root: u64,
+  },
+  // the unread badge for a room
+  Unread {
+    room: u64,
+    name: String,
+    unread: u64,
+    mentions: u64,
+  },
+  // the room is gone from the user's list, along with its badge
+  Left {
+    room: u64,
+  },
+  // where the "new messages" divider goes, after the last message read
+  Divider {
+    after: u64,
+  },
+  // the new totals for one emoji on a message
+  Reacted {
+    id: u64,
+    reaction: Reaction,
+  },
 }
 
 /// user->broker command
@@ -35,13 +150,119 @@ pub enum Command {
   Connect(String),
   Disconnect,
 
-  SignUp { name: String, password: String },
-  SignIn { name: String, password: String },
+  SignUp {
+    name: String,
+    password: String,
+    invite: Option<String>,
+  },
+  SignIn {
+    name: String,
+    password: String,
+  },
+  // signs up as the guest this connection is, and stays signed in
+  Upgrade {
+    name: String,
+    password: String,
+    invite: Option<String>,
+  },
+  Recover {
+    name: String,
+    code: String,
+    password: String,
+  },
+  NewRecoveryCodes(String),
+  CreateInvite {
+    uses: Option<u32>,
+    expires_in: Option<u64>,
+  },
+  // answers a two-factor challenge
+  SecondFactor(String),
+  EnableTwoFactor(String),
+  ConfirmTwoFactor(String),
+  DisableTwoFactor(String),
+  // to the given file, or recovery-codes.txt
+  SaveRecoveryCodes(Option<String>),
 
   Ping,
   WhoAmI,
+  // a uid, or a username
+  WhoIs(String),
+  UpdateProfile(ProfileChange),
+  Directory {
+    query: String,
+    fuzzy: bool,
+  },
+
+  NumConnected,
+  PresenceOf(u64),
+  Watch(u64),
+  Unwatch(u64),
+  SetAway(bool),
+
+  Sessions,
+  Kick(u64),
+  ChangePassword {
+    old: String,
+    new: String,
+  },
+  Rename(String),
+  // the username again, as confirmation, and the password
+  DeleteAccount {
+    confirm: String,
+    password: String,
+  },
+
+  Join(String),
+  Leave,
+  Direct(u64),
 
   Message(String),
+  Reply {
+    id: u64,
+    content: String,
+  },
+  Thread(u64),
+  Search {
+    query: String,
+    // a room by name, the broker knows which id that is
+    room: Option<String>,
+    author: Option<u64>,
+    after: Option<u64>,
+    before: Option<u64>,
+  },
+  MoreResults,
+  Upload(String),
+  Download(u64),
+  Edit {
+    id: u64,
+    content: String,
+  },
+  Delete(u64),
+  History,
+  React {
+    id: u64,
+    emoji: String,
+  },
+  Unreact {
+    id: u64,
+    emoji: String,
+  },
+
+  // in the current room
+  Moderate {
+    id: u64,
+    action: Moderation,
+    reason: Option<String>,
+  },
+  // in the current room, or server-wide
+  SetRole {
+    server: bool,
+    id: u64,
+    role: Option<String>,
+  },
+  AuditLog,
+  // lets guests into the current room, or stops letting them in
+  AllowGuests(bool),
 
   Unknown,
   Error(String),
@@ -50,8 +271,284 @@ pub enum Command {
 mod command_parsing {
   use logos::Logos;
 
+  use convos::{Moderation, ProfileChange};
+
   use super::Command;
 
+  // seconds since the unix epoch at the start of a YYYY-MM-DD day, in UTC
+  fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+      return None;
+    }
+
+    // days from civil, shifted so the year starts in march and leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    u64::try_from(days * 86400).ok()
+  }
+
+  // /search [in:room] [from:uid] [after:YYYY-MM-DD] [before:YYYY-MM-DD] query...
+  fn parse_search(args: &str) -> Command {
+    let mut query = vec![];
+    let (mut room, mut author, mut after, mut before) = (None, None, None, None);
+
+    for word in args.split_whitespace() {
+      let invalid = || Command::Error(format!("{} is not a valid search filter", word));
+
+      if let Some(name) = word.strip_prefix("in:") {
+        room = Some(name.trim_start_matches('#').to_owned());
+      } else if let Some(uid) = word.strip_prefix("from:") {
+        let Ok(uid) = uid.parse() else {
+          return invalid();
+        };
+        author = Some(uid);
+      } else if let Some(date) = word.strip_prefix("after:") {
+        let Some(date) = parse_date(date) else {
+          return invalid();
+        };
+        after = Some(date);
+      } else if let Some(date) = word.strip_prefix("before:") {
+        let Some(date) = parse_date(date) else {
+          return invalid();
+        };
+        before = Some(date);
+      } else {
+        query.push(word);
+      }
+    }
+
+    if query.is_empty() {
+      return Command::Error("expected something to search for".to_owned());
+    }
+
+    Command::Search {
+      query: query.join(" "),
+      room,
+      author,
+      after,
+      before,
+    }
+  }
+
+  // seconds in something like 45s, 30m, 12h, 7d or 2w
+  fn parse_duration(duration: &str) -> Option<u64> {
+    let unit = match duration.chars().last()? {
+      's' => 1,
+      'm' => 60,
+      'h' => 60 * 60,
+      'd' => 24 * 60 * 60,
+      'w' => 7 * 24 * 60 * 60,
+      _ => return None,
+    };
+
+    let amount: u64 = duration[..duration.len() - 1].parse().ok()?;
+    amount.checked_mul(unit)
+  }
+
+  // /profile <displayname|bio|pronouns|status> [text...], no text clears it
+  fn parse_profile(args: &str) -> Command {
+    let args = args.trim();
+    let (field, text) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let text = Some(text.trim().to_owned()).filter(|text| !text.is_empty());
+
+    Command::UpdateProfile(match field {
+      "displayname" => ProfileChange::DisplayName(text),
+      "bio" => ProfileChange::Bio(text),
+      "pronouns" => ProfileChange::Pronouns(text),
+      "status" => ProfileChange::Status(text),
+      _ => {
+        return Command::Error(
+          "expected displayname, bio, pronouns or status after profile command".to_owned(),
+        )
+      }
+    })
+  }
+
+  // /mod <kick|ban|unban|mute|unmute> uid [duration] [reason...]
+  fn parse_moderation(args: &str) -> Command {
+    let mut words = args.split_whitespace().peekable();
+
+    let Some(action) = words.next() else {
+      return Command::Error("expected kick, ban, unban, mute or unmute after mod".to_owned());
+    };
+    let Some(id) = words.next() else {
+      return Command::Error("expected an id after mod command".to_owned());
+    };
+    let Ok(id) = id.parse() else {
+      return Command::Error(format!("{} is not a valid id", id));
+    };
+
+    // bans and mutes last for good unless given a duration
+    let mut duration = || {
+      let duration = parse_duration(words.peek()?)?;
+      words.next();
+      Some(duration)
+    };
+
+    let action = match action {
+      "kick" => Moderation::Kick,
+      "ban" => Moderation::Ban {
+        duration: duration(),
+      },
+      "unban" => Moderation::Unban,
+      "mute" => Moderation::Mute {
+        duration: duration(),
+      },
+      "unmute" => Moderation::Unmute,
+      other => return Command::Error(format!("{} is not something mod can do", other)),
+    };
+
+    let reason = words.collect::<Vec<_>>().join(" ");
+    Command::Moderate {
+      id,
+      action,
+      reason: (!reason.is_empty()).then_some(reason),
+    }
+  }
+
+  // /role uid <name|none>, and /serverrole the same
+  fn parse_role(lex: &mut logos::Lexer<Token>, command: &str) -> Command {
+    let id = match expect_id(lex, command) {
+      Ok(id) => id,
+      Err(e) => return e,
+    };
+
+    let role = match lex.remainder().trim() {
+      "" => return Command::Error(format!("expected a role or none after {command} command")),
+      "none" => None,
+      role => Some(role.to_owned()),
+    };
+
+    Command::SetRole {
+      server: command == "serverrole",
+      id,
+      role,
+    }
+  }
+
+  // a username and then a password, which is everything after it so passphrases work
+  // neither goes through the lexer, which only knows ascii
+  fn parse_credentials(args: &str, command: &str) -> Result<(String, String), Command> {
+    let args = args.trim_start();
+    let Some((name, password)) = args.split_once(char::is_whitespace) else {
+      return Err(Command::Error(match args {
+        "" => format!("expected a username after {command} command"),
+        _ => format!("expected a password after {command} command"),
+      }));
+    };
+
+    // only the one space after the name goes, spaces are as much a part of a password as anything
+    Ok((name.to_owned(), password.to_owned()))
+  }
+
+  // /recover name code password..., the code can be written with or without its dash
+  fn parse_recovery(args: &str) -> Command {
+    let (name, rest) = match parse_credentials(args, "recover") {
+      Ok(credentials) => credentials,
+      Err(e) => return e,
+    };
+
+    // as with the name, only the one space after the code goes
+    let Some((code, password)) = rest.trim_start().split_once(char::is_whitespace) else {
+      return Command::Error("expected a recovery code and then a new password".to_owned());
+    };
+
+    Command::Recover {
+      name,
+      code: code.to_owned(),
+      password: password.to_owned(),
+    }
+  }
+
+  // /signup [invite:code] name password..., and /upgrade the same
+  fn parse_signup(args: &str, command: &str) -> Command {
+    let args = args.trim_start();
+    let (invite, args) = match args.strip_prefix("invite:") {
+      Some(rest) => {
+        let (code, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        (Some(code.to_owned()), rest)
+      }
+      None => (None, args),
+    };
+
+    match parse_credentials(args, command) {
+      Ok((name, password)) if command == "upgrade" => Command::Upgrade {
+        name,
+        password,
+        invite,
+      },
+      Ok((name, password)) => Command::SignUp {
+        name,
+        password,
+        invite,
+      },
+      Err(e) => e,
+    }
+  }
+
+  // /invite [uses|unlimited] [duration], single-use and for good unless told otherwise
+  fn parse_invite(args: &str) -> Command {
+    let (mut uses, mut expires_in) = (Some(1), None);
+
+    for word in args.split_whitespace() {
+      if word == "unlimited" {
+        uses = None;
+      } else if let Ok(count @ 1..) = word.parse() {
+        uses = Some(count);
+      } else if let Some(duration) = parse_duration(word) {
+        expires_in = Some(duration);
+      } else {
+        return Command::Error(format!(
+          "{} is neither a number of uses nor a duration",
+          word
+        ));
+      }
+    }
+
+    Command::CreateInvite { uses, expires_in }
+  }
+
+  // /2fa <enable password|confirm code|disable password>
+  fn parse_two_factor(args: &str) -> Command {
+    let args = args.trim();
+    let (action, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let rest = rest.trim().to_owned();
+
+    let (command, expected): (fn(String) -> Command, _) = match action {
+      "enable" => (Command::EnableTwoFactor, "a password"),
+      "confirm" => (Command::ConfirmTwoFactor, "a code"),
+      "disable" => (Command::DisableTwoFactor, "a password"),
+      "" => return Command::Error("expected enable, confirm or disable after 2fa".to_owned()),
+      other => return Command::Error(format!("{} is not something 2fa can do", other)),
+    };
+
+    match rest.is_empty() {
+      true => Command::Error(format!("expected {expected} after 2fa {action}")),
+      false => command(rest),
+    }
+  }
+
+  // pulls a numeric id off of the lexer, for the commands that take one
+  fn expect_id(lex: &mut logos::Lexer<Token>, command: &str) -> Result<u64, Command> {
+    if lex.next().is_none() {
+      return Err(Command::Error(format!(
+        "expected an id after {command} command"
+      )));
+    }
+
+    lex
+      .slice()
+      .parse()
+      .map_err(|_| Command::Error(format!("{} is not a valid id", lex.slice())))
+  }
+
   #[derive(Logos, Debug, PartialEq)]
   enum Token {
     #[regex(r"[a-zA-Z0-9\.:]+")]
@@ -75,43 +572,133 @@ mod command_parsing {
       match lex.slice() {
         "ping" => Command::Ping,
         "whoami" => Command::WhoAmI,
-        "connect" => {
-          if lex.next().is_none() {
-            return Command::Error("Expected an address after /connect".to_owned());
-          }
-
-          Command::Connect(lex.slice().to_owned())
-        }
-        "signin" => {
-          if lex.next().is_none() {
-            return Command::Error("expected a username after signin command".to_owned());
-          }
-
-          let name = lex.slice().to_owned();
-
+        "whois" => match lex.remainder().trim() {
+          "" => Command::Error("expected a uid or a username after whois command".to_owned()),
+          who => Command::WhoIs(who.to_owned()),
+        },
+        "profile" => parse_profile(lex.remainder()),
+        "avatar" => match lex.remainder().trim() {
+          "none" => Command::UpdateProfile(ProfileChange::Avatar(None)),
+          id => match id.parse() {
+            Ok(id) => Command::UpdateProfile(ProfileChange::Avatar(Some(id))),
+            Err(_) => Command::Error("expected an attachment id or none after avatar".to_owned()),
+          },
+        },
+        // /users for names starting with something, /find for anything like it
+        "users" | "find" => match lex.remainder().trim() {
+          "" => Command::Error("expected a name to look for".to_owned()),
+          query => Command::Directory {
+            query: query.to_owned(),
+            fuzzy: lex.slice() == "find",
+          },
+        },
+        "online" => Command::NumConnected,
+        "away" => Command::SetAway(true),
+        "back" => Command::SetAway(false),
+        "presence" => expect_id(&mut lex, "presence").map_or_else(|e| e, Command::PresenceOf),
+        "watch" => expect_id(&mut lex, "watch").map_or_else(|e| e, Command::Watch),
+        "unwatch" => expect_id(&mut lex, "unwatch").map_or_else(|e| e, Command::Unwatch),
+        "sessions" => Command::Sessions,
+        "kick" => expect_id(&mut lex, "kick").map_or_else(|e| e, Command::Kick),
+        "join" => {
           if lex.next().is_none() {
-            return Command::Error("expected a password after signin command".to_owned());
+            return Command::Error("expected a room name after join command".to_owned());
           }
 
-          let password = lex.slice().to_owned();
-
-          Command::SignIn { name, password }
+          Command::Join(lex.slice().to_owned())
         }
-        "signup" => {
-          if lex.next().is_none() {
-            return Command::Error("expected a username after signup command".to_owned());
-          }
-
-          let name = lex.slice().to_owned();
-
+        "leave" => Command::Leave,
+        "dm" => expect_id(&mut lex, "dm").map_or_else(|e| e, Command::Direct),
+        "edit" => match expect_id(&mut lex, "edit") {
+          // the new content is everything after the id, verbatim
+          Ok(id) => Command::Edit {
+            id,
+            content: lex.remainder().trim().to_owned(),
+          },
+          Err(e) => e,
+        },
+        "delete" => expect_id(&mut lex, "delete").map_or_else(|e| e, Command::Delete),
+        "reply" => match expect_id(&mut lex, "reply") {
+          Ok(id) => Command::Reply {
+            id,
+            content: lex.remainder().trim().to_owned(),
+          },
+          Err(e) => e,
+        },
+        "thread" => expect_id(&mut lex, "thread").map_or_else(|e| e, Command::Thread),
+        "search" => parse_search(lex.remainder()),
+        "more" => Command::MoreResults,
+        "upload" => match lex.remainder().trim() {
+          "" => Command::Error("expected a file after upload command".to_owned()),
+          path => Command::Upload(path.to_owned()),
+        },
+        "download" => expect_id(&mut lex, "download").map_or_else(|e| e, Command::Download),
+        "history" => Command::History,
+        "mod" => parse_moderation(lex.remainder()),
+        "role" => parse_role(&mut lex, "role"),
+        "serverrole" => parse_role(&mut lex, "serverrole"),
+        "audit" => Command::AuditLog,
+        "guests" => match lex.remainder().trim() {
+          "on" => Command::AllowGuests(true),
+          "off" => Command::AllowGuests(false),
+          _ => Command::Error("expected on or off after guests command".to_owned()),
+        },
+        // emoji aren't identifiers, so take whatever comes after the id
+        "react" => match expect_id(&mut lex, "react") {
+          Ok(id) => Command::React {
+            id,
+            emoji: lex.remainder().trim().to_owned(),
+          },
+          Err(e) => e,
+        },
+        "unreact" => match expect_id(&mut lex, "unreact") {
+          Ok(id) => Command::Unreact {
+            id,
+            emoji: lex.remainder().trim().to_owned(),
+          },
+          Err(e) => e,
+        },
+        "connect" => {
           if lex.next().is_none() {
-            return Command::Error("expected a password after signup command".to_owned());
+            return Command::Error("Expected an address after /connect".to_owned());
           }
 
-          let password = lex.slice().to_owned();
-
-          Command::SignUp { name, password }
+          Command::Connect(lex.slice().to_owned())
         }
+        "signin" => match parse_credentials(lex.remainder(), "signin") {
+          Ok((name, password)) => Command::SignIn { name, password },
+          Err(e) => e,
+        },
+        "signup" => parse_signup(lex.remainder(), "signup"),
+        "upgrade" => parse_signup(lex.remainder(), "upgrade"),
+        "invite" => parse_invite(lex.remainder()),
+        // the old password can't have spaces in it, the new one can
+        "passwd" => match parse_credentials(lex.remainder(), "passwd") {
+          Ok((old, new)) => Command::ChangePassword { old, new },
+          Err(e) => e,
+        },
+        "rename" => match lex.remainder().trim() {
+          "" => Command::Error("expected a username after rename command".to_owned()),
+          name => Command::Rename(name.to_owned()),
+        },
+        "deleteaccount" => match parse_credentials(lex.remainder(), "deleteaccount") {
+          Ok((confirm, password)) => Command::DeleteAccount { confirm, password },
+          Err(e) => e,
+        },
+        "recover" => parse_recovery(lex.remainder()),
+        "recoverycodes" => match lex.remainder().trim() {
+          "" => Command::Error("expected a password after recoverycodes command".to_owned()),
+          password => Command::NewRecoveryCodes(password.to_owned()),
+        },
+        "savecodes" => match lex.remainder().trim() {
+          "" => Command::SaveRecoveryCodes(None),
+          path => Command::SaveRecoveryCodes(Some(path.to_owned())),
+        },
+        "code" => match lex.remainder().trim() {
+          "" => Command::Error("expected a code after code command".to_owned()),
+          code => Command::SecondFactor(code.to_owned()),
+        },
+        "2fa" => parse_two_factor(lex.remainder()),
         "disconnect" => Command::Disconnect,
         _ => Command::Unknown,
       }
@@ -120,17 +707,35 @@ mod command_parsing {
 }
 
 impl Broker {
-  pub fn new() -> BrokerHandle {
+  pub fn spawn() -> BrokerHandle {
     let (th_tx, th_rx) = mpsc::channel(256);
     let (fh_tx, fh_rx) = mpsc::channel(256);
+    let (it_tx, it_rx) = watch::channel(false);
+    let (ty_tx, ty_rx) = watch::channel(vec![]);
 
     std::thread::spawn(|| {
       tokio::runtime::Runtime::new().unwrap().block_on(
         Self {
-          my_id: 0,
+          state: State::Closing,
+          username: None,
+          renaming: None,
+          recovery_codes: None,
           to_handle: th_tx,
           from_handle: fh_rx,
           workers: None,
+          target: None,
+          rooms: HashMap::new(),
+          oldest: HashMap::new(),
+          threads: HashMap::new(),
+          unread: HashMap::new(),
+          search: None,
+          upload: None,
+          attachments: vec![],
+          attachment_names: HashMap::new(),
+          downloads: HashMap::new(),
+          is_typing: it_rx,
+          typing: HashMap::new(),
+          to_typing: ty_tx,
         }
         .logic(),
       );
@@ -139,16 +744,33 @@ impl Broker {
     BrokerHandle {
       from_broker: th_rx,
       to_broker: fh_tx,
+      is_typing: it_tx,
+      typing: ty_rx,
     }
   }
 
   // could probably make this better, just would need to do some redesigning
   async fn logic(mut self) {
+    // the server takes a typing indicator down by itself if it isn't refreshed in time
+    let mut typing_refresh = tokio::time::interval(Duration::from_secs(3));
+
     loop {
       if let Some(Workers { from_server, .. }) = &mut self.workers {
         select! {
-          Some(msg) = from_server.recv() => self.handle_incoming_from_server(msg).await,
+          msg = from_server.recv() => match msg {
+            Some(msg) => self.handle_incoming_from_server(msg).await,
+            None => {
+              self.disconnect().await;
+              self.print("Server closed the connection".to_owned()).await;
+            }
+          },
           Some(msg) = self.from_handle.recv() => self.handle_incoming_from_user(msg).await,
+          Ok(()) = self.is_typing.changed() => self.send_typing().await,
+          _ = typing_refresh.tick() => {
+            if *self.is_typing.borrow() {
+              self.send_typing().await;
+            }
+          }
         };
       } else {
         let i = self.from_handle.recv().await.unwrap();
@@ -159,34 +781,795 @@ impl Broker {
 
   async fn disconnect(&mut self) {
     if let Some(workers) = &mut self.workers {
-      workers.kill.send(()).unwrap();
+      // the workers may already be gone if the server hung up on us
+      let _ = workers.kill.send(());
 
       workers.to_server.closed().await;
       workers.from_server.close();
     }
 
     self.workers = None;
+    self.state.close();
+    self.forget_account().await;
+  }
+
+  // drops everything that belonged to whoever was signed in
+  async fn forget_account(&mut self) {
+    self.target = None;
+    self.rooms.clear();
+    self.oldest.clear();
+    self.threads.clear();
+    self.upload = None;
+    self.attachments.clear();
+    self.attachment_names.clear();
+    self.downloads.clear();
+    self.typing.clear();
+    self.publish_typing();
+
+    for (room, _) in self.unread.drain() {
+      self.to_handle.send(Event::Left { room }).await.unwrap();
+    }
+  }
+
+  async fn publish_unread(&self, room: u64) {
+    let Some(count) = self.unread.get(&room) else {
+      return;
+    };
+
+    self
+      .to_handle
+      .send(Event::Unread {
+        room,
+        name: count.name.clone(),
+        unread: count.unread,
+        mentions: count.mentions,
+      })
+      .await
+      .unwrap();
+  }
+
+  // a message came in, either the user is looking at its room or it's one more unread there
+  async fn arrived(&mut self, id: u64, target: Target, mentions_me: bool, mine: bool) {
+    let Target::Room(room) = target else {
+      return;
+    };
+
+    if self.target == Some(target) {
+      self.ask(ClientQuestion::MarkRead { room, id }).await;
+    } else if let Some(count) = self.unread.get_mut(&room).filter(|_| !mine) {
+      count.unread += 1;
+      count.mentions += mentions_me as u64;
+      self.publish_unread(room).await;
+    }
+  }
+
+  // lets the current target know whether the user is typing into it
+  async fn send_typing(&mut self) {
+    let Some(target) = self.target else {
+      return;
+    };
+
+    let question = if *self.is_typing.borrow() {
+      ClientQuestion::TypingStarted { target }
+    } else {
+      ClientQuestion::TypingStopped { target }
+    };
+
+    // typing indicators are best effort, no need to complain about them
+    if self.state.check(&question).is_ok() {
+      self.ask(question).await;
+    }
+  }
+
+  // hands the interface the names of everyone typing into the current target
+  fn publish_typing(&self) {
+    let names = self
+      .target
+      .and_then(|target| self.typing.get(&target))
+      .map(|typing| typing.values().cloned().collect())
+      .unwrap_or_default();
+
+    let _ = self.to_typing.send(names);
+  }
+
+  // a unix time as YYYY-MM-DD HH:MM, in UTC
+  fn format_time(secs: u64) -> String {
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // civil from days, the inverse of how /search reads dates
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+      (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+      "{:04}-{:02}-{:02} {:02}:{:02}",
+      year,
+      month,
+      day,
+      secs / 3600,
+      secs / 60 % 60
+    )
+  }
+
+  fn describe(&self, target: Target) -> String {
+    match target {
+      Target::Room(room) => match self.rooms.get(&room) {
+        Some(name) => format!("#{}", name),
+        None => format!("#{}", room),
+      },
+      Target::Direct(uid) => format!("dm {}", uid),
+    }
+  }
+
+  // asks for the messages in a target from before the oldest one loaded so far
+  async fn load_history(&mut self, target: Target) {
+    let before = self.oldest.get(&target).copied();
+    self.ask(ClientQuestion::History { target, before }).await;
+  }
+
+  fn mentions_me(&self, content: &str) -> bool {
+    match &self.username {
+      Some(username) if self.state.is_authenticated() => {
+        convos::mentions(content).contains(&username.as_str())
+      }
+      _ => false,
+    }
+  }
+
+  // hands a message from a history batch or thread to the interface
+  async fn show_message(&mut self, target: Target, message: convos::Message) {
+    let prefix = format!("[{}] {}", self.describe(target), message.name);
+    let mentions_me = self.mentions_me(&message.content);
+    self.remember_attachments(&message.attachments);
+    self
+      .to_handle
+      .send(Event::Message {
+        id: message.id,
+        prefix,
+        content: message.content,
+        edited: message.edited,
+        deleted: message.deleted,
+        reactions: message.reactions,
+        reply_to: message.reply_to,
+        replies: message.replies,
+        mentions_me,
+        attachments: message.attachments,
+      })
+      .await
+      .unwrap();
+  }
+
+  fn remember_attachments(&mut self, attachments: &[Attachment]) {
+    for attachment in attachments {
+      self
+        .attachment_names
+        .insert(attachment.id, attachment.name.clone());
+    }
+  }
+
+  // sends the next piece of the current upload, or finishes it off once it's all there
+  async fn continue_upload(&mut self, received: u64) {
+    let Some(OutgoingUpload {
+      id: Some(upload),
+      data,
+      ..
+    }) = &self.upload
+    else {
+      return;
+    };
+    let upload = *upload;
+
+    let offset = received as usize;
+    if offset >= data.len() {
+      self.ask(ClientQuestion::FinishUpload { upload }).await;
+      return;
+    }
+
+    let end = data.len().min(offset + convos::CHUNK_SIZE);
+    let data = BASE64.encode(&data[offset..end]);
+    self
+      .ask(ClientQuestion::UploadChunk {
+        upload,
+        offset: received,
+        data,
+      })
+      .await;
+  }
+
+  async fn print(&self, line: impl Into<String>) {
+    self.to_handle.send(Event::Line(line.into())).await.unwrap();
+  }
+
+  // sends a question to the server, so long as the connection state allows asking it
+  async fn ask(&mut self, question: ClientQuestion) -> bool {
+    if let Err(e) = self.state.check(&question) {
+      self.print(format!("Error: {}", e)).await;
+      return false;
+    }
+
+    let Some(workers) = &self.workers else {
+      return false;
+    };
+
+    workers
+      .to_server
+      .send(encode_client_question(question).unwrap())
+      .await
+      .unwrap();
+
+    true
   }
 
   async fn handle_incoming_from_server(&mut self, msg: Vec<u8>) {
     let tell = decode_server_question(msg).unwrap();
+    self.state.on_tell(&tell);
 
     match tell {
-      convos::ServerTell::NumConnected => todo!(),
-      convos::ServerTell::Who { id, name } => {
+      convos::ServerTell::NumConnected { count } => {
+        self.print(format!("{} connected", count)).await
+      }
+      convos::ServerTell::Presence { id, presence } => {
+        self.print(format!("{} is {}", id, presence)).await
+      }
+      convos::ServerTell::Sessions { sessions } => {
+        for session in sessions {
+          self
+            .print(format!(
+              "Session {} from {} since {}{}{}",
+              session.id,
+              session.address,
+              session.connected_at,
+              if session.away { " (away)" } else { "" },
+              if session.current { " (this one)" } else { "" },
+            ))
+            .await;
+        }
+      }
+      convos::ServerTell::Moderated {
+        room,
+        action,
+        by,
+        reason,
+      } => {
+        let mut line = format!(
+          "You were {} in {} by {}",
+          action,
+          self.describe(Target::Room(room)),
+          by
+        );
+        if let Some(reason) = reason {
+          line = format!("{}: {}", line, reason);
+        }
+        self.print(line).await;
+
+        // there is no staying in a room you were thrown out of
+        if let Moderation::Kick | Moderation::Ban { .. } = action {
+          if self.target == Some(Target::Room(room)) {
+            self.target = None;
+            self.publish_typing();
+          }
+
+          self.unread.remove(&room);
+          self.to_handle.send(Event::Left { room }).await.unwrap();
+        }
+      }
+      convos::ServerTell::TwoFactorChallenge => {
+        self
+          .print("Password accepted, now /code with a code from your authenticator app, or a recovery code".to_owned())
+          .await;
+      }
+      convos::ServerTell::TwoFactorEnrolment { uri } => {
+        self
+          .print(
+            "Add this to your authenticator app, then /2fa confirm with its first code:".to_owned(),
+          )
+          .await;
+        self.print(format!("  {}", uri)).await;
+      }
+      convos::ServerTell::Invite {
+        code,
+        uses,
+        expires_at,
+      } => {
+        let uses = match uses {
+          Some(1) => "one sign-up".to_owned(),
+          Some(uses) => format!("{} sign-ups", uses),
+          None => "any number of sign-ups".to_owned(),
+        };
+        let until = match expires_at {
+          Some(at) => format!("until {}", Self::format_time(at)),
+          None => "for good".to_owned(),
+        };
+        self
+          .print(format!("Invite code {}, good for {} {}", code, uses, until))
+          .await;
+      }
+      convos::ServerTell::RecoveryCodes { codes } => {
+        self
+          .print(
+            "Recovery codes, each signs in once with /recover if the password is lost:".to_owned(),
+          )
+          .await;
+        for code in &codes {
+          self.print(format!("  {}", code)).await;
+        }
+        self
+          .print(
+            "These won't be shown again, keep them somewhere safe or /savecodes [file]".to_owned(),
+          )
+          .await;
+
+        self.recovery_codes = Some(codes);
+      }
+      convos::ServerTell::FailedSignIns { attempts } => {
+        if attempts.is_empty() {
+          return;
+        }
+
+        let line = match attempts.len() {
+          1 => "1 failed sign-in since you last signed in:".to_owned(),
+          n => format!("{} failed sign-ins since you last signed in:", n),
+        };
+        self.print(line).await;
+
+        for attempt in attempts {
+          let line = format!(
+            "  {} from {}",
+            Self::format_time(attempt.at),
+            attempt.address
+          );
+          self.print(line).await;
+        }
+      }
+      convos::ServerTell::RoleSet { room, id, role } => {
+        let place = match room {
+          Some(room) => format!("in {}", self.describe(Target::Room(room))),
+          None => "server-wide".to_owned(),
+        };
+        let line = match role {
+          Some(role) => format!("{} is now {} {}", id, role, place),
+          None => format!("{} no longer has a role {}", id, place),
+        };
+        self.print(line).await;
+      }
+      convos::ServerTell::AuditLog { entries } => {
+        if entries.is_empty() {
+          self
+            .print("Nothing in the moderation log.".to_owned())
+            .await;
+        }
+
+        for entry in entries {
+          let mut line = format!(
+            "{} {} {}",
+            Self::format_time(entry.at),
+            entry.actor,
+            entry.action
+          );
+          if let Some(target) = entry.target {
+            line = format!("{} {}", line, target);
+          }
+          if let Some(message) = entry.message {
+            line = format!("{} (message {})", line, message);
+          }
+          if let Some(room) = entry.room {
+            line = format!("{} in {}", line, self.describe(Target::Room(room)));
+          }
+          if let Some(reason) = entry.reason {
+            line = format!("{}: {}", line, reason);
+          }
+          self.print(line).await;
+        }
+      }
+      convos::ServerTell::Who { id, name, profile } => {
+        self.print(format!("Whois id: {} name: {}", id, name)).await;
+
+        if let Some(profile) = profile {
+          let fields = [
+            ("Display name", profile.display_name),
+            ("Pronouns", profile.pronouns),
+            ("Status", profile.status),
+            ("Bio", profile.bio),
+            (
+              "Avatar",
+              profile.avatar.map(|id| format!("attachment {id}")),
+            ),
+          ];
+          for (label, value) in fields {
+            if let Some(value) = value {
+              self.print(format!("  {}: {}", label, value)).await;
+            }
+          }
+          self
+            .print(format!(
+              "  Joined {}",
+              Self::format_time(profile.created_at)
+            ))
+            .await;
+        }
+      }
+      convos::ServerTell::Directory { query, users } => {
+        if users.is_empty() {
+          self.print(format!("Nobody found for {}", query)).await;
+        }
+        for user in users {
+          self
+            .print(match user.display_name {
+              Some(display_name) => format!("{} {} ({})", user.id, user.name, display_name),
+              None => format!("{} {}", user.id, user.name),
+            })
+            .await;
+        }
+      }
+      convos::ServerTell::Syndication {
+        id,
+        name,
+        target,
+        content,
+        reply_to,
+        attachments,
+        ..
+      } => {
+        let prefix = format!("[{}] {}", self.describe(target), name);
+        let mentions_me = self.mentions_me(&content);
+        self.remember_attachments(&attachments);
+        self
+          .to_handle
+          .send(Event::Message {
+            id,
+            prefix,
+            content,
+            edited: false,
+            deleted: false,
+            reactions: vec![],
+            reply_to,
+            replies: 0,
+            mentions_me,
+            attachments,
+          })
+          .await
+          .unwrap();
+
+        if let Some(root) = reply_to {
+          self
+            .to_handle
+            .send(Event::Replied { id: root })
+            .await
+            .unwrap();
+        }
+
+        let mine = self.username.as_ref() == Some(&name);
+        self.arrived(id, target, mentions_me, mine).await;
+      }
+      convos::ServerTell::Edited { id, content, .. } => {
+        self
+          .to_handle
+          .send(Event::Edited { id, content })
+          .await
+          .unwrap();
+      }
+      convos::ServerTell::Deleted { id, reply_to, .. } => {
+        self.to_handle.send(Event::Deleted { id }).await.unwrap();
+
+        if let Some(root) = reply_to {
+          self
+            .to_handle
+            .send(Event::ReplyDeleted { id: root })
+            .await
+            .unwrap();
+        }
+      }
+      convos::ServerTell::Joined { room, name } => {
+        self.rooms.insert(room, name.clone());
+        self.unread.entry(room).or_insert(UnreadCount {
+          room,
+          name,
+          last_read: None,
+          unread: 0,
+          mentions: 0,
+        });
+        self.publish_unread(room).await;
+
+        self.target = Some(Target::Room(room));
+        self.publish_typing();
+
+        let line = format!("Joined {}", self.describe(Target::Room(room)));
+        self.print(line).await;
+        self.load_history(Target::Room(room)).await;
+      }
+      convos::ServerTell::History { target, messages } => {
+        let Some(first) = messages.first() else {
+          self
+            .print(format!("No earlier messages in {}", self.describe(target)))
+            .await;
+          return;
+        };
+        let first_batch = self.oldest.insert(target, first.id).is_none();
+        let newest = messages.last().map(|message| message.id);
+
+        let line = format!("Earlier in {}:", self.describe(target));
+        self.print(line).await;
+
+        for message in messages {
+          self.show_message(target, message).await;
+        }
+
+        // the newest batch is where the user picks up from, everything in it is now on screen
+        if let (true, Target::Room(room), Some(newest)) = (first_batch, target, newest) {
+          if let Some(after) = self.unread.get(&room).and_then(|count| count.last_read) {
+            if after != newest {
+              self.to_handle.send(Event::Divider { after }).await.unwrap();
+            }
+          }
+
+          if self.target == Some(target) {
+            self
+              .ask(ClientQuestion::MarkRead { room, id: newest })
+              .await;
+          }
+        }
+      }
+      convos::ServerTell::ReadMarker { room, id } => {
+        // markers only move up to the newest message on screen, so nothing is left unread
+        if let Some(count) = self.unread.get_mut(&room) {
+          count.last_read = Some(id);
+          count.unread = 0;
+          count.mentions = 0;
+        }
+        self.publish_unread(room).await;
+      }
+      convos::ServerTell::UploadReady { upload } => {
+        let Some(outgoing) = &mut self.upload else {
+          return;
+        };
+        outgoing.id = Some(upload);
+
+        let line = format!("Uploading {}…", outgoing.name);
+        self.print(line).await;
+        self.continue_upload(0).await;
+      }
+      convos::ServerTell::ChunkReceived { received, .. } => {
+        // a chunk sent twice is acknowledged twice, only carry on from the first
+        let Some(upload) = self.upload.as_mut().filter(|u| received > u.received) else {
+          return;
+        };
+        upload.received = received;
+        self.continue_upload(received).await;
+      }
+      convos::ServerTell::Uploaded { attachment } => {
+        self.upload = None;
+        self.attachments.push(attachment.id);
+        self.remember_attachments(std::slice::from_ref(&attachment));
+
+        self
+          .print(format!(
+            "Uploaded {} ({} bytes), it goes out with your next message",
+            attachment.name, attachment.size
+          ))
+          .await;
+      }
+      convos::ServerTell::AttachmentChunk {
+        id,
+        offset,
+        size,
+        data,
+      } => {
+        let Some(download) = self.downloads.get_mut(&id) else {
+          return;
+        };
+
+        match BASE64.decode(data) {
+          Ok(data) if offset == download.len() as u64 && !data.is_empty() => {
+            download.extend_from_slice(&data)
+          }
+          // an empty chunk short of the end would have us asking for the same one forever
+          Ok(data) if data.is_empty() && offset < size => {
+            self.downloads.remove(&id);
+            self
+              .print(format!("Download of {} was cut short", id))
+              .await;
+            return;
+          }
+          Ok(_) => {}
+          Err(_) => {
+            self.downloads.remove(&id);
+            self
+              .print(format!("Download of {} came back garbled", id))
+              .await;
+            return;
+          }
+        }
+
+        if (download.len() as u64) < size {
+          let offset = download.len() as u64;
+          self.ask(ClientQuestion::Download { id, offset }).await;
+          return;
+        }
+
+        let data = self.downloads.remove(&id).unwrap_or_default();
+        // only ever the name itself, never a path somebody slipped in
+        let name = self
+          .attachment_names
+          .get(&id)
+          .and_then(|name| Path::new(name).file_name())
+          .map(|name| format!("{}-{}", id, name.to_string_lossy()))
+          .unwrap_or_else(|| format!("attachment-{}", id));
+
+        match fs::write(&name, data).await {
+          Ok(()) => self.print(format!("Saved to {}", name)).await,
+          Err(e) => self.print(format!("Couldn't save {}: {}", name, e)).await,
+        }
+      }
+      convos::ServerTell::SearchResults {
+        query,
+        page,
+        results,
+        more,
+      } => {
+        if results.is_empty() {
+          self.print(format!("Nothing found for \"{}\"", query)).await;
+          return;
+        }
+
+        self
+          .print(format!("Results for \"{}\", page {}:", query, page + 1))
+          .await;
+        for result in results {
+          // no formatting in a plain line, so matches get marked up the markdown way
+          let snippet: String = result
+            .snippet
+            .iter()
+            .map(|fragment| match fragment.highlighted {
+              true => format!("**{}**", fragment.text),
+              false => fragment.text.clone(),
+            })
+            .collect();
+
+          let line = format!(
+            "[{}] {} (#{}): {}",
+            self.describe(result.target),
+            result.name,
+            result.id,
+            snippet
+          );
+          self.print(line).await;
+        }
+
+        if more {
+          self.print("/more for the next page").await;
+        }
+      }
+      convos::ServerTell::Unread { rooms } => {
+        for count in rooms {
+          let room = count.room;
+          self.rooms.insert(room, count.name.clone());
+          self.unread.insert(room, count);
+          self.publish_unread(room).await;
+        }
+      }
+      convos::ServerTell::Thread {
+        target,
+        root,
+        replies,
+      } => {
+        let id = root.id;
+        self.threads.insert(id, target);
+
+        self.show_message(target, root).await;
+        for reply in replies {
+          self.show_message(target, reply).await;
+        }
+
+        self
+          .to_handle
+          .send(Event::Thread { root: id })
+          .await
+          .unwrap();
+      }
+      convos::ServerTell::Reacted { id, reaction, .. } => {
         self
           .to_handle
-          .send(format!("Whois id: {} name: {}", id, name))
+          .send(Event::Reacted { id, reaction })
           .await
           .unwrap();
       }
-      convos::ServerTell::Syndication { from, content } => todo!(),
-      convos::ServerTell::Success(s) => self
-        .to_handle
-        .send(format!("Success: {}", s))
-        .await
-        .unwrap(),
-      convos::ServerTell::Error(x) => self.to_handle.send(format!("Error: {}", x)).await.unwrap(),
+      convos::ServerTell::TypingStarted { target, who, name } => {
+        self.typing.entry(target).or_default().insert(who, name);
+        self.publish_typing();
+      }
+      convos::ServerTell::TypingStopped { target, who } => {
+        if let Some(typing) = self.typing.get_mut(&target) {
+          typing.remove(&who);
+        }
+        self.publish_typing();
+      }
+      convos::ServerTell::Mentioned { mention } => {
+        let line = format!(
+          "{} mentioned you in {}",
+          mention.name,
+          self.describe(mention.target)
+        );
+        self.print(line).await;
+      }
+      convos::ServerTell::Mentions { mentions } => {
+        if mentions.is_empty() {
+          return;
+        }
+
+        self.print("Mentioned while you were away:").await;
+        for mention in mentions {
+          let prefix = format!("[{}] {}", self.describe(mention.target), mention.name);
+          self
+            .to_handle
+            .send(Event::Message {
+              id: mention.id,
+              prefix,
+              content: mention.content,
+              edited: false,
+              deleted: false,
+              reactions: vec![],
+              reply_to: None,
+              replies: 0,
+              mentions_me: true,
+              attachments: vec![],
+            })
+            .await
+            .unwrap();
+        }
+      }
+      convos::ServerTell::Success(s) => {
+        self.print(format!("Success: {}", s)).await;
+
+        // catch up on whatever happened while signed out
+        match s {
+          convos::Success::SignIn | convos::Success::Recovered | convos::Success::Upgraded => {
+            self.ask(ClientQuestion::FailedSignIns).await;
+            self.ask(ClientQuestion::Mentions).await;
+            self.ask(ClientQuestion::Unread).await;
+          }
+          convos::Success::Renamed => {
+            if let Some(name) = self.renaming.take() {
+              self.username = Some(name);
+            }
+          }
+          // the connection stays open, but there is nobody signed in on it anymore
+          convos::Success::AccountDeleted => {
+            self.username = None;
+            self.forget_account().await;
+          }
+          _ => {}
+        }
+      }
+      convos::ServerTell::Error(x) => {
+        // none of these leave anything worth carrying on with
+        if let convos::Error::AttachmentTooLarge
+        | convos::Error::AttachmentNameTooLong { .. }
+        | convos::Error::QuotaExceeded
+        | convos::Error::InvalidUpload
+        | convos::Error::HashMismatch = x
+        {
+          self.upload = None;
+        }
+
+        // whatever was turned away may have been the next chunk, sending it again is harmless
+        if let convos::Error::RateLimited { retry_after } = x {
+          let upload = self.upload.as_ref().filter(|u| u.id.is_some());
+          if let Some(received) = upload.map(|u| u.received) {
+            tokio::time::sleep(Duration::from_millis(retry_after)).await;
+            self.continue_upload(received).await;
+            return;
+          }
+        }
+
+        self.print(format!("Error: {}", x)).await
+      }
     }
   }
 
@@ -194,7 +1577,7 @@ impl Broker {
     let cmd = command_parsing::parse(msg);
 
     match cmd {
-      Command::Ping => self.to_handle.send("Pong!".to_string()).await.unwrap(),
+      Command::Ping => self.print("Pong!".to_string()).await,
 
       Command::Connect(ref addr) => {
         self.disconnect().await;
@@ -202,13 +1585,16 @@ impl Broker {
         // try to connect to the server
         dbg!(addr.clone());
         let Ok(mut stream) = TcpStream::connect(addr).await else {
-          self.to_handle.send("Invalid address.".to_owned()).await.unwrap();
+          self.print("Invalid address.".to_owned()).await;
           return;
         };
 
+        self.state = State::default();
+
         // give the handshake to the server
         let hs = stream.read_u64().await.unwrap();
         stream.write_u64(hs).await.unwrap();
+        self.state.handshake_complete();
 
         let (read_half, write_half) = stream.into_split();
 
@@ -227,92 +1613,438 @@ impl Broker {
           from_server: read_rx,
         });
 
+        self.print(format!("Connected to: {}", addr)).await;
+        // everyone starts out as a guest, under a name the server picks
+        self.ask(ClientQuestion::WhoAmI).await;
+      }
+
+      Command::WhoIs(who) => {
+        let question = match who.parse() {
+          Ok(id) => ClientQuestion::WhoIsID { id },
+          Err(_) => ClientQuestion::WhoIsName { name: who },
+        };
+        self.ask(question).await;
+      }
+
+      Command::UpdateProfile(change) => {
         self
-          .to_handle
-          .send(format!("Connected to: {}", addr))
-          .await
-          .unwrap();
+          .ask(ClientQuestion::UpdateProfile {
+            changes: vec![change],
+          })
+          .await;
+      }
+
+      Command::Directory { query, fuzzy } => {
+        self.ask(ClientQuestion::Directory { query, fuzzy }).await;
       }
 
       Command::WhoAmI => {
-        let Some(workers) = &self.workers else {
-          self.to_handle.send("Cannot whoami when not connected.".to_owned()).await.unwrap();
+        if self.ask(ClientQuestion::WhoAmI).await {
+          self.print("Sent whoami".to_owned()).await;
+        }
+      }
+
+      Command::NumConnected => {
+        self.ask(ClientQuestion::NumConnected).await;
+      }
+
+      Command::PresenceOf(id) => {
+        self.ask(ClientQuestion::PresenceOf { id }).await;
+      }
+
+      Command::Watch(id) => {
+        self.ask(ClientQuestion::WatchPresence { id }).await;
+      }
+
+      Command::Unwatch(id) => {
+        self.ask(ClientQuestion::UnwatchPresence { id }).await;
+      }
+
+      Command::SetAway(away) => {
+        self.ask(ClientQuestion::SetAway { away }).await;
+      }
+
+      Command::Sessions => {
+        self.ask(ClientQuestion::ListSessions).await;
+      }
+
+      Command::Kick(id) => {
+        self.ask(ClientQuestion::KickSession { id }).await;
+      }
+
+      Command::ChangePassword { old, new } => {
+        self.ask(ClientQuestion::ChangePassword { old, new }).await;
+      }
+
+      Command::Rename(username) => {
+        self.renaming = Some(username.clone());
+        self.ask(ClientQuestion::Rename { username }).await;
+      }
+
+      Command::DeleteAccount { confirm, password } => {
+        self
+          .ask(ClientQuestion::DeleteAccount { password, confirm })
+          .await;
+      }
+
+      Command::Join(name) => {
+        self.ask(ClientQuestion::JoinRoom { name }).await;
+      }
+
+      Command::Leave => {
+        let Some(Target::Room(room)) = self.target else {
+          self.print("Not in a room.".to_owned()).await;
           return;
         };
 
-        workers
-          .to_server
-          .send(encode_client_question(ClientQuestion::WhoAmI).unwrap())
-          .await
-          .unwrap();
+        if self.ask(ClientQuestion::LeaveRoom { room }).await {
+          self.target = None;
+          self.publish_typing();
 
-        self.to_handle.send("Sent whoami".to_owned()).await.unwrap();
+          self.unread.remove(&room);
+          self.to_handle.send(Event::Left { room }).await.unwrap();
+        }
       }
 
-      Command::Disconnect => {
-        if self.workers.is_none() {
+      Command::Edit { id, content } => {
+        self.ask(ClientQuestion::EditMessage { id, content }).await;
+      }
+
+      Command::Moderate { id, action, reason } => {
+        let Some(Target::Room(room)) = self.target else {
+          self.print("Not in a room.".to_owned()).await;
+          return;
+        };
+
+        self
+          .ask(ClientQuestion::Moderate {
+            room,
+            id,
+            action,
+            reason,
+          })
+          .await;
+      }
+
+      Command::SetRole { server, id, role } => {
+        let room = match self.target {
+          _ if server => None,
+          Some(Target::Room(room)) => Some(room),
+          _ => {
+            self.print("Not in a room.".to_owned()).await;
+            return;
+          }
+        };
+
+        self.ask(ClientQuestion::SetRole { room, id, role }).await;
+      }
+
+      Command::AuditLog => {
+        // the whole server's outside of a room
+        let room = match self.target {
+          Some(Target::Room(room)) => Some(room),
+          _ => None,
+        };
+
+        self.ask(ClientQuestion::AuditLog { room }).await;
+      }
+
+      Command::AllowGuests(allowed) => {
+        let Some(Target::Room(room)) = self.target else {
+          self.print("Not in a room.".to_owned()).await;
+          return;
+        };
+
+        self
+          .ask(ClientQuestion::AllowGuests { room, allowed })
+          .await;
+      }
+
+      Command::Delete(id) => {
+        self.ask(ClientQuestion::DeleteMessage { id }).await;
+      }
+
+      Command::History => {
+        let Some(target) = self.target else {
           self
-            .to_handle
-            .send("Already disconnected.".to_owned())
-            .await
-            .unwrap();
+            .print("Join a room or dm someone first.".to_owned())
+            .await;
+          return;
+        };
+
+        self.load_history(target).await;
+      }
+
+      Command::React { id, emoji } => {
+        self.ask(ClientQuestion::React { id, emoji }).await;
+      }
+
+      Command::Unreact { id, emoji } => {
+        self.ask(ClientQuestion::Unreact { id, emoji }).await;
+      }
+
+      Command::Direct(uid) => {
+        self.target = Some(Target::Direct(uid));
+        self.publish_typing();
+
+        let line = format!("Now talking in {}", self.describe(Target::Direct(uid)));
+        self.print(line).await;
+        self.load_history(Target::Direct(uid)).await;
+      }
+
+      Command::Disconnect => {
+        if self.state == State::Closing {
+          self.print("Already disconnected.".to_owned()).await;
         } else {
           self.disconnect().await;
-          self
-            .to_handle
-            .send("Disconnected from server".to_owned())
-            .await
-            .unwrap();
+          self.print("Disconnected from server".to_owned()).await;
         }
       }
 
       Command::Message(msg) => {
-        if let Some(workers) = &self.workers {
-          workers
-            .to_server
-            .send(msg.clone().into_bytes())
-            .await
-            .unwrap();
-          self.to_handle.send(msg).await.unwrap();
-        } else {
+        let Some(target) = self.target else {
           self
-            .to_handle
-            .send("No server currently connected to send a message to.".to_owned())
-            .await
-            .unwrap();
-        }
+            .print("Join a room or dm someone to send a message.".to_owned())
+            .await;
+          return;
+        };
+
+        let attachments = std::mem::take(&mut self.attachments);
+        self
+          .ask(ClientQuestion::Say {
+            target,
+            content: msg,
+            reply_to: None,
+            attachments,
+          })
+          .await;
       }
 
-      Command::SignUp { name, password } => {
-        let Some(workers) = &self.workers else {
+      Command::Reply { id, content } => {
+        // a thread that was never opened is most likely somewhere in the current target
+        let Some(target) = self.threads.get(&id).copied().or(self.target) else {
           self
-            .to_handle
-            .send("No server currently connected to send a message to".to_owned()).await.unwrap();
+            .print("Open the thread to reply to it.".to_owned())
+            .await;
           return;
         };
 
-        workers
-          .to_server
-          .send(
-            encode_client_question(ClientQuestion::SignUp {
-              username: name,
-              password,
-            })
-            .unwrap(),
-          )
+        let attachments = std::mem::take(&mut self.attachments);
+        self
+          .ask(ClientQuestion::Say {
+            target,
+            content,
+            reply_to: Some(id),
+            attachments,
+          })
+          .await;
+      }
+
+      Command::Thread(id) => {
+        self.ask(ClientQuestion::Thread { id }).await;
+      }
+
+      Command::Search {
+        query,
+        room,
+        author,
+        after,
+        before,
+      } => {
+        let room = match room {
+          Some(name) => match self.rooms.iter().find(|(_, room)| **room == name) {
+            Some((id, _)) => Some(*id),
+            None => {
+              self.print(format!("Not in a room called {}.", name)).await;
+              return;
+            }
+          },
+          None => None,
+        };
+
+        let filter = SearchFilter {
+          room,
+          author,
+          after,
+          before,
+        };
+        self.search = Some((query.clone(), filter.clone(), 0));
+        self
+          .ask(ClientQuestion::Search {
+            query,
+            filter,
+            page: 0,
+          })
+          .await;
+      }
+
+      Command::Upload(path) => {
+        if self.upload.is_some() {
+          self.print("Already uploading something.".to_owned()).await;
+          return;
+        }
+
+        let data = match fs::read(&path).await {
+          Ok(data) => data,
+          Err(e) => {
+            self.print(format!("Couldn't read {}: {}", path, e)).await;
+            return;
+          }
+        };
+
+        let name = Path::new(&path)
+          .file_name()
+          .map(|name| name.to_string_lossy().into_owned())
+          .unwrap_or(path);
+        let hash = format!("{:x}", Sha256::digest(&data));
+        let size = data.len() as u64;
+
+        if self
+          .ask(ClientQuestion::BeginUpload {
+            name: name.clone(),
+            size,
+            hash,
+          })
           .await
-          .unwrap();
+        {
+          self.upload = Some(OutgoingUpload {
+            id: None,
+            name,
+            data,
+            received: 0,
+          });
+        }
+      }
+
+      Command::Download(id) => {
+        if self.ask(ClientQuestion::Download { id, offset: 0 }).await {
+          self.downloads.insert(id, vec![]);
+        }
+      }
+
+      Command::MoreResults => {
+        let Some((query, filter, page)) = self.search.clone() else {
+          self.print("Search for something first.".to_owned()).await;
+          return;
+        };
+
+        self.search = Some((query.clone(), filter.clone(), page + 1));
+        self
+          .ask(ClientQuestion::Search {
+            query,
+            filter,
+            page: page + 1,
+          })
+          .await;
+      }
+
+      Command::SignUp {
+        name,
+        password,
+        invite,
+      } => {
+        self
+          .ask(ClientQuestion::SignUp {
+            username: name,
+            password,
+            invite,
+          })
+          .await;
+      }
+
+      Command::SignIn { name, password } => {
+        self.username = Some(name.clone());
+        self
+          .ask(ClientQuestion::SignIn {
+            username: name,
+            password,
+          })
+          .await;
+      }
+
+      Command::Upgrade {
+        name,
+        password,
+        invite,
+      } => {
+        self.username = Some(name.clone());
+        self
+          .ask(ClientQuestion::Upgrade {
+            username: name,
+            password,
+            invite,
+          })
+          .await;
+      }
+
+      Command::Recover {
+        name,
+        code,
+        password,
+      } => {
+        self.username = Some(name.clone());
+        self
+          .ask(ClientQuestion::Recover {
+            username: name,
+            code,
+            password,
+          })
+          .await;
+      }
+
+      Command::SecondFactor(code) => {
+        self.ask(ClientQuestion::SecondFactor { code }).await;
+      }
+
+      Command::EnableTwoFactor(password) => {
+        self.ask(ClientQuestion::EnableTwoFactor { password }).await;
+      }
+
+      Command::ConfirmTwoFactor(code) => {
+        self.ask(ClientQuestion::ConfirmTwoFactor { code }).await;
       }
 
-      Command::SignIn { name, password } => todo!(),
+      Command::DisableTwoFactor(password) => {
+        self
+          .ask(ClientQuestion::DisableTwoFactor { password })
+          .await;
+      }
+
+      Command::CreateInvite { uses, expires_in } => {
+        self
+          .ask(ClientQuestion::CreateInvite { uses, expires_in })
+          .await;
+      }
+
+      Command::NewRecoveryCodes(password) => {
+        self
+          .ask(ClientQuestion::NewRecoveryCodes { password })
+          .await;
+      }
+
+      Command::SaveRecoveryCodes(path) => {
+        let Some(codes) = &self.recovery_codes else {
+          self.print("No recovery codes to save".to_owned()).await;
+          return;
+        };
+
+        let path = path.unwrap_or_else(|| "recovery-codes.txt".to_owned());
+        let mut contents = codes.join("\n");
+        contents.push('\n');
+
+        match fs::write(&path, contents).await {
+          Ok(()) => {
+            // saved once is enough, they shouldn't linger in memory
+            self.recovery_codes = None;
+            self.print(format!("Saved to {}", path)).await;
+          }
+          Err(e) => self.print(format!("Couldn't save {}: {}", path, e)).await,
+        }
+      }
 
-      Command::Unknown => self
-        .to_handle
-        .send("Unknown command".to_string())
-        .await
-        .unwrap(),
+      Command::Unknown => self.print("Unknown command".to_string()).await,
 
-      Command::Error(e) => self.to_handle.send(e).await.unwrap(),
+      Command::Error(e) => self.print(e).await,
     }
   }
 }
@@ -340,12 +2072,16 @@ async fn reader(
 ) {
   loop {
     select! {
-      _ = async {
-        let len = stream.read_u16().await.unwrap();
+      read = async {
+        let len = stream.read_u16().await?;
         let mut vec = vec![0; len as usize];
-        stream.read_exact(vec.as_mut_slice()).await.unwrap();
-        to_broker.send(vec).await.unwrap();
-      } => {},
+        stream.read_exact(vec.as_mut_slice()).await?;
+        Ok::<_, std::io::Error>(vec)
+      } => match read {
+        Ok(vec) => to_broker.send(vec).await.unwrap(),
+        // the server hung up, dropping to_broker is how the broker finds out
+        Err(_) => return,
+      },
       _ = kill.changed() => return,
     }
   }
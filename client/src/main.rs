@@ -1,26 +1,260 @@
 mod broker;
-use broker::{Broker, BrokerHandle};
-use eframe::{egui::CentralPanel, App, CreationContext, NativeOptions};
+use std::collections::HashMap;
+
+use broker::{Broker, BrokerHandle, Event};
+use convos::{Attachment, Reaction};
+use eframe::{
+  egui::{CentralPanel, Label, RichText, ScrollArea, Sense, SidePanel, TopBottomPanel, Ui},
+  App, CreationContext, NativeOptions,
+};
+
+// a line of the history, chat messages keep their id so they can be changed in place
+struct HistoryLine {
+  id: Option<u64>,
+  prefix: String,
+  content: String,
+  edited: bool,
+  deleted: bool,
+  reactions: Vec<Reaction>,
+  // replies live in their thread's panel rather than the main history
+  reply_to: Option<u64>,
+  replies: u64,
+  mentions_me: bool,
+  attachments: Vec<Attachment>,
+}
+
+impl HistoryLine {
+  fn text(&self) -> String {
+    match (self.id, self.deleted, self.edited) {
+      (None, _, _) => self.content.clone(),
+      (Some(_), true, _) => format!("{}: (deleted)", self.prefix),
+      (Some(_), false, true) => format!("{}: {} (edited)", self.prefix, self.content),
+      (Some(_), false, false) => format!("{}: {}", self.prefix, self.content),
+    }
+  }
+}
+
+// something picked while drawing the history, acted on once drawing is done
+enum Action {
+  Send(String),
+  // put something in the input box for the user to finish off
+  Fill(String),
+}
+
+fn draw_line(ui: &mut Ui, line: &HistoryLine, action: &mut Option<Action>) {
+  let mut text = RichText::new(line.text());
+  if line.mentions_me {
+    text = text.strong().color(ui.visuals().warn_fg_color);
+  }
+  let label = ui.add(Label::new(text).sense(Sense::click()));
+
+  let Some(id) = line.id.filter(|_| !line.deleted) else {
+    return;
+  };
+
+  for attachment in &line.attachments {
+    let text = format!("📎 {} ({} bytes)", attachment.name, attachment.size);
+    if ui.link(text).clicked() {
+      *action = Some(Action::Send(format!("/download {}", attachment.id)));
+    }
+  }
+
+  if !line.reactions.is_empty() {
+    ui.horizontal(|ui| {
+      for reaction in &line.reactions {
+        let chip = format!("{} {}", reaction.emoji, reaction.count);
+        if ui.selectable_label(reaction.mine, chip).clicked() {
+          let verb = if reaction.mine { "unreact" } else { "react" };
+          *action = Some(Action::Send(format!("/{} {} {}", verb, id, reaction.emoji)));
+        }
+      }
+    });
+  }
+
+  if line.replies != 0 {
+    let replies = match line.replies {
+      1 => "1 reply".to_owned(),
+      n => format!("{} replies", n),
+    };
+    if ui.link(replies).clicked() {
+      *action = Some(Action::Send(format!("/thread {}", id)));
+    }
+  }
+
+  label.context_menu(|ui| {
+    if ui.button("Edit").clicked() {
+      *action = Some(Action::Fill(format!("/edit {} {}", id, line.content)));
+      ui.close_menu();
+    }
+    if ui.button("Delete").clicked() {
+      *action = Some(Action::Send(format!("/delete {}", id)));
+      ui.close_menu();
+    }
+    if ui.button("React 👍").clicked() {
+      *action = Some(Action::Send(format!("/react {} 👍", id)));
+      ui.close_menu();
+    }
+    if line.reply_to.is_none() && ui.button("Reply in thread").clicked() {
+      *action = Some(Action::Send(format!("/thread {}", id)));
+      ui.close_menu();
+    }
+  });
+}
+
+// a joined room, and how much has happened there since the user last looked
+struct Badge {
+  name: String,
+  unread: u64,
+  mentions: u64,
+}
+
+impl Badge {
+  fn text(&self) -> String {
+    match (self.unread, self.mentions) {
+      (0, _) => format!("#{}", self.name),
+      (unread, 0) => format!("#{} ({})", self.name, unread),
+      (unread, mentions) => format!("#{} ({}, {}@)", self.name, unread, mentions),
+    }
+  }
+}
 
 struct Application {
   handle: BrokerHandle,
-  history: String,
+  history: Vec<HistoryLine>,
   current_input: String,
+
+  rooms: HashMap<u64, Badge>,
+  // the last message read before catching up, the "new messages" divider goes under it
+  divider: Option<u64>,
+
+  // the root of the thread open in the side panel
+  thread: Option<u64>,
+  thread_input: String,
 }
 
 impl App for Application {
-  fn update(&mut self, ctx: &eframe::egui::Context, frame: &mut eframe::Frame) {
+  fn update(&mut self, ctx: &eframe::egui::Context, _frame: &mut eframe::Frame) {
     // check for any updates real quick
-    while let Ok(msg) = self.handle.from_broker.try_recv() {
-      self.history += &msg;
-      self.history.push('\n');
+    while let Ok(event) = self.handle.from_broker.try_recv() {
+      self.on_event(event);
     }
 
     // ok now draw
+    let mut action = None;
+
+    if let Some(root) = self.thread {
+      SidePanel::right("thread").show(ctx, |ui| {
+        ui.horizontal(|ui| {
+          ui.heading("Thread");
+          if ui.button("Close").clicked() {
+            self.thread = None;
+          }
+        });
+
+        ScrollArea::vertical()
+          .auto_shrink([false, false])
+          .stick_to_bottom(true)
+          .max_height(ui.available_height() - 24.0)
+          .show(ui, |ui| {
+            let thread = self
+              .history
+              .iter()
+              .filter(|line| line.id == Some(root) || line.reply_to == Some(root));
+            for line in thread {
+              draw_line(ui, line, &mut action);
+            }
+          });
+
+        let input = ui.text_edit_singleline(&mut self.thread_input);
+        if input.lost_focus()
+          && ctx.input(|i| i.key_pressed(eframe::egui::Key::Enter))
+          && !self.thread_input.is_empty()
+        {
+          action = Some(Action::Send(format!(
+            "/reply {} {}",
+            root, self.thread_input
+          )));
+          input.request_focus();
+          self.thread_input.clear();
+        }
+      });
+    }
+
+    if !self.rooms.is_empty() {
+      TopBottomPanel::top("rooms").show(ctx, |ui| {
+        ui.horizontal_wrapped(|ui| {
+          let mut rooms: Vec<&Badge> = self.rooms.values().collect();
+          rooms.sort_by(|a, b| a.name.cmp(&b.name));
+
+          for badge in rooms {
+            let mut text = RichText::new(badge.text());
+            if badge.mentions != 0 {
+              text = text.strong().color(ui.visuals().warn_fg_color);
+            } else if badge.unread != 0 {
+              text = text.strong();
+            }
+
+            // joining again just switches over to it
+            if ui.button(text).clicked() {
+              action = Some(Action::Send(format!("/join {}", badge.name)));
+            }
+          }
+        });
+      });
+    }
+
     CentralPanel::default().show(ctx, |ui| {
-      ui.text_edit_multiline(&mut self.history);
+      ScrollArea::vertical()
+        .auto_shrink([false, false])
+        .stick_to_bottom(true)
+        .max_height(ui.available_height() - 48.0)
+        .show(ui, |ui| {
+          let mut lines = self
+            .history
+            .iter()
+            .filter(|line| line.reply_to.is_none())
+            .peekable();
+
+          while let Some(line) = lines.next() {
+            draw_line(ui, line, &mut action);
+
+            // only worth drawing if something new actually comes after it
+            if line.id.is_some() && line.id == self.divider && lines.peek().is_some() {
+              ui.separator();
+              ui.label(RichText::new("new messages").color(ui.visuals().warn_fg_color));
+            }
+          }
+        });
+
+      match action.take() {
+        Some(Action::Send(command)) => self.handle.to_broker.blocking_send(command).unwrap(),
+        Some(Action::Fill(input)) => self.current_input = input,
+        None => {}
+      }
+
+      let typing = self.handle.typing.borrow().clone();
+      match typing.as_slice() {
+        [] => {}
+        [name] => {
+          ui.label(format!("{} is typing…", name));
+        }
+        names => {
+          ui.label(format!("{} are typing…", names.join(", ")));
+        }
+      }
+
       let input = ui.text_edit_singleline(&mut self.current_input);
 
+      if input.changed() {
+        // commands are not messages, nobody needs to see those being typed
+        let typing = !self.current_input.is_empty() && !self.current_input.starts_with('/');
+        self.handle.is_typing.send_if_modified(|is_typing| {
+          let changed = *is_typing != typing;
+          *is_typing = typing;
+          changed
+        });
+      }
+
       if input.lost_focus() && ctx.input(|i| i.key_pressed(eframe::egui::Key::Enter)) {
         self
           .handle
@@ -29,23 +263,147 @@ impl App for Application {
           .unwrap();
         input.request_focus();
         self.current_input.clear();
+        self.handle.is_typing.send_replace(false);
       }
     });
   }
 }
 
 impl Application {
-  fn new(cc: &CreationContext, handle: BrokerHandle) -> Self {
+  fn new(_cc: &CreationContext, handle: BrokerHandle) -> Self {
     Self {
       handle,
-      history: String::new(),
+      history: vec![],
       current_input: String::new(),
+      thread: None,
+      thread_input: String::new(),
+      rooms: HashMap::new(),
+      divider: None,
+    }
+  }
+
+  fn message(&mut self, id: u64) -> Option<&mut HistoryLine> {
+    self.history.iter_mut().find(|line| line.id == Some(id))
+  }
+
+  fn on_event(&mut self, event: Event) {
+    match event {
+      Event::Line(content) => self.history.push(HistoryLine {
+        id: None,
+        prefix: String::new(),
+        content,
+        edited: false,
+        deleted: false,
+        reactions: vec![],
+        reply_to: None,
+        replies: 0,
+        mentions_me: false,
+        attachments: vec![],
+      }),
+
+      Event::Message {
+        id,
+        prefix,
+        content,
+        edited,
+        deleted,
+        reactions,
+        reply_to,
+        replies,
+        mentions_me,
+        attachments,
+      } => {
+        let line = HistoryLine {
+          id: Some(id),
+          prefix,
+          content,
+          edited,
+          deleted,
+          reactions,
+          reply_to,
+          replies,
+          mentions_me,
+          attachments,
+        };
+
+        // history can come back with messages that are already on screen
+        match self.message(id) {
+          Some(existing) => *existing = line,
+          None => self.history.push(line),
+        }
+      }
+
+      Event::Edited { id, content } => {
+        if let Some(line) = self.message(id) {
+          line.content = content;
+          line.edited = true;
+        }
+      }
+
+      Event::Deleted { id } => {
+        if let Some(line) = self.message(id) {
+          line.deleted = true;
+        }
+      }
+
+      Event::Replied { id } => {
+        if let Some(line) = self.message(id) {
+          line.replies += 1;
+        }
+      }
+
+      Event::ReplyDeleted { id } => {
+        if let Some(line) = self.message(id) {
+          line.replies = line.replies.saturating_sub(1);
+        }
+      }
+
+      Event::Thread { root } => self.thread = Some(root),
+
+      Event::Unread {
+        room,
+        name,
+        unread,
+        mentions,
+      } => {
+        self.rooms.insert(
+          room,
+          Badge {
+            name,
+            unread,
+            mentions,
+          },
+        );
+      }
+
+      Event::Left { room } => {
+        self.rooms.remove(&room);
+      }
+
+      Event::Divider { after } => self.divider = Some(after),
+
+      Event::Reacted { id, reaction } => {
+        if let Some(line) = self.message(id) {
+          let existing = line
+            .reactions
+            .iter()
+            .position(|r| r.emoji == reaction.emoji);
+          match (existing, reaction.count) {
+            (Some(i), 0) => {
+              line.reactions.remove(i);
+            }
+            (Some(i), _) => line.reactions[i] = reaction,
+            (None, 0) => {}
+            (None, _) => line.reactions.push(reaction),
+          }
+        }
+      }
     }
   }
 }
 
 fn main() {
-  let handle = Broker::new();
+  let handle = Broker::spawn();
 
   eframe::run_native(
     "yacs2",
@@ -1,7 +1,9 @@
-use std::{fmt::Display, io::Read, mem::transmute};
+use std::fmt::Display;
 
 use serde::{Deserialize, Serialize};
 
+pub mod state;
+
 pub mod bytes {
   pub const OK: u8 = 0x00;
   pub const WHO_IS: u8 = 0x10;
@@ -13,6 +15,9 @@ pub mod bytes {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Error {
+  // something went wrong on the server's end, nothing the client did
+  ServerError,
+  NotConnected,
   NotLoggedIn,
   AlreadyLoggedIn,
 
@@ -20,18 +25,169 @@ pub enum Error {
   InvalidUID,
   InvalidUsername,
   InvalidPassword,
+  InvalidSession,
+  NotInRoom,
+  InvalidMessage,
+  // a message can be at most this many characters
+  MessageTooLong { max: u64 },
+  NotPermitted,
+  InvalidEmoji,
+  // the message already has as many different emoji on it as it can
+  TooManyReactions,
+  InvalidQuery,
+  AttachmentTooLarge,
+  // an attachment's name can be at most this many characters
+  AttachmentNameTooLong { max: u64 },
+  QuotaExceeded,
+  InvalidUpload,
+  HashMismatch,
+  InvalidAttachment,
+  Banned,
+  Muted,
+  InvalidRole,
+  // deleting an account has to be confirmed by typing its username
+  ConfirmationMismatch,
+  InvalidRecoveryCode,
+  // signed in with the right password, but the challenge hasn't been answered yet
+  TwoFactorRequired,
+  InvalidTwoFactorCode,
+  // the challenge wasn't answered in time, the sign-in has to start over
+  ChallengeExpired,
+  TwoFactorAlreadyEnabled,
+  RegistrationClosed,
+  // the server only lets people sign up with an invite
+  InviteRequired,
+  // no such invite, or it has expired or been used up
+  InvalidInvite,
+  // the room isn't open to guests, or doesn't exist yet, which guests can't change
+  GuestsNotAllowed,
+  ProfileTooLong { field: String, max: u64 },
+  // a moderation reason can be at most this many characters
+  ReasonTooLong { max: u64 },
+  // too many questions too quickly, worth asking again after this many milliseconds
+  RateLimited { retry_after: u64 },
+  // too many failed sign-ins, from this address or at this account, wait this many seconds
+  LockedOut { retry_after: u64 },
+  UsernameRejected { reason: UsernameProblem },
+  PasswordRejected { reason: PasswordProblem },
+}
+
+// why a username can't be signed up with
+#[derive(Debug, Serialize, Deserialize)]
+pub enum UsernameProblem {
+  TooShort { min: u64 },
+  TooLong { max: u64 },
+  // only letters, digits, `_`, `-` and `.` are allowed
+  InvalidCharacter { character: char },
+  // has to start and end with a letter or digit
+  BadEdge,
+  Reserved,
+  // too easily mistaken for an existing username
+  TooSimilar,
+}
+
+impl Display for UsernameProblem {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      UsernameProblem::TooShort { min } => write!(f, "shorter than {} characters", min),
+      UsernameProblem::TooLong { max } => write!(f, "longer than {} characters", max),
+      UsernameProblem::InvalidCharacter { character } => {
+        write!(
+          f,
+          "{:?} is not allowed, only letters, digits, _, - and .",
+          character
+        )
+      }
+      UsernameProblem::BadEdge => f.write_str("has to start and end with a letter or digit"),
+      UsernameProblem::Reserved => f.write_str("reserved"),
+      UsernameProblem::TooSimilar => f.write_str("too similar to an existing username"),
+    }
+  }
+}
+
+// why a password can't be used
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PasswordProblem {
+  TooShort { min: u64 },
+  TooLong { max: u64 },
+  SameAsUsername,
+  // known from a breach, and so one of the first to be guessed
+  Breached,
+}
+
+impl Display for PasswordProblem {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      PasswordProblem::TooShort { min } => write!(f, "shorter than {} characters", min),
+      PasswordProblem::TooLong { max } => write!(f, "longer than {} characters", max),
+      PasswordProblem::SameAsUsername => f.write_str("the same as the username"),
+      PasswordProblem::Breached => f.write_str("known from a data breach"),
+    }
+  }
 }
 
 impl Display for Error {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_str(match self {
+    let message = match self {
+      Error::ServerError => "Something went wrong on the server, try again later",
+      Error::NotConnected => "Not connected",
       Error::NotLoggedIn => "Not logged in",
       Error::AlreadyLoggedIn => "Already logged in",
       Error::UsernameTaken => "Username is taken",
       Error::InvalidUID => "Invalid UID",
       Error::InvalidUsername => "Invalid Username",
       Error::InvalidPassword => "Invalid password",
-    })
+      Error::InvalidSession => "No such session",
+      Error::NotInRoom => "Not in that room",
+      Error::InvalidMessage => "No such message",
+      Error::NotPermitted => "Not permitted",
+      Error::InvalidEmoji => "Invalid emoji",
+      Error::TooManyReactions => "That message can't take any more different reactions",
+      Error::InvalidQuery => "Invalid search query",
+      Error::AttachmentTooLarge => "Attachment is too large",
+      Error::QuotaExceeded => "Out of space for attachments",
+      Error::InvalidUpload => "No such upload",
+      Error::HashMismatch => "Upload did not match its hash",
+      Error::InvalidAttachment => "No such attachment",
+      Error::Banned => "Banned from that room",
+      Error::Muted => "Muted in that room",
+      Error::InvalidRole => "No such role",
+      Error::ConfirmationMismatch => "Confirmation did not match the username",
+      Error::InvalidRecoveryCode => "Invalid or already used recovery code",
+      Error::TwoFactorRequired => "A two-factor code is needed to finish signing in",
+      Error::InvalidTwoFactorCode => "Invalid two-factor code",
+      Error::ChallengeExpired => "The two-factor code came too late, sign in again",
+      Error::TwoFactorAlreadyEnabled => "Two-factor authentication is already enabled",
+      Error::RegistrationClosed => "Signing up is closed",
+      Error::InviteRequired => "Signing up needs an invite code",
+      Error::InvalidInvite => "Invalid, expired or used up invite code",
+      Error::GuestsNotAllowed => "That room is not open to guests",
+      Error::UsernameRejected { reason } => return write!(f, "Username rejected: {}", reason),
+      Error::PasswordRejected { reason } => return write!(f, "Password rejected: {}", reason),
+      Error::MessageTooLong { max } => {
+        return write!(f, "A message can be at most {} characters", max)
+      }
+      Error::AttachmentNameTooLong { max } => {
+        return write!(f, "The attachment's name can be at most {} characters", max)
+      }
+      Error::ReasonTooLong { max } => {
+        return write!(f, "The reason can be at most {} characters", max)
+      }
+      Error::ProfileTooLong { field, max } => {
+        return write!(f, "The {} can be at most {} characters", field, max)
+      }
+      Error::LockedOut { retry_after } => {
+        return write!(f, "Too many failed sign-ins, try again in {}s", retry_after)
+      }
+      Error::RateLimited { retry_after } => {
+        return write!(
+          f,
+          "Slow down, try again in {:.1}s",
+          *retry_after as f64 / 1000.0
+        )
+      }
+    };
+    f.write_str(message)
   }
 }
 
@@ -39,6 +195,18 @@ impl Display for Error {
 pub enum Success {
   SignIn,
   SignUp,
+  Unwatched,
+  Kicked,
+  Left,
+  Moderated,
+  PasswordChanged,
+  Renamed,
+  AccountDeleted,
+  Recovered,
+  TwoFactorEnabled,
+  TwoFactorDisabled,
+  Upgraded,
+  GuestAccessChanged,
 }
 
 impl Display for Success {
@@ -46,17 +214,456 @@ impl Display for Success {
     f.write_str(match self {
       Success::SignIn => "Successfully signed in",
       Success::SignUp => "Successfully signed up",
+      Success::Unwatched => "No longer watching",
+      Success::Kicked => "Session kicked",
+      Success::Left => "Left the room",
+      Success::Moderated => "Done",
+      Success::PasswordChanged => "Password changed",
+      Success::Renamed => "Username changed",
+      Success::AccountDeleted => "Account deleted",
+      Success::Recovered => "Signed in with a recovery code and changed the password",
+      Success::TwoFactorEnabled => "Two-factor authentication enabled",
+      Success::TwoFactorDisabled => "Two-factor authentication disabled",
+      Success::Upgraded => "Signed up and signed in, keeping everything from as a guest",
+      Success::GuestAccessChanged => "Guest access changed",
     })
   }
 }
 
+// a user's presence, aggregated over all of their connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Presence {
+  Online,
+  Away,
+  Offline,
+}
+
+impl Display for Presence {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self {
+      Presence::Online => "online",
+      Presence::Away => "away",
+      Presence::Offline => "offline",
+    })
+  }
+}
+
+// where a message (or anything scoped like one) is said
+// in tells, a direct target is always the other party from the receiver's point of view
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Target {
+  Room(u64),
+  // a direct message with another user, by their uid
+  Direct(u64),
+}
+
+// one of the connections a user is signed in on
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+  pub id: u64,
+  pub address: String,
+  // seconds since the unix epoch
+  pub connected_at: u64,
+  pub away: bool,
+  // whether this is the connection that asked
+  pub current: bool,
+}
+
+// what a user says about themselves, all of it optional but when they signed up
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Profile {
+  pub display_name: Option<String>,
+  pub bio: Option<String>,
+  pub pronouns: Option<String>,
+  // a line about what they're up to
+  pub status: Option<String>,
+  // an attachment, which anyone may download
+  pub avatar: Option<u64>,
+  // seconds since the unix epoch
+  pub created_at: u64,
+}
+
+// one part of a profile, set to something or cleared with None
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ProfileChange {
+  DisplayName(Option<String>),
+  Bio(Option<String>),
+  Pronouns(Option<String>),
+  Status(Option<String>),
+  // has to be an attachment of the user's own
+  Avatar(Option<u64>),
+}
+
+impl ProfileChange {
+  pub fn field(&self) -> &'static str {
+    match self {
+      ProfileChange::DisplayName(_) => "display name",
+      ProfileChange::Bio(_) => "bio",
+      ProfileChange::Pronouns(_) => "pronouns",
+      ProfileChange::Status(_) => "status",
+      ProfileChange::Avatar(_) => "avatar",
+    }
+  }
+
+  // the most characters the field may hold, if it is text
+  pub fn max_len(&self) -> Option<usize> {
+    match self {
+      ProfileChange::DisplayName(_) => Some(64),
+      ProfileChange::Bio(_) => Some(500),
+      ProfileChange::Pronouns(_) => Some(32),
+      ProfileChange::Status(_) => Some(128),
+      ProfileChange::Avatar(_) => None,
+    }
+  }
+}
+
+// one user turned up by a directory search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryEntry {
+  pub id: u64,
+  pub name: String,
+  pub display_name: Option<String>,
+}
+
+// at most this many users come back from a directory search
+pub const DIRECTORY_LIMIT: usize = 20;
+
+// all of one emoji on a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+  pub emoji: String,
+  pub count: u64,
+  // whether the receiver is one of the ones who reacted with it
+  pub mine: bool,
+}
+
+// uploads and downloads move this much of a file per frame at most,
+// it has to fit in a frame as base64 alongside everything else
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+// the longest a message can be, in characters, which leaves plenty of the frame
+// for it to be wrapped up and sent on to everyone, escaped, attachments and all
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+// the longest name an attachment can have, in characters
+pub const MAX_ATTACHMENT_NAME_LEN: usize = 255;
+
+// a finished upload, which messages can then carry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+  pub id: u64,
+  pub name: String,
+  pub size: u64,
+  // hex sha256 of the contents
+  pub hash: String,
+}
+
+// a message as it comes back in a history batch
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Message {
+  pub id: u64,
+  pub from: u64,
+  pub name: String,
+  pub content: String,
+  pub edited: bool,
+  pub deleted: bool,
+  pub reactions: Vec<Reaction>,
+  // the root of the thread this is a reply in
+  pub reply_to: Option<u64>,
+  // for thread roots, how many replies there are and when the last one was sent,
+  // in seconds since the unix epoch
+  pub replies: u64,
+  pub last_reply_at: Option<u64>,
+  pub attachments: Vec<Attachment>,
+}
+
+// what a role lets its holder do, in one room or everywhere if held server-wide
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Permission {
+  Kick,
+  Ban,
+  Mute,
+  DeleteMessages,
+  ManageRoom,
+  // minting invite codes, only ever held server-wide
+  Invite,
+}
+
+// the longest reason a moderation action can be given, in characters
+pub const MAX_REASON_LEN: usize = 500;
+
+// something a moderator can do to a member of a room
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Moderation {
+  // out of the room, free to come back
+  Kick,
+  // out of the room and kept out, for a number of seconds or for good
+  Ban { duration: Option<u64> },
+  Unban,
+  // still in the room but unable to say anything there
+  Mute { duration: Option<u64> },
+  Unmute,
+}
+
+impl Moderation {
+  pub fn permission(&self) -> Permission {
+    match self {
+      Moderation::Kick => Permission::Kick,
+      Moderation::Ban { .. } | Moderation::Unban => Permission::Ban,
+      Moderation::Mute { .. } | Moderation::Unmute => Permission::Mute,
+    }
+  }
+}
+
+impl Display for Moderation {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self {
+      Moderation::Kick => "kicked",
+      Moderation::Ban { .. } => "banned",
+      Moderation::Unban => "unbanned",
+      Moderation::Mute { .. } => "muted",
+      Moderation::Unmute => "unmuted",
+    })
+  }
+}
+
+// one line of the moderation log
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+  pub actor: u64,
+  pub action: String,
+  pub room: Option<u64>,
+  pub target: Option<u64>,
+  pub message: Option<u64>,
+  pub reason: Option<String>,
+  // seconds since the unix epoch
+  pub at: u64,
+}
+
+// narrows a search down, every filter given has to match
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchFilter {
+  pub room: Option<u64>,
+  pub author: Option<u64>,
+  // seconds since the unix epoch
+  pub after: Option<u64>,
+  pub before: Option<u64>,
+}
+
+// a piece of a search result's snippet, highlighted if it matched the query
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fragment {
+  pub text: String,
+  pub highlighted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchResult {
+  pub id: u64,
+  // as the receiver sees it
+  pub target: Target,
+  pub from: u64,
+  pub name: String,
+  // seconds since the unix epoch
+  pub sent_at: u64,
+  pub snippet: Vec<Fragment>,
+}
+
+// how far behind the receiver is in one of their rooms
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnreadCount {
+  pub room: u64,
+  pub name: String,
+  // the last message read, none if nothing in the room has been read yet
+  pub last_read: Option<u64>,
+  pub unread: u64,
+  pub mentions: u64,
+}
+
+// a sign-in at someone's account that got the password wrong
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FailedSignIn {
+  pub address: String,
+  // seconds since the unix epoch
+  pub at: u64,
+}
+
+// a message that mentioned the receiver
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Mention {
+  pub id: u64,
+  pub target: Target,
+  pub from: u64,
+  pub name: String,
+  pub content: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerTell {
-  NumConnected,
+  NumConnected {
+    count: u64,
+  },
+
+  // response to a WhoIs packet, and to UpdateProfile with the profile as it now stands
+  Who {
+    id: u64,
+    name: String,
+    // guests don't have one
+    profile: Option<Profile>,
+  },
+  // response to Directory, best matches first
+  Directory {
+    query: String,
+    users: Vec<DirectoryEntry>,
+  },
+  // response to PresenceOf/WatchPresence, and pushed to watchers whenever it changes
+  Presence {
+    id: u64,
+    presence: Presence,
+  },
+  // response to ListSessions
+  Sessions {
+    sessions: Vec<SessionInfo>,
+  },
+  Syndication {
+    // assigned by the server, edits and deletions refer back to it
+    id: u64,
+    from: u64,
+    name: String,
+    target: Target,
+    content: String,
+    reply_to: Option<u64>,
+    attachments: Vec<Attachment>,
+  },
+  // a message was edited by its author
+  Edited {
+    id: u64,
+    target: Target,
+    content: String,
+  },
+  // a message was deleted, by its author or a moderator
+  Deleted {
+    id: u64,
+    target: Target,
+    // the thread it was a reply in, which now has one reply less
+    reply_to: Option<u64>,
+  },
+  Joined {
+    room: u64,
+    name: String,
+  },
+  // response to History, oldest first
+  History {
+    target: Target,
+    messages: Vec<Message>,
+  },
+  // response to Thread, the root and then its replies oldest first
+  Thread {
+    target: Target,
+    root: Message,
+    replies: Vec<Message>,
+  },
+  // pushed to every connection of a user mentioned in a message
+  Mentioned {
+    mention: Mention,
+  },
+  // the answer to a SignIn with the right password at an account with two-factor
+  // authentication, which isn't signed in until SecondFactor gets a valid code
+  TwoFactorChallenge,
+  // response to EnableTwoFactor, an otpauth:// uri to give to an authenticator app
+  TwoFactorEnrolment {
+    uri: String,
+  },
+  // response to CreateInvite, `uses` and `expires_at` are None if unlimited
+  Invite {
+    code: String,
+    uses: Option<u32>,
+    expires_at: Option<u64>,
+  },
+  // sent after signing up and in response to NewRecoveryCodes,
+  // the server only keeps their hashes so this is the one time they are ever shown
+  RecoveryCodes {
+    codes: Vec<String>,
+  },
+  // response to FailedSignIns, oldest first
+  FailedSignIns {
+    attempts: Vec<FailedSignIn>,
+  },
+  // response to Mentions, everything that came in while nobody was connected
+  Mentions {
+    mentions: Vec<Mention>,
+  },
+  // a read marker moved, pushed to every connection of the user it belongs to
+  ReadMarker {
+    room: u64,
+    id: u64,
+  },
+  // response to Unread
+  Unread {
+    rooms: Vec<UnreadCount>,
+  },
+  // a moderation action in a room, sent to whoever it was done to
+  Moderated {
+    room: u64,
+    action: Moderation,
+    by: u64,
+    reason: Option<String>,
+  },
+  // response to SetRole
+  RoleSet {
+    room: Option<u64>,
+    id: u64,
+    role: Option<String>,
+  },
+  // response to AuditLog, newest first
+  AuditLog {
+    entries: Vec<AuditEntry>,
+  },
+  // response to BeginUpload, chunks go to the given upload id
+  UploadReady {
+    upload: u64,
+  },
+  // response to each UploadChunk, how much of the file the server has so far
+  ChunkReceived {
+    upload: u64,
+    received: u64,
+  },
+  // response to FinishUpload, the attachment can be sent in messages from now on
+  Uploaded {
+    attachment: Attachment,
+  },
+  // response to Download, data is base64
+  AttachmentChunk {
+    id: u64,
+    offset: u64,
+    size: u64,
+    data: String,
+  },
+  // response to Search, best matches first
+  SearchResults {
+    query: String,
+    page: u32,
+    results: Vec<SearchResult>,
+    // whether asking for the next page would turn up anything
+    more: bool,
+  },
+  // the reactions on a message changed, carries the new totals for that emoji
+  Reacted {
+    id: u64,
+    target: Target,
+    reaction: Reaction,
+  },
 
-  // response to a WhoIs packet
-  Who { id: u64, name: String },
-  Syndication { from: u64, content: String },
+  // ephemeral, the server stops the indicator itself if it is not refreshed
+  TypingStarted {
+    target: Target,
+    who: u64,
+    name: String,
+  },
+  TypingStopped {
+    target: Target,
+    who: u64,
+  },
 
   Success(Success),
   Error(Error),
@@ -64,17 +671,244 @@ pub enum ServerTell {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientQuestion {
-  SignUp { username: String, password: String },
-  SignIn { id: u64, password: String },
-  WhoIsID { id: u64 },
-  WhoIsName { name: String },
+  SignUp {
+    username: String,
+    password: String,
+    // needed when the server is invite-only, ignored otherwise
+    invite: Option<String>,
+  },
+  SignIn {
+    username: String,
+    password: String,
+  },
+  // signs in with one of the account's recovery codes instead of its password,
+  // which is replaced by `password` in the same go
+  Recover {
+    username: String,
+    code: String,
+    password: String,
+  },
+  // answers a TwoFactorChallenge, with a code from the authenticator app or a recovery code
+  SecondFactor {
+    code: String,
+  },
+  // signs up like SignUp, but as the guest this connection already is,
+  // keeping the rooms it joined and what it said there, and signs in right away
+  Upgrade {
+    username: String,
+    password: String,
+    invite: Option<String>,
+  },
+  WhoIsID {
+    id: u64,
+  },
+  WhoIsName {
+    name: String,
+  },
   WhoAmI,
+  // changes any number of parts of the asker's profile at once, all or none of them
+  UpdateProfile {
+    changes: Vec<ProfileChange>,
+  },
+  // usernames starting with `query`, for autocomplete, or if fuzzy,
+  // also the ones that only look something like it, for finding people
+  Directory {
+    query: String,
+    fuzzy: bool,
+  },
+
+  NumConnected,
+  PresenceOf {
+    id: u64,
+  },
+  // hear about every change to a user's presence, until unwatched or disconnected
+  WatchPresence {
+    id: u64,
+  },
+  UnwatchPresence {
+    id: u64,
+  },
+  // marks this connection as away, the user is only away once all of their connections are
+  SetAway {
+    away: bool,
+  },
+
+  // failed sign-ins at the asker's account they haven't been shown yet,
+  // they count as shown afterwards, and no longer count towards a lockout
+  FailedSignIns,
+  // every connection the asking user is signed in on
+  ListSessions,
+  ChangePassword {
+    old: String,
+    new: String,
+  },
+  // takes a new username, under the same rules as signing up
+  Rename {
+    username: String,
+  },
+  // deletes the asking user's account, signing out all of their connections,
+  // `confirm` has to be their current username
+  DeleteAccount {
+    password: String,
+    confirm: String,
+  },
+  // replaces the account's recovery codes with new ones
+  NewRecoveryCodes {
+    password: String,
+  },
+  // starts enrolling an authenticator app, which only counts once confirmed with its first code
+  EnableTwoFactor {
+    password: String,
+  },
+  ConfirmTwoFactor {
+    code: String,
+  },
+  DisableTwoFactor {
+    password: String,
+  },
+  // mints an invite code good for `uses` sign-ups (any number if None),
+  // for `expires_in` seconds (for good if None)
+  CreateInvite {
+    uses: Option<u32>,
+    expires_in: Option<u64>,
+  },
+  // disconnect one of the asking user's connections, by the id from ListSessions
+  KickSession {
+    id: u64,
+  },
+
+  // join a room by name, creating it if nobody has yet
+  JoinRoom {
+    name: String,
+  },
+  LeaveRoom {
+    room: u64,
+  },
+  // a reply goes in the thread of whatever it replies to
+  Say {
+    target: Target,
+    content: String,
+    reply_to: Option<u64>,
+    // ids of attachments uploaded by the asker
+    attachments: Vec<u64>,
+  },
+  EditMessage {
+    id: u64,
+    content: String,
+  },
+  DeleteMessage {
+    id: u64,
+  },
+  // the messages in a target from before the given one, or the newest if none is given,
+  // replies are left out and fetched by thread instead
+  History {
+    target: Target,
+    before: Option<u64>,
+  },
+  Thread {
+    id: u64,
+  },
+  // mentions of the asker nobody has been told about yet, they count as told afterwards
+  Mentions,
+  // everything in a room up to and including the given message has been read
+  MarkRead {
+    room: u64,
+    id: u64,
+  },
+  // unread and mention counts for every room the asker is in
+  Unread,
+  // full text search over every message the asker can see, a page at a time
+  Search {
+    query: String,
+    filter: SearchFilter,
+    page: u32,
+  },
+  // needs the action's permission in the room, and the target must not have it there
+  Moderate {
+    room: u64,
+    id: u64,
+    action: Moderation,
+    reason: Option<String>,
+  },
+  // gives a user a role in a room, or server-wide if no room is given, none takes it away
+  // needs manage room in the room, or server-wide for server-wide roles
+  SetRole {
+    room: Option<u64>,
+    id: u64,
+    role: Option<String>,
+  },
+  // the moderation log of a room, or everything if no room is given, needs manage room
+  AuditLog {
+    room: Option<u64>,
+  },
+  // lets guests join a room, or stops letting them, guests already in it stay, needs manage room
+  AllowGuests {
+    room: u64,
+    allowed: bool,
+  },
+  // uploads go initiate, chunks in order, finish, the server checks the hash at the end
+  BeginUpload {
+    name: String,
+    size: u64,
+    // hex sha256 of the whole file
+    hash: String,
+  },
+  // data is base64, at most CHUNK_SIZE bytes once decoded
+  UploadChunk {
+    upload: u64,
+    offset: u64,
+    data: String,
+  },
+  FinishUpload {
+    upload: u64,
+  },
+  // one chunk of an attachment, starting at the given offset
+  Download {
+    id: u64,
+    offset: u64,
+  },
+  React {
+    id: u64,
+    emoji: String,
+  },
+  Unreact {
+    id: u64,
+    emoji: String,
+  },
+
+  TypingStarted {
+    target: Target,
+  },
+  TypingStopped {
+    target: Target,
+  },
 }
 
-pub fn encode_client_question(question: ClientQuestion) -> Option<Vec<u8>> {
-  let str = serde_json::to_string(&question).ok()?;
-  dbg!(str);
+// every @username in a message, in the order they appear and without repeats
+pub fn mentions(content: &str) -> Vec<&str> {
+  let is_name = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.';
+  let mut names = vec![];
+
+  for (i, _) in content.match_indices('@') {
+    // part of an email address or the like, not a mention
+    if content[..i].chars().next_back().is_some_and(is_name) {
+      continue;
+    }
 
+    let rest = &content[i + 1..];
+    let end = rest.find(|c| !is_name(c)).unwrap_or(rest.len());
+    // a mention at the end of a sentence doesn't take the full stop with it
+    let name = rest[..end].trim_end_matches('.');
+
+    if !name.is_empty() && !names.contains(&name) {
+      names.push(name);
+    }
+  }
+
+  names
+}
+
+pub fn encode_client_question(question: ClientQuestion) -> Option<Vec<u8>> {
   let mut json = serde_json::to_vec(&question).ok()?;
   let len = json.len();
 
@@ -109,7 +943,155 @@ pub fn encode_server_question(question: ServerTell) -> Option<Vec<u8>> {
   Some(vec)
 }
 
+/// how many bytes `value` takes up once encoded, which is what counts against a frame
+pub fn encoded_len<T: Serialize>(value: &T) -> usize {
+  serde_json::to_vec(value).map_or(usize::MAX, |json| json.len())
+}
+
+/// as many of `items` as encode into `budget` bytes between them, in the order given,
+/// but always at least the first, or whatever pages through them could never get past it
+pub fn within_budget<T: Serialize>(items: impl IntoIterator<Item = T>, budget: usize) -> Vec<T> {
+  let mut size = 0usize;
+  let mut batch = vec![];
+
+  for item in items {
+    size = size.saturating_add(encoded_len(&item));
+    if size > budget && !batch.is_empty() {
+      break;
+    }
+    batch.push(item);
+  }
+
+  batch
+}
+
+/// cuts the end off of the text in `value` until `value` encodes into `budget` bytes,
+/// for the odd item too big to go in a batch of its own
+pub fn shorten_to_budget<T: Serialize>(
+  value: &mut T,
+  budget: usize,
+  text: fn(&mut T) -> &mut String,
+) {
+  loop {
+    let over = encoded_len(value).saturating_sub(budget);
+    let text = text(value);
+    if over == 0 || text.is_empty() {
+      return;
+    }
+
+    // a byte escapes to at most six, so cutting a sixth of what's over never cuts too much,
+    // it just may take a few goes
+    let mut len = text.len().saturating_sub(over.div_ceil(6));
+    while !text.is_char_boundary(len) {
+      len -= 1;
+    }
+    text.truncate(len);
+  }
+}
+
 pub fn decode_server_question(vec: Vec<u8>) -> Option<ServerTell> {
   let cli = serde_json::from_slice(vec.as_slice()).ok()?;
   Some(cli)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn mentions_stop_at_punctuation() {
+    assert_eq!(mentions("hi @alice, @bob: look"), ["alice", "bob"]);
+    assert_eq!(mentions("ask @carol."), ["carol"]);
+    assert_eq!(mentions("ask @carol..."), ["carol"]);
+    assert_eq!(mentions("(@dave) and @erin's"), ["dave", "erin"]);
+    // dots, dashes and underscores inside a name are part of it
+    assert_eq!(mentions("@f.g-h_i!"), ["f.g-h_i"]);
+  }
+
+  #[test]
+  fn mentions_need_an_at_at_the_start_of_a_word() {
+    assert!(mentions("mail alice@example.com").is_empty());
+    assert!(mentions("a@b c_@d 9@e").is_empty());
+    assert!(mentions("just an @ on its own, or @!").is_empty());
+    assert_eq!(mentions("@@alice"), ["alice"]);
+    assert_eq!(mentions("@alice@bob"), ["alice"]);
+  }
+
+  #[test]
+  fn mentions_are_listed_once_in_order() {
+    assert_eq!(mentions("@bob @alice @bob, again @alice"), ["bob", "alice"]);
+    assert!(mentions("").is_empty());
+  }
+
+  #[test]
+  fn mentions_of_unicode_names() {
+    assert_eq!(mentions("thanks @zoë!"), ["zoë"]);
+    assert_eq!(mentions("@名前、こんにちは"), ["名前"]);
+    assert_eq!(mentions("@Ωμέγα and @ünter"), ["Ωμέγα", "ünter"]);
+    // a letter of any script before the @ makes it part of a word
+    assert!(mentions("über@bob").is_empty());
+  }
+
+  #[test]
+  fn within_budget_takes_what_fits() {
+    // each of these encodes to four bytes, quotes included
+    let items = ["ab", "cd", "ef"];
+    assert_eq!(within_budget(items, 12), items);
+    assert_eq!(within_budget(items, 11), ["ab", "cd"]);
+    assert_eq!(within_budget(items, 8), ["ab", "cd"]);
+    assert!(within_budget(Vec::<&str>::new(), 100).is_empty());
+  }
+
+  #[test]
+  fn within_budget_always_takes_the_first() {
+    assert_eq!(within_budget(["too long", "a"], 0), ["too long"]);
+    // and stops at the first that doesn't fit, even if a later one would
+    assert_eq!(within_budget(["ab", "too long", "c"], 6), ["ab"]);
+  }
+
+  #[test]
+  fn shorten_to_budget_cuts_the_text() {
+    let mut text = "a".repeat(100);
+    shorten_to_budget(&mut text, 10, |text| text);
+    // as much as fits, quotes included
+    assert_eq!(text, "a".repeat(8));
+
+    let mut fits = "abc".to_owned();
+    shorten_to_budget(&mut fits, 10, |text| text);
+    assert_eq!(fits, "abc");
+  }
+
+  #[test]
+  fn shorten_to_budget_counts_escapes() {
+    let mut text = "\u{1}".repeat(50);
+    shorten_to_budget(&mut text, 20, |text| text);
+    assert!(encoded_len(&text) <= 20);
+    assert_eq!(text, "\u{1}".repeat(3));
+
+    let mut quoted = "\"".repeat(50);
+    shorten_to_budget(&mut quoted, 20, |text| text);
+    assert!(encoded_len(&quoted) <= 20);
+    assert_eq!(quoted, "\"".repeat(9));
+  }
+
+  #[test]
+  fn shorten_to_budget_keeps_whole_characters() {
+    let mut text = "é".repeat(50);
+    shorten_to_budget(&mut text, 11, |text| text);
+    assert!(encoded_len(&text) <= 11);
+    assert_eq!(text, "é".repeat(4));
+  }
+
+  #[test]
+  fn shorten_to_budget_leaves_the_rest_alone() {
+    let mut message = (7u64, "x".repeat(100));
+    shorten_to_budget(&mut message, 16, |message| &mut message.1);
+    // [7,"..."] takes up six bytes around the text
+    assert_eq!(message, (7, "x".repeat(10)));
+
+    // nothing left to cut still doesn't fit, but that is as short as it gets
+    let mut message = (7u64, "x".repeat(100));
+    shorten_to_budget(&mut message, 2, |message| &mut message.1);
+    assert_eq!(message, (7, String::new()));
+  }
+}
@@ -0,0 +1,351 @@
+//! the lifecycle of a single connection, shared by the client and the server
+//!
+//! this does no io of its own, each peer feeds it the questions it is about to
+//! send (or has just received) and the tells that move it between states,
+//! so the rules for what may be asked when live in exactly one place
+
+use crate::{ClientQuestion, Error, ServerTell, Success};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum State {
+  // the u64 echo handshake has not finished yet
+  #[default]
+  Handshaking,
+  // connected, but not signed in to any account, which makes it a guest
+  Anonymous,
+  // the password was right, but the account still wants a second factor
+  Challenged,
+  // connected and signed in
+  Authenticated,
+  // the connection is shutting down (or already gone), nothing may be asked anymore
+  Closing,
+}
+
+impl State {
+  /// the handshake has been echoed back, the connection may start asking questions
+  pub fn handshake_complete(&mut self) {
+    if *self == State::Handshaking {
+      *self = State::Anonymous;
+    }
+  }
+
+  /// a sign-in got the password right, and has to answer a two-factor challenge next
+  pub fn challenged(&mut self) {
+    if *self == State::Anonymous {
+      *self = State::Challenged;
+    }
+  }
+
+  /// an anonymous connection has successfully signed in
+  pub fn signed_in(&mut self) {
+    if let State::Anonymous | State::Challenged = self {
+      *self = State::Authenticated;
+    }
+  }
+
+  /// the account this connection was signed in to is gone
+  pub fn signed_out(&mut self) {
+    if let State::Authenticated | State::Challenged = self {
+      *self = State::Anonymous;
+    }
+  }
+
+  pub fn close(&mut self) {
+    *self = State::Closing;
+  }
+
+  pub fn is_authenticated(&self) -> bool {
+    *self == State::Authenticated
+  }
+
+  /// checks whether `question` may be asked in the current state,
+  /// returning the error that should be told to the client if not
+  pub fn check(&self, question: &ClientQuestion) -> Result<(), Error> {
+    match self {
+      State::Handshaking | State::Closing => Err(Error::NotConnected),
+      State::Anonymous if requires_auth(question) && !open_to_guests(question) => {
+        Err(Error::NotLoggedIn)
+      }
+      // the only way out of a challenge is answering it, or hanging up
+      State::Challenged if !matches!(question, ClientQuestion::SecondFactor { .. }) => {
+        Err(Error::TwoFactorRequired)
+      }
+      State::Authenticated if requires_anonymous(question) => Err(Error::AlreadyLoggedIn),
+      _ => Ok(()),
+    }
+  }
+
+  /// advance the state off of a tell sent by the server
+  pub fn on_tell(&mut self, tell: &ServerTell) {
+    match tell {
+      ServerTell::Success(Success::SignIn | Success::Recovered | Success::Upgraded) => {
+        self.signed_in()
+      }
+      ServerTell::Success(Success::AccountDeleted) => self.signed_out(),
+      ServerTell::Error(Error::ChallengeExpired) => self.signed_out(),
+      ServerTell::TwoFactorChallenge => self.challenged(),
+      _ => {}
+    }
+  }
+}
+
+// questions which may only be asked by a signed in connection
+fn requires_auth(question: &ClientQuestion) -> bool {
+  match question {
+    ClientQuestion::SignUp { .. }
+    | ClientQuestion::SignIn { .. }
+    | ClientQuestion::Recover { .. }
+    | ClientQuestion::SecondFactor { .. }
+    | ClientQuestion::Upgrade { .. }
+    | ClientQuestion::WhoIsID { .. }
+    | ClientQuestion::WhoIsName { .. }
+    | ClientQuestion::WhoAmI
+    | ClientQuestion::Directory { .. }
+    | ClientQuestion::NumConnected
+    | ClientQuestion::PresenceOf { .. } => false,
+
+    ClientQuestion::UpdateProfile { .. }
+    | ClientQuestion::WatchPresence { .. }
+    | ClientQuestion::UnwatchPresence { .. }
+    | ClientQuestion::SetAway { .. }
+    | ClientQuestion::FailedSignIns
+    | ClientQuestion::ListSessions
+    | ClientQuestion::ChangePassword { .. }
+    | ClientQuestion::Rename { .. }
+    | ClientQuestion::DeleteAccount { .. }
+    | ClientQuestion::NewRecoveryCodes { .. }
+    | ClientQuestion::EnableTwoFactor { .. }
+    | ClientQuestion::ConfirmTwoFactor { .. }
+    | ClientQuestion::DisableTwoFactor { .. }
+    | ClientQuestion::CreateInvite { .. }
+    | ClientQuestion::KickSession { .. }
+    | ClientQuestion::JoinRoom { .. }
+    | ClientQuestion::LeaveRoom { .. }
+    | ClientQuestion::Say { .. }
+    | ClientQuestion::EditMessage { .. }
+    | ClientQuestion::DeleteMessage { .. }
+    | ClientQuestion::History { .. }
+    | ClientQuestion::Thread { .. }
+    | ClientQuestion::Mentions
+    | ClientQuestion::MarkRead { .. }
+    | ClientQuestion::Unread
+    | ClientQuestion::Search { .. }
+    | ClientQuestion::BeginUpload { .. }
+    | ClientQuestion::UploadChunk { .. }
+    | ClientQuestion::FinishUpload { .. }
+    | ClientQuestion::Download { .. }
+    | ClientQuestion::Moderate { .. }
+    | ClientQuestion::SetRole { .. }
+    | ClientQuestion::AuditLog { .. }
+    | ClientQuestion::AllowGuests { .. }
+    | ClientQuestion::React { .. }
+    | ClientQuestion::Unreact { .. }
+    | ClientQuestion::TypingStarted { .. }
+    | ClientQuestion::TypingStopped { .. } => true,
+  }
+}
+
+// questions which make no sense once signed in
+fn requires_anonymous(question: &ClientQuestion) -> bool {
+  matches!(
+    question,
+    ClientQuestion::SignUp { .. }
+      | ClientQuestion::SignIn { .. }
+      | ClientQuestion::Recover { .. }
+      | ClientQuestion::SecondFactor { .. }
+      | ClientQuestion::Upgrade { .. }
+  )
+}
+
+/// the questions that need a signed in connection, but which a guest may ask too,
+/// in rooms that let guests in
+pub fn open_to_guests(question: &ClientQuestion) -> bool {
+  matches!(
+    question,
+    ClientQuestion::JoinRoom { .. }
+      | ClientQuestion::LeaveRoom { .. }
+      | ClientQuestion::Say { .. }
+      | ClientQuestion::History { .. }
+      | ClientQuestion::Thread { .. }
+      | ClientQuestion::TypingStarted { .. }
+      | ClientQuestion::TypingStopped { .. }
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sign_in() -> ClientQuestion {
+    ClientQuestion::SignIn {
+      username: "alice".into(),
+      password: "hunter22".into(),
+    }
+  }
+
+  fn join() -> ClientQuestion {
+    ClientQuestion::JoinRoom {
+      name: "lobby".into(),
+    }
+  }
+
+  fn signed_in() -> State {
+    let mut state = State::default();
+    state.handshake_complete();
+    state.signed_in();
+    state
+  }
+
+  #[test]
+  fn starts_out_handshaking() {
+    let state = State::default();
+    assert_eq!(state, State::Handshaking);
+    assert!(matches!(state.check(&sign_in()), Err(Error::NotConnected)));
+    assert!(matches!(
+      state.check(&ClientQuestion::NumConnected),
+      Err(Error::NotConnected)
+    ));
+  }
+
+  #[test]
+  fn anonymous_may_sign_in_but_not_use_an_account() {
+    let mut state = State::default();
+    state.handshake_complete();
+    assert_eq!(state, State::Anonymous);
+
+    assert!(state.check(&sign_in()).is_ok());
+    assert!(state.check(&ClientQuestion::WhoAmI).is_ok());
+    assert!(matches!(
+      state.check(&ClientQuestion::Mentions),
+      Err(Error::NotLoggedIn)
+    ));
+  }
+
+  #[test]
+  fn anonymous_may_ask_what_guests_may() {
+    let mut state = State::default();
+    state.handshake_complete();
+    assert!(state.check(&join()).is_ok());
+    assert!(state.check(&ClientQuestion::LeaveRoom { room: 1 }).is_ok());
+  }
+
+  #[test]
+  fn challenged_has_to_answer_first() {
+    let mut state = State::default();
+    state.handshake_complete();
+    state.challenged();
+    assert_eq!(state, State::Challenged);
+
+    let answer = ClientQuestion::SecondFactor {
+      code: "123456".into(),
+    };
+    assert!(state.check(&answer).is_ok());
+    assert!(matches!(
+      state.check(&ClientQuestion::Mentions),
+      Err(Error::TwoFactorRequired)
+    ));
+    // not even the questions guests may ask, or the ones anybody may
+    for question in [
+      join(),
+      sign_in(),
+      ClientQuestion::WhoAmI,
+      ClientQuestion::NumConnected,
+      ClientQuestion::Upgrade {
+        username: "alice".into(),
+        password: "hunter22".into(),
+        invite: None,
+      },
+    ] {
+      assert!(matches!(
+        state.check(&question),
+        Err(Error::TwoFactorRequired)
+      ));
+    }
+
+    state.signed_in();
+    assert_eq!(state, State::Authenticated);
+  }
+
+  #[test]
+  fn authenticated_may_not_sign_in_again() {
+    let state = signed_in();
+    assert!(state.is_authenticated());
+    assert!(state.check(&ClientQuestion::Mentions).is_ok());
+    assert!(state.check(&join()).is_ok());
+    assert!(matches!(
+      state.check(&sign_in()),
+      Err(Error::AlreadyLoggedIn)
+    ));
+  }
+
+  #[test]
+  fn signing_out_goes_back_to_anonymous() {
+    let mut state = signed_in();
+    state.signed_out();
+    assert_eq!(state, State::Anonymous);
+
+    let mut state = State::default();
+    state.handshake_complete();
+    state.challenged();
+    state.signed_out();
+    assert_eq!(state, State::Anonymous);
+  }
+
+  #[test]
+  fn transitions_out_of_turn_are_ignored() {
+    let mut state = State::default();
+    state.challenged();
+    state.signed_in();
+    state.signed_out();
+    assert_eq!(state, State::Handshaking);
+
+    let mut state = signed_in();
+    state.handshake_complete();
+    state.challenged();
+    assert_eq!(state, State::Authenticated);
+  }
+
+  #[test]
+  fn closing_is_final() {
+    let mut state = signed_in();
+    state.close();
+    assert_eq!(state, State::Closing);
+    assert!(!state.is_authenticated());
+
+    state.handshake_complete();
+    state.signed_in();
+    assert_eq!(state, State::Closing);
+    assert!(matches!(
+      state.check(&ClientQuestion::WhoAmI),
+      Err(Error::NotConnected)
+    ));
+  }
+
+  #[test]
+  fn tells_move_the_state() {
+    let mut state = State::default();
+    state.handshake_complete();
+
+    state.on_tell(&ServerTell::TwoFactorChallenge);
+    assert_eq!(state, State::Challenged);
+    state.on_tell(&ServerTell::Success(Success::SignIn));
+    assert_eq!(state, State::Authenticated);
+    state.on_tell(&ServerTell::Error(Error::ServerError));
+    assert_eq!(state, State::Authenticated);
+    state.on_tell(&ServerTell::Success(Success::AccountDeleted));
+    assert_eq!(state, State::Anonymous);
+
+    state.on_tell(&ServerTell::TwoFactorChallenge);
+    state.on_tell(&ServerTell::Error(Error::InvalidTwoFactorCode));
+    assert_eq!(state, State::Challenged);
+    state.on_tell(&ServerTell::Error(Error::ChallengeExpired));
+    assert_eq!(state, State::Anonymous);
+
+    for success in [Success::Recovered, Success::Upgraded] {
+      let mut state = State::default();
+      state.handshake_complete();
+      state.on_tell(&ServerTell::Success(success));
+      assert_eq!(state, State::Authenticated);
+    }
+  }
+}
@@ -0,0 +1,7 @@
+-- the users table predates migrations, so only create it if it isn't there yet
+create table if not exists users (
+  uid bigint primary key,
+  name text not null,
+  salt text not null,
+  hash bytea not null
+);
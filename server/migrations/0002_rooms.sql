@@ -0,0 +1,10 @@
+create table rooms (
+  id bigint primary key,
+  name text not null unique
+);
+
+create table room_members (
+  room bigint not null references rooms (id) on delete cascade,
+  uid bigint not null,
+  primary key (room, uid)
+);
@@ -0,0 +1,22 @@
+-- moderators may delete anyone's messages
+alter table users add column if not exists moderator boolean not null default false;
+
+-- exactly one of room or recipient is set, recipient being the uid a dm was sent to
+create table messages (
+  id bigint primary key,
+  author bigint not null,
+  room bigint references rooms (id) on delete cascade,
+  recipient bigint,
+  content text not null,
+  sent_at timestamptz not null default now(),
+  edited_at timestamptz,
+  deleted_at timestamptz,
+  check ((room is null) != (recipient is null))
+);
+
+-- every version a message had before it was edited (or deleted)
+create table message_edits (
+  message bigint not null references messages (id) on delete cascade,
+  content text not null,
+  replaced_at timestamptz not null default now()
+);
@@ -0,0 +1,7 @@
+-- each user can react to a message with each emoji at most once
+create table reactions (
+  message bigint not null references messages (id) on delete cascade,
+  uid bigint not null,
+  emoji text not null,
+  primary key (message, uid, emoji)
+);
@@ -0,0 +1,6 @@
+-- replies always point at the root of their thread, never at another reply
+alter table messages add column reply_to bigint references messages (id) on delete cascade;
+alter table messages add column reply_count integer not null default 0;
+alter table messages add column last_reply_at timestamptz;
+
+create index messages_reply_to on messages (reply_to);
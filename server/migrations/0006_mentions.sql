@@ -0,0 +1,9 @@
+-- everyone a message mentioned, delivered once any of their connections has been told about it
+create table mentions (
+  message bigint not null references messages (id) on delete cascade,
+  uid bigint not null,
+  delivered boolean not null default false,
+  primary key (message, uid)
+);
+
+create index mentions_undelivered on mentions (uid) where not delivered;
@@ -0,0 +1,7 @@
+-- the last message each member has read in each room, shared by all of their devices
+create table read_markers (
+  room bigint not null references rooms (id) on delete cascade,
+  uid bigint not null,
+  message bigint not null references messages (id) on delete cascade,
+  primary key (room, uid)
+);
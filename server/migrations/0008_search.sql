@@ -0,0 +1,5 @@
+-- kept up to date by postgres itself, so edits and deletions are searched as they stand
+alter table messages add column search tsvector
+  generated always as (to_tsvector('english', content)) stored;
+
+create index messages_search on messages using gin (search);
@@ -0,0 +1,17 @@
+-- the files themselves live on disk, named by their hash, so identical uploads share one
+create table attachments (
+  id bigint primary key,
+  uploader bigint not null,
+  name text not null,
+  size bigint not null,
+  hash text not null,
+  uploaded_at timestamptz not null default now()
+);
+
+create index attachments_uploader on attachments (uploader);
+
+create table message_attachments (
+  message bigint not null references messages (id) on delete cascade,
+  attachment bigint not null references attachments (id) on delete cascade,
+  primary key (message, attachment)
+);
@@ -0,0 +1,56 @@
+-- a role is a named set of permissions, held server-wide or in a single room
+-- permissions are kick, ban, mute, delete_messages and manage_room
+create table roles (
+  name text primary key,
+  permissions text[] not null
+);
+
+insert into roles values
+  ('admin', '{kick, ban, mute, delete_messages, manage_room}'),
+  ('moderator', '{kick, ban, mute, delete_messages}');
+
+-- server-wide roles apply in every room, the first admin has to be put in here by hand
+create table server_roles (
+  uid bigint primary key,
+  role text not null references roles (name)
+);
+
+create table room_roles (
+  room bigint not null references rooms (id) on delete cascade,
+  uid bigint not null,
+  role text not null references roles (name),
+  primary key (room, uid)
+);
+
+-- the old moderator flag becomes the moderator role
+insert into server_roles select uid, 'moderator' from users where moderator;
+alter table users drop column moderator;
+
+-- no expiry means for good
+create table room_bans (
+  room bigint not null references rooms (id) on delete cascade,
+  uid bigint not null,
+  expires_at timestamptz,
+  primary key (room, uid)
+);
+
+create table room_mutes (
+  room bigint not null references rooms (id) on delete cascade,
+  uid bigint not null,
+  expires_at timestamptz,
+  primary key (room, uid)
+);
+
+-- every moderation action, kept even after whatever it was about is gone
+create table moderation_log (
+  id bigserial primary key,
+  actor bigint not null,
+  action text not null,
+  room bigint,
+  target bigint,
+  message bigint,
+  reason text,
+  at timestamptz not null default now()
+);
+
+create index moderation_log_room on moderation_log (room, at);
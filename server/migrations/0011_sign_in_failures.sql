@@ -0,0 +1,12 @@
+-- failed sign-ins, by account whenever the name was real and always by address
+create table sign_in_failures (
+  id bigserial primary key,
+  uid bigint,
+  address text not null,
+  at timestamptz not null default now(),
+  -- whether the account's owner has been shown it, which also stops it counting against them
+  reported boolean not null default false
+);
+
+create index sign_in_failures_uid on sign_in_failures (uid, at);
+create index sign_in_failures_address on sign_in_failures (address, at);
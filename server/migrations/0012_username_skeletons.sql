@@ -0,0 +1,5 @@
+-- what a username looks like once confusable characters are folded together,
+-- filled in for older accounts when the server starts
+alter table users add column skeleton text;
+
+create index users_skeleton on users (skeleton);
@@ -0,0 +1,13 @@
+-- signup used to check names with a query that never matched, so a name can have been taken twice
+-- every account but one that shares a name gets its uid tacked on to tell them apart
+update users u set name = u.name || '-' || u.uid, skeleton = null
+  where exists (select 1 from users o where o.name = u.name and o.uid < u.uid);
+
+-- lookalikes from before skeletons were checked keep their names, but only one keeps its skeleton
+update users u set skeleton = null
+  where exists (select 1 from users o where o.skeleton = u.skeleton and o.uid < u.uid);
+
+create unique index users_name_key on users (name);
+
+drop index users_skeleton;
+create unique index users_skeleton on users (skeleton);
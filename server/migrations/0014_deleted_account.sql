@@ -0,0 +1,3 @@
+-- messages of deleted accounts are kept, but under this one instead
+-- nobody can sign in as it, no password hashes to nothing and the name fails the username policy
+insert into users (uid, name, salt, hash) values (0, '[deleted]', '', '') on conflict do nothing;
@@ -0,0 +1,6 @@
+-- one-time codes to get back into an account without its password, only ever stored hashed
+create table recovery_codes (
+  uid bigint not null,
+  hash bytea not null,
+  primary key (uid, hash)
+);
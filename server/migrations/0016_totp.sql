@@ -0,0 +1,8 @@
+-- an account's totp secret, only asked for at sign-in once it has been confirmed with a first code
+create table totp_secrets (
+  uid bigint primary key,
+  secret bytea not null,
+  confirmed boolean not null default false,
+  -- the last time step a code was accepted for, so no code works twice
+  last_step bigint not null default 0
+);
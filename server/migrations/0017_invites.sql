@@ -0,0 +1,13 @@
+-- minting invites is a server-wide permission, admins have it and so does the inviter role
+update roles set permissions = array_append(permissions, 'invite')
+  where name = 'admin' and not 'invite' = any(permissions);
+insert into roles values ('inviter', '{invite}') on conflict do nothing;
+
+-- no uses_left means as many sign-ups as happen before it expires, no expiry means for good
+create table invites (
+  code text primary key,
+  created_by bigint not null,
+  uses_left integer,
+  expires_at timestamptz,
+  created_at timestamptz not null default now()
+);
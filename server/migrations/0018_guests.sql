@@ -0,0 +1,6 @@
+-- guests get a row of their own once they join a room, so what they say has an author,
+-- it goes away with them unless they said something, in which case it is only renamed
+alter table users add column guest boolean not null default false;
+
+-- rooms are closed to guests until someone who can manage them says otherwise
+alter table rooms add column allow_guests boolean not null default false;
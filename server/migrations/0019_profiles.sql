@@ -0,0 +1,12 @@
+-- accounts from before profiles all get the time this ran as when they were made
+alter table users
+  add column display_name text,
+  add column bio text,
+  add column pronouns text,
+  add column status text,
+  add column avatar bigint,
+  add column created_at timestamptz not null default now();
+
+-- the directory matches usernames by prefix and by trigram similarity, both of which this serves
+create extension if not exists pg_trgm;
+create index users_name_trgm on users using gin (name gin_trgm_ops);
@@ -0,0 +1,7 @@
+-- roles are ordered, nobody can change the role of someone ranked the same or higher,
+-- roles made by hand start out at the bottom until given a rank
+alter table roles add column rank integer not null default 0;
+
+update roles set rank = 3 where name = 'admin';
+update roles set rank = 2 where name = 'moderator';
+update roles set rank = 1 where name = 'inviter';
@@ -0,0 +1,273 @@
+use std::{
+  net::IpAddr,
+  path::{Path, PathBuf},
+  str::FromStr,
+  time::SystemTime,
+};
+
+use directories::ProjectDirs;
+use tokio::{fs, io};
+
+// one address per line, or a whole network in CIDR notation, after allow or deny:
+//   # anything after a hash is a comment
+//   deny 203.0.113.0/24
+//   allow 10.0.0.0/8
+// deny always wins, and once anything is allowed everything else is denied
+pub fn access_list_path() -> PathBuf {
+  ProjectDirs::from("", "", "yacs2")
+    .map(|dirs| dirs.config_dir().join("access.list"))
+    .unwrap_or_else(|| PathBuf::from("access.list"))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+  network: IpAddr,
+  prefix: u32,
+}
+
+impl Cidr {
+  pub fn contains(&self, address: IpAddr) -> bool {
+    // v4 peers on a dual stack socket show up as v4-mapped v6 addresses
+    match (self.network, address.to_canonical()) {
+      (IpAddr::V4(network), IpAddr::V4(address)) => {
+        let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+        u32::from(network) & mask == u32::from(address) & mask
+      }
+      (IpAddr::V6(network), IpAddr::V6(address)) => {
+        let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+        u128::from(network) & mask == u128::from(address) & mask
+      }
+      _ => false,
+    }
+  }
+}
+
+impl FromStr for Cidr {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (network, prefix) = match s.split_once('/') {
+      Some((network, prefix)) => (network, Some(prefix)),
+      None => (s, None),
+    };
+
+    let network = IpAddr::from_str(network).map_err(|_| ())?.to_canonical();
+    let bits = if network.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(prefix) => prefix.parse().map_err(|_| ())?,
+      None => bits,
+    };
+
+    if prefix > bits {
+      return Err(());
+    }
+
+    Ok(Self { network, prefix })
+  }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct AccessList {
+  allow: Vec<Cidr>,
+  deny: Vec<Cidr>,
+  // when the file was last read, so it is only read again once it changes
+  modified: Option<SystemTime>,
+}
+
+impl AccessList {
+  pub fn permits(&self, address: IpAddr) -> bool {
+    if self.deny.iter().any(|cidr| cidr.contains(address)) {
+      return false;
+    }
+
+    self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(address))
+  }
+
+  // reads the list again if the file changed since the last time, saying whether it did,
+  // a missing file means no restrictions at all
+  pub async fn reload(&mut self) -> io::Result<bool> {
+    let path = access_list_path();
+
+    let modified = match fs::metadata(&path).await {
+      Ok(metadata) => Some(metadata.modified()?),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+      Err(e) => return Err(e),
+    };
+
+    if modified == self.modified {
+      return Ok(false);
+    }
+
+    let contents = match modified {
+      Some(_) => fs::read_to_string(&path).await?,
+      None => String::new(),
+    };
+
+    *self = Self {
+      modified,
+      ..Self::parse(&contents, &path)
+    };
+    Ok(true)
+  }
+
+  // a line that doesn't make sense is skipped, the rest of the list still applies
+  fn parse(contents: &str, path: &Path) -> Self {
+    let (mut allow, mut deny) = (vec![], vec![]);
+    for (number, line) in contents.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or_default().trim();
+      if line.is_empty() {
+        continue;
+      }
+
+      let entry = match line.split_once(char::is_whitespace) {
+        Some(("allow", cidr)) => cidr.trim().parse().map(|cidr| (&mut allow, cidr)),
+        Some(("deny", cidr)) => cidr.trim().parse().map(|cidr| (&mut deny, cidr)),
+        _ => Err(()),
+      };
+
+      match entry {
+        Ok((list, cidr)) => list.push(cidr),
+        Err(()) => eprintln!("Skipping line {} of {}: {line}", number + 1, path.display()),
+      }
+    }
+
+    Self {
+      allow,
+      deny,
+      modified: None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cidr(s: &str) -> Cidr {
+    s.parse().unwrap()
+  }
+
+  fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+  }
+
+  fn list(contents: &str) -> AccessList {
+    AccessList::parse(contents, Path::new("access.list"))
+  }
+
+  #[test]
+  fn v4_prefixes() {
+    let network = cidr("203.0.113.0/24");
+    assert!(network.contains(ip("203.0.113.0")));
+    assert!(network.contains(ip("203.0.113.255")));
+    assert!(!network.contains(ip("203.0.114.0")));
+    assert!(!network.contains(ip("203.0.112.255")));
+
+    // host bits in the network don't matter
+    assert!(cidr("10.1.2.3/8").contains(ip("10.200.0.1")));
+    assert!(cidr("10.0.0.0/9").contains(ip("10.127.0.1")));
+    assert!(!cidr("10.0.0.0/9").contains(ip("10.128.0.1")));
+  }
+
+  #[test]
+  fn v6_prefixes() {
+    let network = cidr("2001:db8::/32");
+    assert!(network.contains(ip("2001:db8::1")));
+    assert!(network.contains(ip("2001:db8:ffff:ffff::1")));
+    assert!(!network.contains(ip("2001:db9::1")));
+
+    assert!(cidr("2001:db8:0:80::/57").contains(ip("2001:db8:0:ff::1")));
+    assert!(!cidr("2001:db8:0:80::/57").contains(ip("2001:db8:0:7f::1")));
+  }
+
+  #[test]
+  fn whole_and_single_addresses() {
+    assert!(cidr("0.0.0.0/0").contains(ip("198.51.100.7")));
+    assert!(cidr("::/0").contains(ip("2001:db8::1")));
+    // /0 of one family still says nothing about the other
+    assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+    assert!(!cidr("::/0").contains(ip("198.51.100.7")));
+
+    assert!(cidr("198.51.100.7/32").contains(ip("198.51.100.7")));
+    assert!(!cidr("198.51.100.7/32").contains(ip("198.51.100.8")));
+    assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+    assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+
+    // a bare address is a single one
+    assert!(cidr("198.51.100.7").contains(ip("198.51.100.7")));
+    assert!(!cidr("198.51.100.7").contains(ip("198.51.100.6")));
+    assert!(!cidr("2001:db8::1").contains(ip("2001:db8::2")));
+  }
+
+  #[test]
+  fn v4_mapped_addresses_are_v4() {
+    assert!(cidr("198.51.100.0/24").contains(ip("::ffff:198.51.100.7")));
+    assert!(cidr("::ffff:198.51.100.0/24").contains(ip("198.51.100.7")));
+  }
+
+  #[test]
+  fn malformed_entries() {
+    for s in [
+      "",
+      "/24",
+      "198.51.100.0/",
+      "198.51.100.0/33",
+      "2001:db8::/129",
+      "198.51.100.0/-1",
+      "198.51.100.0/24/8",
+      "198.51.100.256",
+      "example.com",
+    ] {
+      assert!(s.parse::<Cidr>().is_err(), "{s} should not parse");
+    }
+
+    // only the broken lines are skipped
+    let access = list(
+      "deny 198.51.100.0/33
+       deny
+       block 203.0.113.0/24
+       allow 10.0.0.0/8 # the office
+       allow banana
+",
+    );
+    assert!(access.deny.is_empty());
+    assert_eq!(access.allow.len(), 1);
+    assert!(access.permits(ip("10.1.1.1")));
+    assert!(!access.permits(ip("203.0.113.1")));
+  }
+
+  #[test]
+  fn empty_permits_everyone() {
+    let access = list(
+      "# nothing yet
+
+",
+    );
+    assert!(access.permits(ip("198.51.100.7")));
+    assert!(access.permits(ip("2001:db8::1")));
+  }
+
+  #[test]
+  fn deny_wins_over_allow() {
+    let access = list(
+      "allow 10.0.0.0/8
+       deny 10.0.0.0/24
+       allow 10.0.0.7
+",
+    );
+    assert!(access.permits(ip("10.1.0.1")));
+    assert!(!access.permits(ip("10.0.0.1")));
+    assert!(!access.permits(ip("10.0.0.7")));
+    // once anything is allowed, everything else is denied
+    assert!(!access.permits(ip("192.0.2.1")));
+    assert!(!access.permits(ip("2001:db8::1")));
+
+    let access = list(
+      "deny 2001:db8::/32
+",
+    );
+    assert!(!access.permits(ip("2001:db8::1")));
+    assert!(access.permits(ip("2001:db9::1")));
+    assert!(access.permits(ip("192.0.2.1")));
+  }
+}
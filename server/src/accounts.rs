@@ -0,0 +1,273 @@
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha512};
+use sqlx::{pool::PoolConnection, Connection, PgPool, Postgres, Row};
+
+use crate::{connection::Uid, ids, invites, policy};
+
+pub fn hash_password(password: &str, salt: &str) -> Vec<u8> {
+  let mut hasher = Sha512::new();
+  hasher.update(password);
+  hasher.update(salt);
+  hasher.finalize().to_vec()
+}
+
+pub fn new_salt() -> String {
+  rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(5)
+    .map(char::from)
+    .collect()
+}
+
+// the unique constraint a failed query ran into, if that is why it failed
+pub fn violated_constraint(error: &sqlx::Error) -> Option<&str> {
+  match error {
+    sqlx::Error::Database(e) => e.constraint(),
+    _ => None,
+  }
+}
+
+// creates an account for an already normalized and checked username,
+// leaving it to the database to turn away names that are taken or look like one that is
+// an invite, if given, is only used up if the account is made
+pub async fn create(
+  db: &mut PoolConnection<Postgres>,
+  username: &str,
+  password: &str,
+  invite: Option<&str>,
+) -> Result<Uid, convos::Error> {
+  let uid = ids::next();
+  insert(db, uid, username, password, invite).await?;
+  Ok(uid)
+}
+
+// the same as creating an account, but it keeps the guest's uid,
+// and with it the rooms the guest joined and what it said
+pub async fn upgrade(
+  db: &mut PoolConnection<Postgres>,
+  guest: Uid,
+  username: &str,
+  password: &str,
+  invite: Option<&str>,
+) -> Result<(), convos::Error> {
+  insert(db, guest, username, password, invite).await
+}
+
+async fn insert(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  username: &str,
+  password: &str,
+  invite: Option<&str>,
+) -> Result<(), convos::Error> {
+  let salt = new_salt();
+  let hash = hash_password(password, &salt);
+  let skeleton = policy::skeleton_of(username);
+
+  let created = async {
+    let mut tx = db.begin().await?;
+
+    if let Some(invite) = invite {
+      if !invites::redeem(&mut tx, invite).await? {
+        return Ok(false);
+      }
+    }
+
+    // only ever a guest's row is there already
+    sqlx::query(
+      "insert into users (uid, name, salt, hash, skeleton) values ($1, $2, $3, $4, $5)
+        on conflict (uid) do update
+        set name=$2, salt=$3, hash=$4, skeleton=$5, guest=false, created_at=now()
+        where users.guest",
+    )
+    .bind(uid as i64)
+    .bind(username)
+    .bind(&salt)
+    .bind(&hash)
+    .bind(&skeleton)
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await.map(|()| true)
+  }
+  .await;
+
+  match created {
+    Ok(true) => Ok(()),
+    Ok(false) => Err(convos::Error::InvalidInvite),
+    Err(e) => Err(conflict(db, username, e).await),
+  }
+}
+
+// what went wrong giving an account `username`, taken names being the usual suspects
+async fn conflict(
+  db: &mut PoolConnection<Postgres>,
+  username: &str,
+  e: sqlx::Error,
+) -> convos::Error {
+  match violated_constraint(&e) {
+    Some("users_name_key") => convos::Error::UsernameTaken,
+    // the name itself being taken can trip this one first
+    Some("users_skeleton") => match policy::lookalike(db, username).await {
+      Ok(Some(existing)) if existing == username => convos::Error::UsernameTaken,
+      _ => convos::Error::UsernameRejected {
+        reason: convos::UsernameProblem::TooSimilar,
+      },
+    },
+    _ => {
+      eprintln!("Ran into error when trying to give an account the name {username}: {e}");
+      convos::Error::ServerError
+    }
+  }
+}
+
+// the account going by `username`, which is never a guest or the one standing in for deleted ones
+pub async fn uid_of(
+  db: &mut PoolConnection<Postgres>,
+  username: &str,
+) -> sqlx::Result<Option<Uid>> {
+  let row = sqlx::query("select uid from users where name=$1 and not guest and uid != 0")
+    .bind(username)
+    .fetch_optional(db)
+    .await?;
+
+  Ok(row.map(|row| row.get::<i64, _>("uid") as u64))
+}
+
+// the account here for one that is kept by another authenticator,
+// with a random password nobody knows since it is never checked,
+// under a name held to the same rules as any other
+pub async fn provision(
+  db: &mut PoolConnection<Postgres>,
+  username: &str,
+) -> Result<Uid, convos::Error> {
+  let username = policy::normalize(username);
+  if let Err(reason) = policy::check_username(&username) {
+    return Err(convos::Error::UsernameRejected { reason });
+  }
+
+  let password: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(32)
+    .map(char::from)
+    .collect();
+
+  create(db, &username, &password, None).await
+}
+
+pub async fn set_password(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  password: &str,
+) -> sqlx::Result<()> {
+  let salt = new_salt();
+  sqlx::query("update users set salt=$2, hash=$3 where uid=$1")
+    .bind(uid as i64)
+    .bind(&salt)
+    .bind(hash_password(password, &salt))
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+// gives an account an already normalized and checked username, under the same rules as signing up
+pub async fn rename(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  username: &str,
+) -> Result<(), convos::Error> {
+  let renamed = sqlx::query("update users set name=$2, skeleton=$3 where uid=$1")
+    .bind(uid as i64)
+    .bind(username)
+    .bind(policy::skeleton_of(username))
+    .execute(&mut *db)
+    .await;
+
+  match renamed {
+    Ok(_) => Ok(()),
+    Err(e) => Err(conflict(db, username, e).await),
+  }
+}
+
+// a guest's row, made the first time it joins a room, there is no password that gets in
+pub async fn add_guest(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  name: &str,
+) -> sqlx::Result<()> {
+  sqlx::query(
+    "insert into users (uid, name, salt, hash, guest) values ($1, $2, '', '', true)
+      on conflict (uid) do nothing",
+  )
+  .bind(uid as i64)
+  .bind(name)
+  .execute(db)
+  .await?;
+
+  Ok(())
+}
+
+// a guest is gone for good once its connection is, or it signed in as someone else,
+// its row only stays if it said something, under a name that frees up the one it had
+pub async fn retire_guest(db: &mut PoolConnection<Postgres>, uid: Uid) -> sqlx::Result<()> {
+  let mut tx = db.begin().await?;
+
+  for query in [
+    "delete from mentions where uid = $1",
+    "delete from read_markers where uid = $1",
+    "delete from room_members where uid = $1",
+    "delete from room_roles where uid = $1",
+    "delete from room_bans where uid = $1",
+    "delete from room_mutes where uid = $1",
+    "delete from sign_in_failures where uid = $1",
+    "delete from users where uid = $1 and guest
+      and not exists (select 1 from messages where author = $1)",
+    "update users set name = 'guest-' || uid where uid = $1 and guest",
+  ] {
+    sqlx::query(query).bind(uid as i64).execute(&mut tx).await?;
+  }
+
+  tx.commit().await
+}
+
+// guests left over from before the server last stopped, whose connections are long gone
+pub async fn retire_guests(db: &PgPool) -> sqlx::Result<()> {
+  let guests = sqlx::query("select uid from users where guest and name <> 'guest-' || uid")
+    .fetch_all(db)
+    .await?;
+
+  let mut conn = db.acquire().await?;
+  for guest in guests {
+    retire_guest(&mut conn, guest.get::<i64, _>("uid") as Uid).await?;
+  }
+
+  Ok(())
+}
+
+// deletes an account along with everything that only mattered to it,
+// what it said stays, but as said by the deleted account
+pub async fn delete(db: &mut PoolConnection<Postgres>, uid: Uid) -> sqlx::Result<()> {
+  let mut tx = db.begin().await?;
+
+  for query in [
+    "update messages set author = 0 where author = $1",
+    "update attachments set uploader = 0 where uploader = $1",
+    "delete from reactions where uid = $1",
+    "delete from mentions where uid = $1",
+    "delete from read_markers where uid = $1",
+    "delete from room_members where uid = $1",
+    "delete from room_roles where uid = $1",
+    "delete from server_roles where uid = $1",
+    "delete from room_bans where uid = $1",
+    "delete from room_mutes where uid = $1",
+    "delete from sign_in_failures where uid = $1",
+    "delete from recovery_codes where uid = $1",
+    "delete from totp_secrets where uid = $1",
+    "delete from users where uid = $1",
+  ] {
+    sqlx::query(query).bind(uid as i64).execute(&mut tx).await?;
+  }
+
+  tx.commit().await
+}
@@ -0,0 +1,393 @@
+use std::{
+  collections::HashMap,
+  io,
+  path::PathBuf,
+  sync::{Arc, RwLock},
+};
+
+use convos::Attachment;
+use directories::ProjectDirs;
+use sha2::{Digest, Sha256};
+use sqlx::{pool::PoolConnection, Connection, PgConnection, Postgres, Row};
+use tokio::{
+  fs::{self, File},
+  io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+  sync::Mutex,
+};
+
+use crate::{
+  connection::{ConID, Uid},
+  ids,
+};
+
+// the biggest single file anyone may upload
+pub const MAX_ATTACHMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+// how much everything a user has uploaded may add up to
+pub const QUOTA: u64 = 256 * 1024 * 1024;
+
+// the most attachments one message can carry
+pub const MAX_PER_MESSAGE: usize = 10;
+
+// where attachments are kept, each named by its hash,
+// with uploads that are still in progress off to the side in partial/
+fn store_dir() -> PathBuf {
+  ProjectDirs::from("", "", "yacs2")
+    .map(|dirs| dirs.data_dir().join("attachments"))
+    .unwrap_or_else(|| PathBuf::from("attachments"))
+}
+
+fn stored_path(hash: &str) -> PathBuf {
+  store_dir().join(hash)
+}
+
+pub fn is_valid_hash(hash: &str) -> bool {
+  hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub struct Upload {
+  pub name: String,
+  pub size: u64,
+  pub received: u64,
+  pub hash: String,
+  hasher: Sha256,
+  file: File,
+  path: PathBuf,
+}
+
+impl Upload {
+  pub async fn create(id: u64, name: String, size: u64, hash: String) -> io::Result<Self> {
+    let partial = store_dir().join("partial");
+    fs::create_dir_all(&partial).await?;
+
+    let path = partial.join(id.to_string());
+    Ok(Self {
+      name,
+      size,
+      received: 0,
+      hash,
+      hasher: Sha256::new(),
+      file: File::create(&path).await?,
+      path,
+    })
+  }
+
+  // chunks have to come in order, and can't run past the size given up front
+  pub fn accepts(&self, offset: u64, len: usize) -> bool {
+    offset == self.received && self.received + len as u64 <= self.size
+  }
+
+  pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+    self.file.write_all(data).await?;
+    self.hasher.update(data);
+    self.received += data.len() as u64;
+    Ok(())
+  }
+
+  // moves the file into the store if it all arrived and matches its hash,
+  // returning false (and throwing it away) if it didn't
+  pub async fn finish(&mut self) -> io::Result<bool> {
+    self.file.flush().await?;
+
+    let hash = format!("{:x}", self.hasher.clone().finalize());
+    if self.received != self.size || hash != self.hash {
+      fs::remove_file(&self.path).await?;
+      return Ok(false);
+    }
+
+    // somebody already uploaded the exact same thing, no need to keep two
+    let stored = stored_path(&hash);
+    if fs::try_exists(&stored).await? {
+      fs::remove_file(&self.path).await?;
+    } else {
+      fs::rename(&self.path, &stored).await?;
+    }
+
+    Ok(true)
+  }
+}
+
+struct Pending {
+  con_id: ConID,
+  uid: Uid,
+  size: u64,
+  upload: Arc<Mutex<Upload>>,
+  // handed over to be finished, it counts against the quota until it is recorded
+  finishing: bool,
+}
+
+// uploads in progress, shared the same way as the sessions, so nothing in here may await
+// each upload has its own lock for writing chunks while this one is not held
+#[derive(Default)]
+pub struct Uploads {
+  active: HashMap<u64, Pending>,
+}
+
+impl Uploads {
+  pub fn insert(&mut self, id: u64, con_id: ConID, uid: Uid, upload: Upload) {
+    self.active.insert(
+      id,
+      Pending {
+        con_id,
+        uid,
+        size: upload.size,
+        upload: Arc::new(Mutex::new(upload)),
+        finishing: false,
+      },
+    );
+  }
+
+  // only the connection that began an upload gets to touch it, and only until it is finishing
+  pub fn get(&self, id: u64, con_id: ConID) -> Option<Arc<Mutex<Upload>>> {
+    self
+      .active
+      .get(&id)
+      .filter(|pending| pending.con_id == con_id && !pending.finishing)
+      .map(|pending| pending.upload.clone())
+  }
+
+  // hands an upload over to be finished, once, keeping it reserved until `release`
+  pub fn take(&mut self, id: u64, con_id: ConID) -> Option<Arc<Mutex<Upload>>> {
+    let upload = self.get(id, con_id)?;
+    self.active.get_mut(&id)?.finishing = true;
+    Some(upload)
+  }
+
+  pub fn release(&mut self, id: u64) {
+    self.active.remove(&id);
+  }
+
+  // how much a user has reserved for uploads that aren't finished yet
+  pub fn reserved(&self, uid: Uid) -> u64 {
+    self
+      .active
+      .values()
+      .filter(|pending| pending.uid == uid)
+      .map(|pending| pending.size)
+      .sum()
+  }
+
+  // drops every upload the connection left unfinished, returning their partial files,
+  // the ones already finishing are left to whatever is finishing them
+  pub fn disconnected(&mut self, con_id: ConID) -> Vec<Arc<Mutex<Upload>>> {
+    let ids: Vec<u64> = self
+      .active
+      .iter()
+      .filter(|(_, pending)| pending.con_id == con_id && !pending.finishing)
+      .map(|(id, _)| *id)
+      .collect();
+
+    ids
+      .into_iter()
+      .filter_map(|id| self.active.remove(&id))
+      .map(|pending| pending.upload)
+      .collect()
+  }
+}
+
+// throws away what an abandoned upload had written so far
+pub async fn discard(upload: Arc<Mutex<Upload>>) {
+  let path = upload.lock().await.path.clone();
+  let _ = fs::remove_file(path).await;
+}
+
+// holds up every other quota check for `uid` until the transaction is over
+async fn lock_quota(db: &mut PgConnection, uid: Uid) -> sqlx::Result<()> {
+  sqlx::query("select pg_advisory_xact_lock($1)")
+    .bind(uid as i64)
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+// puts a new upload in progress if it fits in what is left of the uploader's quota,
+// counting everything stored and everything in progress, and throws it away if not
+// the check and the reservation happen under one lock per user, which recording a finished
+// upload takes too, so that uploads started together can't all fit in the same space
+pub async fn reserve(
+  db: &mut PoolConnection<Postgres>,
+  uploads: &RwLock<Uploads>,
+  id: u64,
+  con_id: ConID,
+  uid: Uid,
+  upload: Upload,
+) -> sqlx::Result<bool> {
+  let path = upload.path.clone();
+  let reserved = try_reserve(db, uploads, id, con_id, uid, upload).await;
+  if !matches!(reserved, Ok(true)) {
+    let _ = fs::remove_file(path).await;
+  }
+
+  reserved
+}
+
+async fn try_reserve(
+  db: &mut PoolConnection<Postgres>,
+  uploads: &RwLock<Uploads>,
+  id: u64,
+  con_id: ConID,
+  uid: Uid,
+  upload: Upload,
+) -> sqlx::Result<bool> {
+  let mut tx = db.begin().await?;
+  lock_quota(&mut tx, uid).await?;
+  let used = used(&mut tx, uid).await?;
+
+  let fits = {
+    let mut uploads = uploads.write().unwrap();
+    let fits = used + uploads.reserved(uid) + upload.size <= QUOTA;
+    if fits {
+      uploads.insert(id, con_id, uid, upload);
+    }
+    fits
+  };
+
+  // nothing was written, this only lets go of the lock, which a failure does just the same
+  let _ = tx.commit().await;
+  Ok(fits)
+}
+
+// the total size of everything a user has uploaded
+pub async fn used(db: &mut PgConnection, uid: Uid) -> sqlx::Result<u64> {
+  let used: i64 =
+    sqlx::query("select coalesce(sum(size), 0)::bigint as used from attachments where uploader=$1")
+      .bind(uid as i64)
+      .fetch_one(db)
+      .await?
+      .get("used");
+
+  Ok(used as u64)
+}
+
+// stores a finished upload and releases what it had reserved, under the quota lock,
+// so that it counts as either one or the other to anyone checking the quota
+pub async fn record(
+  db: &mut PoolConnection<Postgres>,
+  uploads: &RwLock<Uploads>,
+  upload: u64,
+  uploader: Uid,
+  name: &str,
+  size: u64,
+  hash: &str,
+) -> sqlx::Result<Attachment> {
+  let mut tx = db.begin().await?;
+  lock_quota(&mut tx, uploader).await?;
+
+  let id = ids::next() as i64;
+  sqlx::query(
+    "insert into attachments (id, uploader, name, size, hash) values ($1, $2, $3, $4, $5)",
+  )
+  .bind(id)
+  .bind(uploader as i64)
+  .bind(name)
+  .bind(size as i64)
+  .bind(hash)
+  .execute(&mut tx)
+  .await?;
+
+  uploads.write().unwrap().release(upload);
+  tx.commit().await?;
+
+  Ok(Attachment {
+    id: id as u64,
+    name: name.to_owned(),
+    size,
+    hash: hash.to_owned(),
+  })
+}
+
+fn attachment_from_row(row: &sqlx::postgres::PgRow) -> Attachment {
+  Attachment {
+    id: row.get::<i64, _>("id") as u64,
+    name: row.get("name"),
+    size: row.get::<i64, _>("size") as u64,
+    hash: row.get("hash"),
+  }
+}
+
+// the attachments out of `ids` that `uid` uploaded, anything else is left out
+pub async fn owned(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  ids: &[u64],
+) -> sqlx::Result<Vec<Attachment>> {
+  let ids: Vec<i64> = ids.iter().map(|id| *id as i64).collect();
+
+  Ok(
+    sqlx::query("select id, name, size, hash from attachments where uploader=$1 and id = any($2)")
+      .bind(uid as i64)
+      .bind(&ids)
+      .fetch_all(db)
+      .await?
+      .iter()
+      .map(attachment_from_row)
+      .collect(),
+  )
+}
+
+// the attachments on each of the given messages
+pub async fn of_messages(
+  db: &mut PoolConnection<Postgres>,
+  ids: &[u64],
+) -> sqlx::Result<HashMap<u64, Vec<Attachment>>> {
+  let ids: Vec<i64> = ids.iter().map(|id| *id as i64).collect();
+
+  let rows = sqlx::query(
+    "select ma.message, a.id, a.name, a.size, a.hash
+      from message_attachments ma join attachments a on a.id = ma.attachment
+      where ma.message = any($1)
+      order by a.uploaded_at",
+  )
+  .bind(&ids)
+  .fetch_all(db)
+  .await?;
+
+  let mut attachments: HashMap<u64, Vec<Attachment>> = HashMap::new();
+  for row in rows {
+    attachments
+      .entry(row.get::<i64, _>("message") as u64)
+      .or_default()
+      .push(attachment_from_row(&row));
+  }
+
+  Ok(attachments)
+}
+
+// an attachment `uid` may download, having either uploaded it,
+// being able to see a message that carries it, or it being someone's avatar
+pub async fn visible(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  id: u64,
+) -> sqlx::Result<Option<Attachment>> {
+  Ok(
+    sqlx::query(
+      "select a.id, a.name, a.size, a.hash from attachments a
+        where a.id = $1 and (a.uploader = $2
+          or exists (select 1 from users u where u.avatar = a.id)
+          or exists (
+            select 1 from message_attachments ma join messages m on m.id = ma.message
+              where ma.attachment = a.id and m.deleted_at is null
+                and ((m.room is not null
+                    and m.room in (select room from room_members where uid = $2))
+                  or (m.room is null and (m.author = $2 or m.recipient = $2)))))",
+    )
+    .bind(id as i64)
+    .bind(uid as i64)
+    .fetch_optional(db)
+    .await?
+    .as_ref()
+    .map(attachment_from_row),
+  )
+}
+
+// up to `len` bytes of a stored attachment, starting at `offset`
+pub async fn read_chunk(hash: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+  let mut file = File::open(stored_path(hash)).await?;
+  file.seek(io::SeekFrom::Start(offset)).await?;
+
+  let mut data = Vec::with_capacity(len);
+  file.take(len as u64).read_to_end(&mut data).await?;
+  Ok(data)
+}
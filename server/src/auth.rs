@@ -0,0 +1,242 @@
+use std::{
+  collections::HashMap,
+  env,
+  error::Error,
+  path::PathBuf,
+  process::Stdio,
+  sync::Mutex,
+  time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use directories::ProjectDirs;
+use sha1::{Digest, Sha1};
+use sqlx::{pool::PoolConnection, Postgres, Row};
+use tokio::{
+  fs,
+  io::{self, AsyncWriteExt},
+  process::Command,
+  time::timeout,
+};
+
+use crate::accounts;
+
+pub type AuthResult = Result<Outcome, Box<dyn Error + Send + Sync>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+  Accepted,
+  WrongPassword,
+  NoSuchUser,
+}
+
+// checks a username and password against wherever the passwords are kept
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+  async fn authenticate(
+    &self,
+    db: &mut PoolConnection<Postgres>,
+    username: &str,
+    password: &str,
+  ) -> AuthResult;
+
+  // whether accounts are made, renamed and given new passwords here on the server,
+  // rather than in some store the server only reads from
+  fn manages_accounts(&self) -> bool {
+    false
+  }
+}
+
+// YACS2_AUTH picks the backend, db (the default), file or command,
+// the file is YACS2_AUTH_FILE or htpasswd in the config directory,
+// and the command is YACS2_AUTH_COMMAND, a program followed by its arguments
+pub fn load() -> Box<dyn Authenticator> {
+  match env::var("YACS2_AUTH").as_deref() {
+    Ok("file") => {
+      let path = env::var_os("YACS2_AUTH_FILE")
+        .map(PathBuf::from)
+        .or_else(|| {
+          ProjectDirs::from("", "", "yacs2").map(|dirs| dirs.config_dir().join("htpasswd"))
+        })
+        .unwrap_or_else(|| PathBuf::from("htpasswd"));
+      Box::new(PasswordFile::new(path))
+    }
+    Ok("command") => {
+      let command = env::var("YACS2_AUTH_COMMAND").unwrap_or_default();
+      let mut words = command.split_whitespace().map(str::to_owned);
+      let Some(program) = words.next() else {
+        panic!("YACS2_AUTH is command, but YACS2_AUTH_COMMAND doesn't name a program");
+      };
+      Box::new(ExternalCommand {
+        program,
+        args: words.collect(),
+      })
+    }
+    Ok("db") | Err(_) => Box::new(Database),
+    Ok(other) => panic!("YACS2_AUTH is {other}, which isn't db, file or command"),
+  }
+}
+
+impl Default for Box<dyn Authenticator> {
+  fn default() -> Self {
+    Box::new(Database)
+  }
+}
+
+// the users table itself
+pub struct Database;
+
+#[async_trait]
+impl Authenticator for Database {
+  async fn authenticate(
+    &self,
+    db: &mut PoolConnection<Postgres>,
+    username: &str,
+    password: &str,
+  ) -> AuthResult {
+    let row = sqlx::query("select salt, hash from users where name=$1")
+      .bind(username)
+      .fetch_optional(db)
+      .await?;
+    let Some(row) = row else {
+      return Ok(Outcome::NoSuchUser);
+    };
+
+    let salt: String = row.get("salt");
+    let hash: Vec<u8> = row.get("hash");
+    Ok(match accounts::hash_password(password, &salt) == hash {
+      true => Outcome::Accepted,
+      false => Outcome::WrongPassword,
+    })
+  }
+
+  fn manages_accounts(&self) -> bool {
+    true
+  }
+}
+
+// an htpasswd file, one `name:hash` per line, hashed with bcrypt or {SHA},
+// read again whenever it changes
+pub struct PasswordFile {
+  path: PathBuf,
+  // the entries as of when the file was last modified
+  entries: Mutex<(Option<SystemTime>, HashMap<String, String>)>,
+}
+
+impl PasswordFile {
+  fn new(path: PathBuf) -> Self {
+    Self {
+      path,
+      entries: Mutex::default(),
+    }
+  }
+
+  async fn hash_of(&self, username: &str) -> io::Result<Option<String>> {
+    let modified = fs::metadata(&self.path).await?.modified()?;
+
+    if self.entries.lock().unwrap().0 != Some(modified) {
+      let contents = fs::read_to_string(&self.path).await?;
+      let entries = contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.trim().split_once(':'))
+        .map(|(name, hash)| (name.to_owned(), hash.to_owned()))
+        .collect();
+
+      *self.entries.lock().unwrap() = (Some(modified), entries);
+    }
+
+    Ok(self.entries.lock().unwrap().1.get(username).cloned())
+  }
+}
+
+#[async_trait]
+impl Authenticator for PasswordFile {
+  async fn authenticate(
+    &self,
+    _db: &mut PoolConnection<Postgres>,
+    username: &str,
+    password: &str,
+  ) -> AuthResult {
+    let Some(hash) = self.hash_of(username).await? else {
+      return Ok(Outcome::NoSuchUser);
+    };
+
+    let matches = if let Some(sha) = hash.strip_prefix("{SHA}") {
+      BASE64.encode(Sha1::digest(password)) == sha
+    } else if hash.starts_with("$2") {
+      // bcrypt is slow on purpose, which isn't something to do on the runtime's threads
+      let password = password.to_owned();
+      tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash)).await??
+    } else {
+      return Err(
+        format!(
+          "{username} has a hash in {} that isn't bcrypt or {{SHA}}",
+          self.path.display()
+        )
+        .into(),
+      );
+    };
+
+    Ok(match matches {
+      true => Outcome::Accepted,
+      false => Outcome::WrongPassword,
+    })
+  }
+}
+
+// how long the command gets to make up its mind
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// a program that gets the username and the password on stdin, a line each,
+// and exits with 0 if they're right, 1 if the password is wrong and 2 if there is no such user
+pub struct ExternalCommand {
+  program: String,
+  args: Vec<String>,
+}
+
+#[async_trait]
+impl Authenticator for ExternalCommand {
+  async fn authenticate(
+    &self,
+    _db: &mut PoolConnection<Postgres>,
+    username: &str,
+    password: &str,
+  ) -> AuthResult {
+    // the command reads one line each, a line break would let the password pick another user
+    if username.chars().any(char::is_control) {
+      return Ok(Outcome::NoSuchUser);
+    }
+    if password.chars().any(char::is_control) {
+      return Ok(Outcome::WrongPassword);
+    }
+
+    let mut child = Command::new(&self.program)
+      .args(&self.args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .kill_on_drop(true)
+      .spawn()?;
+
+    let run = async {
+      if let Some(mut stdin) = child.stdin.take() {
+        stdin
+          .write_all(format!("{username}\n{password}\n").as_bytes())
+          .await?;
+      }
+      child.wait().await
+    };
+
+    let status = timeout(COMMAND_TIMEOUT, run)
+      .await
+      .map_err(|_| format!("{} took too long to answer", self.program))??;
+
+    match status.code() {
+      Some(0) => Ok(Outcome::Accepted),
+      Some(1) => Ok(Outcome::WrongPassword),
+      Some(2) => Ok(Outcome::NoSuchUser),
+      _ => Err(format!("{} exited with {status}", self.program).into()),
+    }
+  }
+}
@@ -1,9 +1,4 @@
-use std::{
-  cell::Cell,
-  sync::{Arc, RwLock},
-};
-
-use convos::{bytes, decode_client_question, encode_server_question, ServerTell};
+use convos::{decode_client_question, encode_server_question, state::State, ServerTell};
 use tokio::{
   io::{AsyncReadExt, AsyncWriteExt},
   net::tcp::{OwnedReadHalf, OwnedWriteHalf},
@@ -11,22 +6,31 @@ use tokio::{
   sync::{
     broadcast,
     mpsc::{self, Receiver, Sender},
-    watch,
   },
 };
 
-pub(crate) type UID = u64;
+pub(crate) type Uid = u64;
 pub(crate) type ConID = u64;
 
 // represents a single connection to the server, does not contain client information
 #[derive(Debug, Clone)]
 pub struct ConnectionHandle {
   pub to_connection: Sender<ServerTell>,
-  // update the UID of this connection
-  pub update_uid: mpsc::Sender<u64>,
+  // move the read worker to another state
+  pub transition: mpsc::Sender<Transition>,
   pub kill: broadcast::Sender<()>,
 }
 
+// what the server tells a read worker once a question changes who the connection is
+#[derive(Debug)]
+pub enum Transition {
+  // the password was right, nothing but the second factor may be asked until it is answered
+  Challenged,
+  SignedIn(Uid),
+  // the account is gone, or the challenge ran out, the connection is anonymous again
+  SignedOut,
+}
+
 #[derive(Debug)]
 pub struct ClientQuestion {
   pub data: convos::ClientQuestion,
@@ -36,38 +40,69 @@ pub struct ClientQuestion {
   //     the users UID
   // if the user is not logged in, UID = 0, and thus any question will be
   // indistinguishable from any other anonymous question
+  // (the server swaps in the connection's guest uid for the few questions guests may ask)
   // thus, for when an anonymous user logs in, we need to discern between the connections
   //   then apply the related UID to the cell, and start differentiating that way
   pub con_id: ConID,
-  pub uid: UID,
+  pub uid: Uid,
+
+  // the state the connection was in when the question was asked,
+  // the read worker has already checked that the question is legal in this state
+  pub state: State,
 }
 
 pub async fn read_worker(
   mut kill: broadcast::Receiver<()>,
-  update_uid: mpsc::Receiver<u64>,
+  transition: mpsc::Receiver<Transition>,
   con_id: ConID,
   stream: OwnedReadHalf,
   to_server: Sender<ClientQuestion>,
+  to_connection: Sender<ServerTell>,
+  // tells the server this connection is gone, once the read worker stops
+  closed: Sender<ConID>,
 ) {
   struct ReadWorker {
-    update_uid: mpsc::Receiver<u64>,
-    uid: UID,
+    transition: mpsc::Receiver<Transition>,
+    uid: Uid,
+    state: State,
     con_id: ConID,
     stream: OwnedReadHalf,
     to_server: Sender<ClientQuestion>,
+    // questions that are illegal in the current state get answered here directly,
+    // they never reach the server
+    to_connection: Sender<ServerTell>,
   }
 
   impl ReadWorker {
     async fn logic(&mut self) {
-      // we can only allow the uid to update before any message is received,
-      //  not /while/ a message is being received, this is why we do not have the uid update
+      // we can only allow the state to change before any message is received,
+      //  not /while/ a message is being received, this is why we do not have the transitions
       //  in the outer loop/select
+      // a transition that is already waiting always goes first,
+      //  so the question that follows it is checked against the new state
       let len = select! {
-        Ok(len) = self.stream.read_u16() => len,
-        Some(id) = self.update_uid.recv() => {
-          self.uid = id;
+        biased;
+        Some(transition) = self.transition.recv() => {
+          match transition {
+            Transition::Challenged => self.state.challenged(),
+            Transition::SignedIn(uid) => {
+              self.uid = uid;
+              self.state.signed_in();
+            }
+            Transition::SignedOut => {
+              self.uid = 0;
+              self.state.signed_out();
+            }
+          }
           return;
         }
+        len = self.stream.read_u16() => match len {
+          Ok(len) => len,
+          Err(_) => {
+            self.state.close();
+            return;
+          }
+        },
       };
 
       dbg!(len);
@@ -76,16 +111,25 @@ pub async fn read_worker(
       }
 
       let mut buf = vec![0; len as usize];
-      self.stream.read_exact(buf.as_mut_slice()).await.unwrap();
+      if self.stream.read_exact(buf.as_mut_slice()).await.is_err() {
+        self.state.close();
+        return;
+      }
 
       let Some(data) = decode_client_question(buf) else {return};
 
+      if let Err(e) = self.state.check(&data) {
+        self.to_connection.send(ServerTell::Error(e)).await.unwrap();
+        return;
+      }
+
       self
         .to_server
         .send(ClientQuestion {
           data,
-          uid: self.uid.clone(),
+          uid: self.uid,
           con_id: self.con_id,
+          state: self.state,
         })
         .await
         .unwrap();
@@ -93,23 +137,34 @@ pub async fn read_worker(
   }
 
   let mut worker = ReadWorker {
-    update_uid,
+    transition,
     // all connections to the server start out anonymously,
     uid: 0,
+    state: State::default(),
     con_id,
     stream,
     to_server,
+    to_connection,
   };
 
+  // the listener has already performed the handshake by the time a connection gets its workers
+  worker.state.handshake_complete();
+
   loop {
     select! {
-      _ = worker.logic() => {},
+      _ = worker.logic() => {
+        if worker.state == State::Closing {
+          break
+        }
+      },
       _ = kill.recv() => {
         dbg!("Read got kill.");
-        return
+        break
       },
     }
   }
+
+  closed.send(con_id).await.unwrap();
 }
 
 // subscribe to channels witin the redis database?
@@ -121,7 +176,12 @@ pub async fn write_worker(
   loop {
     select! {
       Some(msg) = from_server.recv() => {
-        stream.write_all(&encode_server_question(msg).unwrap()).await.unwrap();
+        // a tell too big for a frame is a bug somewhere, but not one worth the connection over
+        let Some(frame) = encode_server_question(msg) else {
+          eprintln!("Dropped a tell that did not fit in a frame");
+          continue;
+        };
+        stream.write_all(&frame).await.unwrap();
       }
       _ = kill.recv() => {
         dbg!("Write got kill.");
@@ -130,3 +190,123 @@ pub async fn write_worker(
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use convos::{encode_client_question, Error};
+  use tokio::net::{TcpListener, TcpStream};
+
+  struct Worker {
+    client: TcpStream,
+    transition: Sender<Transition>,
+    to_server: Receiver<ClientQuestion>,
+    to_connection: Receiver<ServerTell>,
+    _kill: broadcast::Sender<()>,
+  }
+
+  async fn worker() -> Worker {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+      .await
+      .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    let (read, _write) = server.into_split();
+
+    let (kill, _) = broadcast::channel(1);
+    let (transition_tx, transition_rx) = mpsc::channel(1);
+    let (to_server_tx, to_server) = mpsc::channel(8);
+    let (to_connection_tx, to_connection) = mpsc::channel(8);
+    let (closed, _) = mpsc::channel(1);
+    tokio::spawn(read_worker(
+      kill.subscribe(),
+      transition_rx,
+      1,
+      read,
+      to_server_tx,
+      to_connection_tx,
+      closed,
+    ));
+
+    Worker {
+      client,
+      transition: transition_tx,
+      to_server,
+      to_connection,
+      _kill: kill,
+    }
+  }
+
+  impl Worker {
+    async fn ask(&mut self, question: convos::ClientQuestion) {
+      let frame = encode_client_question(question).unwrap();
+      self.client.write_all(&frame).await.unwrap();
+    }
+  }
+
+  #[tokio::test]
+  async fn challenged_only_lets_the_second_factor_through() {
+    let mut worker = worker().await;
+    worker
+      .transition
+      .send(Transition::Challenged)
+      .await
+      .unwrap();
+
+    for question in [
+      convos::ClientQuestion::SignIn {
+        username: "alice".into(),
+        password: "hunter22".into(),
+      },
+      convos::ClientQuestion::WhoAmI,
+      convos::ClientQuestion::JoinRoom {
+        name: "lobby".into(),
+      },
+      convos::ClientQuestion::Mentions,
+    ] {
+      worker.ask(question).await;
+      let tell = worker.to_connection.recv().await.unwrap();
+      assert!(matches!(tell, ServerTell::Error(Error::TwoFactorRequired)));
+    }
+
+    worker
+      .ask(convos::ClientQuestion::SecondFactor {
+        code: "123456".into(),
+      })
+      .await;
+    let question = worker.to_server.recv().await.unwrap();
+    assert!(matches!(
+      question.data,
+      convos::ClientQuestion::SecondFactor { .. }
+    ));
+    assert_eq!(question.state, State::Challenged);
+    // still anonymous until the server says otherwise
+    assert_eq!(question.uid, 0);
+    assert!(worker.to_server.try_recv().is_err());
+
+    worker
+      .transition
+      .send(Transition::SignedIn(7))
+      .await
+      .unwrap();
+    worker.ask(convos::ClientQuestion::Mentions).await;
+    let question = worker.to_server.recv().await.unwrap();
+    assert_eq!(question.state, State::Authenticated);
+    assert_eq!(question.uid, 7);
+  }
+
+  #[tokio::test]
+  async fn an_expired_challenge_goes_back_to_anonymous() {
+    let mut worker = worker().await;
+    worker
+      .transition
+      .send(Transition::Challenged)
+      .await
+      .unwrap();
+    worker.transition.send(Transition::SignedOut).await.unwrap();
+
+    worker.ask(convos::ClientQuestion::WhoAmI).await;
+    let question = worker.to_server.recv().await.unwrap();
+    assert_eq!(question.state, State::Anonymous);
+  }
+}
@@ -0,0 +1,122 @@
+use std::{
+  env,
+  sync::{Mutex, OnceLock},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// ids count milliseconds from here instead of the unix epoch, 2023-01-01 UTC,
+// which leaves 41 bits of timestamp good for about 70 years
+const EPOCH: Duration = Duration::from_millis(1_672_531_200_000);
+
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_NODE: u64 = (1 << NODE_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+struct Generator {
+  // milliseconds since EPOCH of the last id handed out
+  last: u64,
+  sequence: u64,
+}
+
+static GENERATOR: Mutex<Generator> = Mutex::new(Generator {
+  last: 0,
+  sequence: 0,
+});
+
+static NODE: OnceLock<u64> = OnceLock::new();
+
+// YACS2_NODE_ID says which server this is, every server sharing a database needs a different one,
+// read up front so that a bad one stops the server before it hands out any ids
+pub fn load() {
+  node();
+}
+
+fn node() -> u64 {
+  *NODE.get_or_init(|| match env::var("YACS2_NODE_ID") {
+    Ok(value) => match value.parse() {
+      Ok(node) if node <= MAX_NODE => node,
+      _ => panic!("YACS2_NODE_ID is {value}, which isn't a node from 0 to {MAX_NODE}"),
+    },
+    Err(_) => 0,
+  })
+}
+
+fn now() -> u64 {
+  let since_epoch = SystemTime::now()
+    .duration_since(UNIX_EPOCH + EPOCH)
+    .unwrap_or_default();
+
+  // never 0, so no id ever is either, 0 means anonymous
+  (since_epoch.as_millis() as u64).max(1)
+}
+
+// a new id, unique across every node, and larger than every id this node handed out before it
+// 41 bits of milliseconds, then 10 of node and 12 of sequence, the top bit stays clear
+// so that ids fit the database's signed bigints
+pub fn next() -> u64 {
+  let mut generator = GENERATOR.lock().unwrap();
+
+  // a clock that jumps back is treated as standing still until it catches up
+  let mut now = now().max(generator.last);
+  if now == generator.last {
+    generator.sequence = (generator.sequence + 1) & MAX_SEQUENCE;
+    // the sequence ran out for this millisecond, borrow from the next one
+    if generator.sequence == 0 {
+      now += 1;
+    }
+  } else {
+    generator.sequence = 0;
+  }
+  generator.last = now;
+
+  (now << (NODE_BITS + SEQUENCE_BITS)) | (node() << SEQUENCE_BITS) | generator.sequence
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ids_are_never_0() {
+    for _ in 0..1000 {
+      assert_ne!(next(), 0);
+    }
+  }
+
+  #[test]
+  fn ids_only_grow() {
+    let mut last = next();
+    for _ in 0..10_000 {
+      let id = next();
+      assert!(id > last, "{id} after {last}");
+      last = id;
+    }
+  }
+
+  #[test]
+  fn ids_fit_a_signed_bigint_and_carry_the_node() {
+    let id = next();
+    assert!(id <= i64::MAX as u64);
+    assert_eq!((id >> SEQUENCE_BITS) & MAX_NODE, node());
+  }
+
+  #[test]
+  fn running_out_of_sequence_borrows_the_next_millisecond() {
+    let before = next();
+    let last = {
+      let mut generator = GENERATOR.lock().unwrap();
+      // as if the clock had jumped back, with this millisecond all but used up
+      generator.last = now() + 1000;
+      generator.sequence = MAX_SEQUENCE - 1;
+      generator.last
+    };
+
+    let full = next();
+    let borrowed = next();
+    assert!(before < full && full < borrowed);
+    assert_eq!(full & MAX_SEQUENCE, MAX_SEQUENCE);
+    assert_eq!(full >> (NODE_BITS + SEQUENCE_BITS), last);
+    assert_eq!(borrowed >> (NODE_BITS + SEQUENCE_BITS), last + 1);
+  }
+}
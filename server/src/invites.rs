@@ -0,0 +1,77 @@
+use std::env;
+
+use rand::{distributions::Alphanumeric, Rng};
+use sqlx::{pool::PoolConnection, Postgres, Row, Transaction};
+
+use crate::connection::Uid;
+
+const CODE_LENGTH: usize = 12;
+
+// who may sign up
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Registration {
+  #[default]
+  Open,
+  // only with an invite code
+  InviteOnly,
+  // nobody, accounts have to be made some other way
+  Closed,
+}
+
+impl Registration {
+  // YACS2_REGISTRATION is open (the default), invite or closed
+  pub fn load() -> Self {
+    match env::var("YACS2_REGISTRATION").as_deref() {
+      Ok("open") | Err(_) => Registration::Open,
+      Ok("invite") => Registration::InviteOnly,
+      Ok("closed") => Registration::Closed,
+      Ok(other) => panic!("YACS2_REGISTRATION is {other}, which isn't open, invite or closed"),
+    }
+  }
+}
+
+// mints a code good for `uses` sign-ups, or any number if None, for `expires_in` seconds,
+// returning it along with when it expires
+pub async fn create(
+  db: &mut PoolConnection<Postgres>,
+  created_by: Uid,
+  uses: Option<u32>,
+  expires_in: Option<u64>,
+) -> sqlx::Result<(String, Option<u64>)> {
+  let code: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(CODE_LENGTH)
+    .map(char::from)
+    .collect();
+
+  let row = sqlx::query(
+    "insert into invites (code, created_by, uses_left, expires_at)
+      values ($1, $2, $3, now() + make_interval(secs => $4))
+      returning extract(epoch from expires_at)::bigint as expires_at",
+  )
+  .bind(&code)
+  .bind(created_by as i64)
+  .bind(uses.map(|uses| uses.min(i32::MAX as u32) as i32))
+  .bind(expires_in.map(|secs| secs as f64))
+  .fetch_one(db)
+  .await?;
+
+  let expires_at = row.get::<Option<i64>, _>("expires_at").map(|at| at as u64);
+  Ok((code, expires_at))
+}
+
+// uses up one sign-up of an invite, as part of creating the account it is for,
+// false if there is no such invite or it has expired or been used up
+pub async fn redeem(tx: &mut Transaction<'_, Postgres>, code: &str) -> sqlx::Result<bool> {
+  let redeemed = sqlx::query(
+    "update invites set uses_left = uses_left - 1
+      where code = $1
+        and (uses_left is null or uses_left > 0)
+        and (expires_at is null or expires_at > now())",
+  )
+  .bind(code.trim())
+  .execute(tx)
+  .await?;
+
+  Ok(redeemed.rows_affected() == 1)
+}
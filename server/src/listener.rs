@@ -1,4 +1,12 @@
-use std::{cell::Cell, collections::hash_map::RandomState, sync::Arc};
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
 
 use tokio::{
   io::{AsyncReadExt, AsyncWriteExt},
@@ -8,47 +16,183 @@ use tokio::{
     mpsc::{self, Receiver, Sender},
     watch,
   },
+  time::timeout,
 };
 
-use crate::connection::{read_worker, write_worker, ClientQuestion, ConnectionHandle};
+use crate::access::AccessList;
+
+// how many connections one address may have open at once, handshaking or not
+const MAX_CONNECTIONS_PER_ADDRESS: usize = 8;
+
+// peers that haven't echoed the handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how often the access list is checked for changes
+const ACCESS_LIST_RELOAD: Duration = Duration::from_secs(5);
+
+// how many peers were turned away at the door, and why
+#[derive(Default)]
+pub struct ListenerMetrics {
+  pub accepted: AtomicU64,
+  pub denied: AtomicU64,
+  pub over_cap: AtomicU64,
+  pub handshake_failed: AtomicU64,
+  pub handshake_timed_out: AtomicU64,
+}
+
+impl ListenerMetrics {
+  pub fn rejected(&self) -> u64 {
+    self.denied.load(Ordering::Relaxed)
+      + self.over_cap.load(Ordering::Relaxed)
+      + self.handshake_failed.load(Ordering::Relaxed)
+      + self.handshake_timed_out.load(Ordering::Relaxed)
+  }
+
+  fn count(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+// counts towards its address's connection cap for as long as it is alive
+pub struct Permit {
+  open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+  address: IpAddr,
+}
+
+impl Permit {
+  fn acquire(open: &Arc<Mutex<HashMap<IpAddr, usize>>>, address: IpAddr) -> Option<Self> {
+    let mut counts = open.lock().unwrap();
+    let count = counts.entry(address).or_default();
+    if *count >= MAX_CONNECTIONS_PER_ADDRESS {
+      return None;
+    }
+    *count += 1;
+
+    Some(Self {
+      open: open.clone(),
+      address,
+    })
+  }
+}
 
-pub fn create_listener<T>(on: T, killswitch: watch::Receiver<()>) -> Receiver<TcpStream>
+impl Drop for Permit {
+  fn drop(&mut self) {
+    let mut counts = self.open.lock().unwrap();
+    if let Some(count) = counts.get_mut(&self.address) {
+      *count -= 1;
+      if *count == 0 {
+        counts.remove(&self.address);
+      }
+    }
+  }
+}
+
+// a connection that made it through the handshake, along with its place under the cap
+pub struct Accepted {
+  pub stream: TcpStream,
+  pub permit: Permit,
+}
+
+// along with the accepted connections comes the access list, every time it changes,
+// so the connections it now denies can be dropped too
+pub fn create_listener<T>(
+  on: T,
+  killswitch: watch::Receiver<()>,
+) -> (
+  Receiver<Accepted>,
+  Arc<ListenerMetrics>,
+  watch::Receiver<AccessList>,
+)
 where
   T: ToSocketAddrs + Send + 'static,
 {
   let (tx, rx) = mpsc::channel(32);
+  let (access_tx, access_rx) = watch::channel(AccessList::default());
+  let metrics = Arc::new(ListenerMetrics::default());
 
-  tokio::spawn(listener_logic(killswitch, on, tx));
+  tokio::spawn(listener_logic(
+    killswitch,
+    on,
+    tx,
+    access_tx,
+    metrics.clone(),
+  ));
 
-  rx
+  (rx, metrics, access_rx)
 }
 
-async fn listener_logic<T>(mut killswitch: watch::Receiver<()>, on: T, to_server: Sender<TcpStream>)
-where
+async fn listener_logic<T>(
+  mut killswitch: watch::Receiver<()>,
+  on: T,
+  to_server: Sender<Accepted>,
+  reloaded: watch::Sender<AccessList>,
+  metrics: Arc<ListenerMetrics>,
+) where
   T: ToSocketAddrs,
 {
   let listener = TcpListener::bind(on).await.unwrap();
+  let open = Arc::new(Mutex::new(HashMap::new()));
+
+  let mut access = AccessList::default();
+  let mut reload_interval = tokio::time::interval(ACCESS_LIST_RELOAD);
 
   loop {
     select! {
       _ = killswitch.changed() => break,
-      Ok((con, _ip)) = listener.accept() => {
-        let to_server = to_server.clone();
+      _ = reload_interval.tick() => {
+        match access.reload().await {
+          Ok(true) => {
+            reloaded.send_replace(access.clone());
+          }
+          Ok(false) => {}
+          Err(e) => eprintln!("Ran into error when trying to reload the access list: {e}"),
+        }
+      }
+      Ok((con, address)) = listener.accept() => {
+        let ip = address.ip().to_canonical();
+
+        if !access.permits(ip) {
+          ListenerMetrics::count(&metrics.denied);
+          continue;
+        }
+
+        let Some(permit) = Permit::acquire(&open, ip) else {
+          ListenerMetrics::count(&metrics.over_cap);
+          continue;
+        };
+
         tokio::spawn(
-          listener_accepted( con, to_server)
+          listener_accepted(con, permit, to_server.clone(), metrics.clone())
         );
       }
     }
   }
 }
 
-async fn listener_accepted(mut con: TcpStream, to_server: Sender<TcpStream>) {
+async fn listener_accepted(
+  mut con: TcpStream,
+  permit: Permit,
+  to_server: Sender<Accepted>,
+  metrics: Arc<ListenerMetrics>,
+) {
   // perform a basic handshake, requesting the echo of a u64
   let num: u64 = rand::random();
-  con.write_u64(num).await.unwrap();
-  if con.read_u64().await.unwrap() != num {
-    return;
+  let handshake = async {
+    con.write_u64(num).await?;
+    con.read_u64().await
+  };
+
+  match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+    Ok(Ok(echo)) if echo == num => {}
+    Ok(_) => return ListenerMetrics::count(&metrics.handshake_failed),
+    Err(_) => return ListenerMetrics::count(&metrics.handshake_timed_out),
   }
 
-  to_server.send(con).await.unwrap();
+  ListenerMetrics::count(&metrics.accepted);
+  let _ = to_server
+    .send(Accepted {
+      stream: con,
+      permit,
+    })
+    .await;
 }
@@ -0,0 +1,176 @@
+use std::{net::IpAddr, time::Duration};
+
+use convos::FailedSignIn;
+use sqlx::{pool::PoolConnection, postgres::PgRow, Postgres, Row};
+
+use crate::connection::Uid;
+
+// failures older than this are forgotten
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+// how many failures go by before each further attempt has to wait,
+// first one second and then twice as long every time, up until the lockout
+const ACCOUNT_FREE_ATTEMPTS: i64 = 3;
+const ADDRESS_FREE_ATTEMPTS: i64 = 10;
+
+// past this many failures, attempts are turned away for the whole lockout
+const ACCOUNT_LOCKOUT_AFTER: i64 = 10;
+const ADDRESS_LOCKOUT_AFTER: i64 = 30;
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+fn backoff(failures: i64, free: i64, lockout_after: i64) -> Duration {
+  if failures < free {
+    Duration::ZERO
+  } else if failures >= lockout_after {
+    LOCKOUT
+  } else {
+    Duration::from_secs(1 << (failures - free).min(16)).min(LOCKOUT)
+  }
+}
+
+// the count and age of the latest of some failures, and so how much longer they have to wait
+const FAILURES: &str = "select count(*) as failures,
+    coalesce(extract(epoch from now() - max(at))::float8, 0) as since
+  from sign_in_failures";
+
+fn remaining(row: PgRow, free: i64, lockout_after: i64) -> Duration {
+  let since = Duration::from_secs_f64(row.get::<f64, _>("since").max(0.0));
+  wait_after(row.get("failures"), since, free, lockout_after)
+}
+
+// how long is left to wait, `since` the latest of some failures
+fn wait_after(failures: i64, since: Duration, free: i64, lockout_after: i64) -> Duration {
+  // the query leaves these out already, once the latest is forgotten so are the rest
+  if since >= WINDOW {
+    return Duration::ZERO;
+  }
+  backoff(failures, free, lockout_after).saturating_sub(since)
+}
+
+// how long until `address`, signing in as `uid` if that is a real account, may try again
+pub async fn locked_for(
+  db: &mut PoolConnection<Postgres>,
+  uid: Option<Uid>,
+  address: IpAddr,
+) -> sqlx::Result<Duration> {
+  let row = sqlx::query(&format!(
+    "{FAILURES} where address = $1 and at > now() - interval '{} seconds'",
+    WINDOW.as_secs()
+  ))
+  .bind(address.to_string())
+  .fetch_one(&mut *db)
+  .await?;
+  let mut wait = remaining(row, ADDRESS_FREE_ATTEMPTS, ADDRESS_LOCKOUT_AFTER);
+
+  // failures the owner has already seen were followed by them getting in
+  if let Some(uid) = uid {
+    let row = sqlx::query(&format!(
+      "{FAILURES} where uid = $1 and not reported and at > now() - interval '{} seconds'",
+      WINDOW.as_secs()
+    ))
+    .bind(uid as i64)
+    .fetch_one(&mut *db)
+    .await?;
+    wait = wait.max(remaining(row, ACCOUNT_FREE_ATTEMPTS, ACCOUNT_LOCKOUT_AFTER));
+  }
+
+  Ok(wait)
+}
+
+pub async fn record(
+  db: &mut PoolConnection<Postgres>,
+  uid: Option<Uid>,
+  address: IpAddr,
+) -> sqlx::Result<()> {
+  sqlx::query("insert into sign_in_failures (uid, address) values ($1, $2)")
+    .bind(uid.map(|uid| uid as i64))
+    .bind(address.to_string())
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+// every failed attempt at `uid` they haven't been shown yet, oldest first,
+// marking them shown
+pub async fn unreported(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+) -> sqlx::Result<Vec<FailedSignIn>> {
+  let mut attempts: Vec<FailedSignIn> = sqlx::query(
+    "update sign_in_failures set reported = true
+      where uid = $1 and not reported
+      returning address, extract(epoch from at)::bigint as at",
+  )
+  .bind(uid as i64)
+  .fetch_all(db)
+  .await?
+  .iter()
+  .map(|row| FailedSignIn {
+    address: row.get("address"),
+    at: row.get::<i64, _>("at") as u64,
+  })
+  .collect();
+
+  attempts.sort_by_key(|attempt| attempt.at);
+  Ok(attempts)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+  }
+
+  #[test]
+  fn free_attempts_cost_nothing() {
+    for failures in 0..ACCOUNT_FREE_ATTEMPTS {
+      assert_eq!(backoff(failures, 3, 10), Duration::ZERO);
+    }
+    assert_eq!(backoff(0, 0, 10), secs(1));
+  }
+
+  #[test]
+  fn doubles_with_every_failure() {
+    assert_eq!(backoff(3, 3, 10), secs(1));
+    assert_eq!(backoff(4, 3, 10), secs(2));
+    assert_eq!(backoff(5, 3, 10), secs(4));
+    assert_eq!(backoff(9, 3, 10), secs(64));
+
+    for failures in ADDRESS_FREE_ATTEMPTS..ADDRESS_LOCKOUT_AFTER - 1 {
+      let next = backoff(failures + 1, ADDRESS_FREE_ATTEMPTS, ADDRESS_LOCKOUT_AFTER);
+      let this = backoff(failures, ADDRESS_FREE_ATTEMPTS, ADDRESS_LOCKOUT_AFTER);
+      assert!(next == this * 2 || next == LOCKOUT);
+    }
+  }
+
+  #[test]
+  fn capped_at_the_lockout() {
+    assert_eq!(backoff(10, 3, 10), LOCKOUT);
+    assert_eq!(backoff(1000, 3, 10), LOCKOUT);
+    // doubling past the lockout before reaching lockout_after stops at it too
+    assert_eq!(backoff(9, 0, 100), secs(512));
+    assert_eq!(backoff(10, 0, 100), LOCKOUT);
+    assert_eq!(backoff(80, 0, 100), LOCKOUT);
+    assert_eq!(backoff(i64::MAX, 0, i64::MAX), LOCKOUT);
+  }
+
+  #[test]
+  fn waiting_counts_down_from_the_latest_failure() {
+    assert_eq!(wait_after(5, secs(0), 3, 10), secs(4));
+    assert_eq!(wait_after(5, secs(3), 3, 10), secs(1));
+    assert_eq!(wait_after(5, secs(10), 3, 10), Duration::ZERO);
+    assert_eq!(wait_after(10, secs(60), 3, 10), LOCKOUT - secs(60));
+    assert_eq!(wait_after(10, LOCKOUT, 3, 10), Duration::ZERO);
+  }
+
+  #[test]
+  fn the_window_forgets_everything() {
+    let wait = |since| wait_after(1000, since, 3, 10);
+    assert_eq!(wait(secs(0)), LOCKOUT);
+    assert_eq!(wait(WINDOW), Duration::ZERO);
+    assert_eq!(wait(WINDOW * 2), Duration::ZERO);
+  }
+}
@@ -1,20 +1,54 @@
+mod access;
+mod accounts;
+mod attachments;
+mod auth;
 mod connection;
+mod ids;
+mod invites;
 mod listener;
+mod lockout;
+mod mentions;
+mod messages;
+mod moderation;
+mod policy;
+mod presence;
+mod profiles;
+mod ratelimit;
+mod recovery;
+mod roles;
+mod rooms;
+mod search;
+mod sessions;
+mod totp;
+mod typing;
+mod unread;
 
 use std::{
-  cell::Cell,
-  collections::HashMap,
-  sync::{Arc, RwLock},
+  fmt,
+  net::IpAddr,
+  sync::{atomic::Ordering, Arc, RwLock},
+  time::{Duration, Instant},
 };
 
-use connection::{read_worker, write_worker, ClientQuestion, ConnectionHandle, UID};
-use convos::ServerTell;
-use listener::create_listener;
-use rand::{distributions::Alphanumeric, Rng};
-use sha2::{Digest, Sha512};
+use access::AccessList;
+use attachments::{Upload, Uploads};
+use auth::{Authenticator, Outcome};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use connection::{
+  read_worker, write_worker, ClientQuestion, ConID, ConnectionHandle, Transition, Uid,
+};
+use convos::{
+  state::{self, State},
+  ServerTell,
+};
+use invites::Registration;
+use listener::{create_listener, Accepted, ListenerMetrics};
+use policy::PasswordPolicy;
+use ratelimit::{RateLimiter, RateLimits, Verdict};
+use sessions::{Challenge, Session, Sessions};
 use sqlx::{pool::PoolConnection, PgPool, Postgres};
 use tokio::{
-  net::TcpStream,
   select,
   sync::{
     broadcast,
@@ -22,51 +56,101 @@ use tokio::{
     watch,
   },
 };
+use totp::TotpPolicy;
+use typing::TypingTracker;
 
 // contains client information that is stored on the server
+#[allow(dead_code)]
 struct Client {
   name: String,
-  uid: UID,
+  uid: Uid,
 }
 
 //# passowrd server, for verifying passwords on attempt to connect?
 
+// everything the server loop shares with the message workers
+#[derive(Default)]
+struct Shared {
+  sessions: RwLock<Sessions>,
+  typing: RwLock<TypingTracker>,
+  uploads: RwLock<Uploads>,
+  password_policy: PasswordPolicy,
+  totp: TotpPolicy,
+  authenticator: Box<dyn Authenticator>,
+  registration: Registration,
+}
+
 struct Server {
-  connections: HashMap<u64, ConnectionHandle>,
+  shared: Arc<Shared>,
   database: PgPool,
 
+  // checked before a question gets a worker, or a database connection
+  limiter: RateLimiter,
+
   // incoming tcpstreams from the listener
   // the listener will have already performed a handshake at this point,
   // all the server has to do is create the worker tasks & the unique connection ID
-  listener: Receiver<TcpStream>,
+  listener: Receiver<Accepted>,
+  listener_metrics: Arc<ListenerMetrics>,
+  // the listener only checks it at the door, the server drops whoever it denies later
+  access: watch::Receiver<AccessList>,
+  // rejections as of the last report, so quiet minutes go unreported
+  reported_rejections: u64,
 
   // keep a copy of the sender alive, so that it may be copied into
   // new connections, and if all of the connections close, the channel does not close
   incoming_questions: Receiver<ClientQuestion>,
   incoming_question_tx: Sender<ClientQuestion>,
 
+  // read workers report here once their connection is gone, same deal as above
+  closed_connections: Receiver<ConID>,
+  closed_connection_tx: Sender<ConID>,
+
+  #[allow(dead_code)]
   killswitch: watch::Sender<()>,
 
   // a copy of the killswitch-receiver, pass this to
   // all subordinate tasks to kill when server is ready to die
+  #[allow(dead_code)]
   killswitch_receiver: watch::Receiver<()>,
 }
 
 impl Server {
   async fn new() -> Self {
+    ids::load();
+
     let (ks_tx, ks_rx) = watch::channel(());
     let (iq_tx, iq_rx) = mpsc::channel(256);
+    let (cc_tx, cc_rx) = mpsc::channel(256);
 
     let pool = PgPool::connect("postgresql://postgres@localhost/rustChatUsers")
       .await
       .unwrap();
 
+    sqlx::migrate!().run(&pool).await.unwrap();
+    policy::backfill_skeletons(&pool).await.unwrap();
+    accounts::retire_guests(&pool).await.unwrap();
+
+    let (listener, listener_metrics, access) = create_listener("0.0.0.0:5555", ks_rx.clone());
+
     Self {
       database: pool,
-      connections: HashMap::new(),
-      listener: create_listener("0.0.0.0:5555", ks_rx.clone()),
+      shared: Arc::new(Shared {
+        password_policy: PasswordPolicy::load().await,
+        totp: TotpPolicy::load(),
+        authenticator: auth::load(),
+        registration: Registration::load(),
+        ..Shared::default()
+      }),
+      limiter: RateLimiter::new(RateLimits::load()),
+      listener,
+      listener_metrics,
+      access,
+      reported_rejections: 0,
       incoming_questions: iq_rx,
       incoming_question_tx: iq_tx,
+      closed_connections: cc_rx,
+      closed_connection_tx: cc_tx,
       killswitch: ks_tx,
       killswitch_receiver: ks_rx,
     }
@@ -74,163 +158,1708 @@ impl Server {
 
   async fn run(mut self) {
     // let mut heartbeat_interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
+    let mut typing_interval = tokio::time::interval(Duration::from_secs(1));
+    let mut prune_interval = tokio::time::interval(Duration::from_secs(60));
+    let mut metrics_interval = tokio::time::interval(Duration::from_secs(60));
 
     loop {
       select! {
         Some(incoming) = self.listener.recv() => self.on_incoming(incoming),
         Some(message) = self.incoming_questions.recv() => {
+          // the connection may have closed while its question was in flight
+          let Some((connection, address)) = self
+            .shared
+            .sessions
+            .read()
+            .unwrap()
+            .get(message.con_id)
+            .map(|session| (session.handle.clone(), session.address.ip())) else {
+            continue;
+          };
+
+          let kind = ratelimit::Kind::of(&message.data);
+          match self.limiter.check(message.con_id, message.uid, address, kind) {
+            Verdict::Allowed => {}
+            Verdict::Limited(wait) => {
+              // a client too busy to read its tells can go without this one
+              let retry_after = (wait.as_micros() as u64).div_ceil(1000);
+              let tell = ServerTell::Error(convos::Error::RateLimited { retry_after });
+              let _ = connection.to_connection.try_send(tell);
+              continue;
+            }
+            Verdict::Disconnect => {
+              eprintln!("Disconnecting {address} for ignoring rate limits");
+              let _ = connection.kill.send(());
+              continue;
+            }
+          }
+
           tokio::spawn(
             message_worker(
               self.database.acquire().await.unwrap(),
-              self.connections.get(&message.con_id).unwrap().clone(),
+              connection,
+              self.shared.clone(),
               message
             )
           );
         },
+        Some(con_id) = self.closed_connections.recv() => self.on_closed(con_id),
+        _ = typing_interval.tick() => self.expire_typing(),
+        _ = prune_interval.tick() => self.limiter.prune(),
+        _ = metrics_interval.tick() => self.report_listener(),
+        Ok(()) = self.access.changed() => self.enforce_access(),
         // _ = heartbeat_interval.tick() => self.heartbeat_and_prune(),
       }
     }
   }
 
+  #[allow(dead_code)]
   fn heartbeat_and_prune(&mut self) {
     unimplemented!()
   }
 
+  fn expire_typing(&mut self) {
+    for (uid, told) in self.shared.typing.write().unwrap().expire() {
+      tokio::spawn(rooms::deliver(told, move |target| {
+        ServerTell::TypingStopped { target, who: uid }
+      }));
+    }
+  }
+
+  // like a kick, the read worker reports the connection closed once it dies
+  fn enforce_access(&mut self) {
+    let access = self.access.borrow();
+    for session in self.shared.sessions.read().unwrap().iter() {
+      let address = session.address.ip();
+      if !access.permits(address) {
+        eprintln!("Disconnecting {address}, which the access list now denies");
+        let _ = session.handle.kill.send(());
+      }
+    }
+  }
+
+  fn report_listener(&mut self) {
+    let metrics = &self.listener_metrics;
+    let rejected = metrics.rejected();
+    if rejected == self.reported_rejections {
+      return;
+    }
+    self.reported_rejections = rejected;
+
+    println!(
+      "Listener: {} accepted, {} denied, {} over the per-address cap, {} failed and {} timed out handshakes",
+      metrics.accepted.load(Ordering::Relaxed),
+      metrics.denied.load(Ordering::Relaxed),
+      metrics.over_cap.load(Ordering::Relaxed),
+      metrics.handshake_failed.load(Ordering::Relaxed),
+      metrics.handshake_timed_out.load(Ordering::Relaxed),
+    );
+  }
+
+  fn on_closed(&mut self, con_id: ConID) {
+    self.shared.typing.write().unwrap().disconnected(con_id);
+    self.limiter.disconnected(con_id);
+
+    for upload in self.shared.uploads.write().unwrap().disconnected(con_id) {
+      tokio::spawn(attachments::discard(upload));
+    }
+
+    let Some((session, change)) = self.shared.sessions.write().unwrap().remove(con_id) else {
+      return;
+    };
+
+    // stop the write worker, the read worker is already gone
+    let _ = session.handle.kill.send(());
+
+    if session.guest {
+      let (db, uid) = (self.database.clone(), session.uid);
+      tokio::spawn(async move {
+        let retired = match db.acquire().await {
+          Ok(mut db) => accounts::retire_guest(&mut db, uid).await,
+          Err(e) => Err(e),
+        };
+        if let Err(e) = retired {
+          eprintln!("Ran into error when trying to retire guest {uid}: {e}");
+        }
+      });
+    }
+
+    if let Some(change) = change {
+      tokio::spawn(change.deliver());
+    }
+  }
+
   // TODO: put this into a worker function
-  fn on_incoming(&mut self, con: TcpStream) {
-    // create the unique connection ID
-    let conid = loop {
-      let test: u64 = rand::random();
-      if !self.connections.contains_key(&test) {
-        break test;
-      };
+  fn on_incoming(&mut self, incoming: Accepted) {
+    let Accepted {
+      stream: con,
+      permit,
+    } = incoming;
+
+    let Ok(address) = con.peer_addr() else {
+      return;
     };
 
+    let conid = ids::next();
+
     let (read, write) = con.into_split();
     let (s2c_tx, s2c_rx) = mpsc::channel(8);
     let (ks_tx, _keepalive) = broadcast::channel(1);
-    let (uid_tx, uid_rx) = mpsc::channel(1);
+    let (transition_tx, transition_rx) = mpsc::channel(1);
 
     tokio::spawn(read_worker(
       ks_tx.subscribe(),
-      uid_rx,
+      transition_rx,
       conid,
       read,
       self.incoming_question_tx.clone(),
+      s2c_tx.clone(),
+      self.closed_connection_tx.clone(),
     ));
 
     tokio::spawn(write_worker(ks_tx.subscribe(), write, s2c_rx));
 
     let handle = ConnectionHandle {
-      update_uid: uid_tx,
+      transition: transition_tx,
       to_connection: s2c_tx,
       kill: ks_tx,
     };
 
-    self.connections.insert(conid, handle);
+    self
+      .shared
+      .sessions
+      .write()
+      .unwrap()
+      .insert(conid, Session::new(handle, address, permit));
   }
 }
 
 async fn message_worker(
   db: PoolConnection<Postgres>,
   connection: ConnectionHandle,
+  shared: Arc<Shared>,
   msg: ClientQuestion,
 ) {
-  dbg!("{:?}", &msg);
-
-  let msg = if msg.uid == 0 {
-    anonymous_message_worker(db, &connection, msg).await
-  } else {
-    signed_in_message_worker(db, &connection, msg).await
+  let msg = match msg.state {
+    State::Authenticated => signed_in_message_worker(db, &connection, &shared, msg).await,
+    State::Anonymous if state::open_to_guests(&msg.data) => {
+      guest_message_worker(db, &connection, &shared, msg).await
+    }
+    _ => anonymous_message_worker(db, &connection, &shared, msg).await,
   };
 
-  connection.to_connection.send(msg).await.unwrap();
+  // some questions, like typing indicators, have nothing to answer
+  if let Some(msg) = msg {
+    connection.to_connection.send(msg).await.unwrap();
+  }
+}
+
+async fn who_is_id(db: &mut PoolConnection<Postgres>, id: u64) -> ServerTell {
+  match profiles::by_id(db, id).await {
+    Ok(Some((id, name, profile))) => ServerTell::Who { id, name, profile },
+    _ => ServerTell::Error(convos::Error::InvalidUID),
+  }
+}
+
+async fn who_is_name(db: &mut PoolConnection<Postgres>, name: String) -> ServerTell {
+  let name = policy::normalize(&name);
+  match profiles::by_name(db, &name).await {
+    Ok(Some((id, name, profile))) => ServerTell::Who { id, name, profile },
+    _ => ServerTell::Error(convos::Error::InvalidUsername),
+  }
+}
+
+fn check_message_len(content: &str) -> Result<(), convos::Error> {
+  match content.chars().count() > convos::MAX_MESSAGE_LEN {
+    true => Err(convos::Error::MessageTooLong {
+      max: convos::MAX_MESSAGE_LEN as u64,
+    }),
+    false => Ok(()),
+  }
+}
+
+// what went wrong on this end goes to the log, the client only hears that something did
+fn server_error(doing: fmt::Arguments, e: impl fmt::Display) -> ServerTell {
+  eprintln!("Ran into error when trying to {doing}: {e}");
+  ServerTell::Error(convos::Error::ServerError)
+}
+
+async fn directory(
+  db: &mut PoolConnection<Postgres>,
+  query: String,
+  fuzzy: bool,
+) -> Option<ServerTell> {
+  match profiles::directory(db, &query, fuzzy).await {
+    Ok(users) => Some(ServerTell::Directory { query, users }),
+    Err(e) => Some(server_error(
+      format_args!("search the directory for {query}"),
+      e,
+    )),
+  }
+}
+
+// checks and stores an upload that all arrived, None if something went wrong on this end
+async fn finish_upload(
+  db: &mut PoolConnection<Postgres>,
+  uploads: &RwLock<Uploads>,
+  id: u64,
+  uid: Uid,
+  upload: &mut Upload,
+) -> Option<ServerTell> {
+  match upload.finish().await {
+    Ok(true) => {}
+    Ok(false) => return Some(ServerTell::Error(convos::Error::HashMismatch)),
+    Err(e) => return Some(server_error(format_args!("finish upload {id}"), e)),
+  }
+
+  match attachments::record(
+    db,
+    uploads,
+    id,
+    uid,
+    &upload.name,
+    upload.size,
+    &upload.hash,
+  )
+  .await
+  {
+    Ok(attachment) => Some(ServerTell::Uploaded { attachment }),
+    Err(e) => Some(server_error(format_args!("record upload {id}"), e)),
+  }
+}
+
+fn presence_of(sessions: &RwLock<Sessions>, id: Uid) -> ServerTell {
+  ServerTell::Presence {
+    id,
+    presence: sessions.read().unwrap().presence(id),
+  }
+}
+
+fn num_connected(sessions: &RwLock<Sessions>) -> ServerTell {
+  ServerTell::NumConnected {
+    count: sessions.read().unwrap().num_connected(),
+  }
 }
 
 async fn anonymous_message_worker(
   mut db: PoolConnection<Postgres>,
   connection: &ConnectionHandle,
+  shared: &Shared,
   msg: ClientQuestion,
-) -> ServerTell {
-  match msg.data {
-    convos::ClientQuestion::WhoIsID { id } => todo!(),
+) -> Option<ServerTell> {
+  let sessions = &shared.sessions;
 
-    convos::ClientQuestion::WhoAmI => ServerTell::Who {
-      id: 0,
-      name: "Anonymous".to_owned(),
+  let tell = match msg.data {
+    convos::ClientQuestion::WhoIsID { id } => who_is_id(&mut db, id).await,
+    convos::ClientQuestion::WhoIsName { name } => who_is_name(&mut db, name).await,
+    convos::ClientQuestion::NumConnected => num_connected(sessions),
+    convos::ClientQuestion::PresenceOf { id } => presence_of(sessions, id),
+
+    convos::ClientQuestion::Directory { query, fuzzy } => {
+      return directory(&mut db, query, fuzzy).await
+    }
+
+    // guests have no profile to show
+    convos::ClientQuestion::WhoAmI => match sessions.read().unwrap().get(msg.con_id) {
+      Some(session) => ServerTell::Who {
+        id: session.uid,
+        name: session.name.clone(),
+        profile: None,
+      },
+      None => return None,
     },
 
-    convos::ClientQuestion::SignUp { username, password } => {
-      // check if the username is already taken
-      let uname_query = sqlx::query("select uid from users where name='$1'")
-        .bind(&username)
-        .fetch_one(&mut db)
+    convos::ClientQuestion::SignUp {
+      username,
+      password,
+      invite,
+    } => {
+      let (username, invite) = match check_sign_up(shared, &username, &password, invite) {
+        Ok(checked) => checked,
+        Err(e) => return Some(ServerTell::Error(e)),
+      };
+
+      let uid = match accounts::create(&mut db, &username, &password, invite.as_deref()).await {
+        Ok(uid) => uid,
+        Err(e) => return Some(ServerTell::Error(e)),
+      };
+
+      send_recovery_codes(&mut db, connection, uid).await;
+      ServerTell::Success(convos::Success::SignUp)
+    }
+
+    convos::ClientQuestion::Upgrade {
+      username,
+      password,
+      invite,
+    } => {
+      let (username, invite) = match check_sign_up(shared, &username, &password, invite) {
+        Ok(checked) => checked,
+        Err(e) => return Some(ServerTell::Error(e)),
+      };
+
+      let guest = match sessions.read().unwrap().get(msg.con_id) {
+        Some(session) if session.guest => session.uid,
+        _ => return None,
+      };
+
+      let upgraded =
+        accounts::upgrade(&mut db, guest, &username, &password, invite.as_deref()).await;
+      if let Err(e) = upgraded {
+        return Some(ServerTell::Error(e));
+      }
+
+      send_recovery_codes(&mut db, connection, guest).await;
+      sign_in(&mut db, connection, sessions, msg.con_id, guest, username).await;
+      ServerTell::Success(convos::Success::Upgraded)
+    }
+
+    convos::ClientQuestion::SignIn { username, password } => {
+      let username = policy::normalize(&username);
+      // names from elsewhere are held to the same rules, so none of them can pass for a guest
+      // or the placeholder deleted accounts leave behind
+      if !shared.authenticator.manages_accounts() && policy::check_username(&username).is_err() {
+        return Some(ServerTell::Error(convos::Error::InvalidUsername));
+      }
+      let address = match sessions.read().unwrap().get(msg.con_id) {
+        Some(session) => session.address.ip(),
+        None => return None,
+      };
+
+      let uid = match accounts::uid_of(&mut db, &username).await {
+        Ok(uid) => uid,
+        Err(e) => return Some(server_error(format_args!("fetch user {username}"), e)),
+      };
+
+      // while locked out, not even the right password gets in
+      if let Err(e) = check_lockout(&mut db, uid, address).await {
+        return Some(ServerTell::Error(e));
+      }
+
+      let outcome = shared
+        .authenticator
+        .authenticate(&mut db, &username, &password)
         .await;
+      let signed_in = match (outcome, uid) {
+        (Ok(Outcome::Accepted), Some(uid)) => Ok(uid),
+        // known to the authenticator but not here yet, which is the first sign-in
+        // of an account kept elsewhere, it still needs a uid of its own
+        (Ok(Outcome::Accepted), None) => match accounts::provision(&mut db, &username).await {
+          Ok(uid) => Ok(uid),
+          Err(e) => return Some(ServerTell::Error(e)),
+        },
+        (Ok(Outcome::WrongPassword), _) => Err(convos::Error::InvalidPassword),
+        (Ok(Outcome::NoSuchUser), _) => Err(convos::Error::InvalidUsername),
+        (Err(e), _) => return Some(server_error(format_args!("authenticate {username}"), e)),
+      };
 
-      if uname_query.is_ok() {
-        return ServerTell::Error(convos::Error::UsernameTaken);
-      };
-
-      // generate a salt for the pass
-      let salt: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(5)
-        .map(char::from)
-        .collect();
-      dbg!(&salt);
-
-      // create the hashed password
-      let password = password + &salt;
-      let mut hasher = Sha512::new();
-      hasher.update(password);
-      let hash: Vec<u8> = hasher.finalize().to_vec();
-
-      // generate a unique id
-      let uid = loop {
-        let uid: i64 = rand::random();
-        if sqlx::query("select uid from users where uid=$1")
-          .bind(uid)
-          .fetch_one(&mut db)
+      let uid = match signed_in {
+        Ok(uid) => uid,
+        Err(error) => {
+          record_failure(&mut db, uid, address).await;
+          return Some(ServerTell::Error(error));
+        }
+      };
+
+      match totp::enabled(&mut db, uid).await {
+        Ok(false) => {}
+        Ok(true) => {
+          if let Some(session) = sessions.write().unwrap().get_mut(msg.con_id) {
+            session.challenge = Some(Challenge {
+              uid,
+              name: username,
+              issued: Instant::now(),
+            });
+          }
+          // the read worker has to refuse everything but the code before the client hears of it
+          connection
+            .transition
+            .send(Transition::Challenged)
+            .await
+            .unwrap();
+          return Some(ServerTell::TwoFactorChallenge);
+        }
+        Err(e) => {
+          return Some(server_error(
+            format_args!("check two-factor authentication of {uid}"),
+            e,
+          ))
+        }
+      }
+
+      sign_in(&mut db, connection, sessions, msg.con_id, uid, username).await;
+      ServerTell::Success(convos::Success::SignIn)
+    }
+
+    // the read worker lets nothing else through until the challenge is answered,
+    // the connection stays anonymous (uid 0) until then
+    convos::ClientQuestion::SecondFactor { code } => {
+      // the challenge is only used up once answered, a wrong code doesn't start the sign-in over
+      let (challenge, address) = match sessions.read().unwrap().get(msg.con_id) {
+        Some(session) => (session.challenge.clone(), session.address.ip()),
+        None => return None,
+      };
+
+      let Some(challenge) = challenge.filter(|c| c.issued.elapsed() < CHALLENGE_TIMEOUT) else {
+        if let Some(session) = sessions.write().unwrap().get_mut(msg.con_id) {
+          session.challenge = None;
+        }
+        connection
+          .transition
+          .send(Transition::SignedOut)
           .await
-          .is_err()
-        {
-          break uid;
+          .unwrap();
+        return Some(ServerTell::Error(convos::Error::ChallengeExpired));
+      };
+      let uid = challenge.uid;
+
+      if let Err(e) = check_lockout(&mut db, Some(uid), address).await {
+        return Some(ServerTell::Error(e));
+      }
+
+      // anything that isn't a code from the app can only be a recovery code
+      let code = code.trim();
+      let accepted = if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        shared.totp.verify(&mut db, uid, code).await
+      } else {
+        recovery::consume(&mut db, uid, code).await
+      };
+
+      match accepted {
+        Ok(true) => {}
+        Ok(false) => {
+          // the lockout is what stops guessing
+          record_failure(&mut db, Some(uid), address).await;
+          return Some(ServerTell::Error(convos::Error::InvalidTwoFactorCode));
+        }
+        Err(e) => {
+          return Some(server_error(
+            format_args!("check the second factor of {uid}"),
+            e,
+          ))
         }
+      }
+
+      if let Some(session) = sessions.write().unwrap().get_mut(msg.con_id) {
+        session.challenge = None;
+      }
+      sign_in(
+        &mut db,
+        connection,
+        sessions,
+        msg.con_id,
+        uid,
+        challenge.name,
+      )
+      .await;
+      ServerTell::Success(convos::Success::SignIn)
+    }
+
+    convos::ClientQuestion::Recover {
+      username,
+      code,
+      password,
+    } => {
+      // the code would only set a password nobody ever checks
+      if !shared.authenticator.manages_accounts() {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      let username = policy::normalize(&username);
+      let address = match sessions.read().unwrap().get(msg.con_id) {
+        Some(session) => session.address.ip(),
+        None => return None,
       };
 
-      if let Err(e) = sqlx::query("insert into users values ($1, $2, $3, $4)")
-        .bind(uid)
-        .bind(&username)
-        .bind(&salt)
-        .bind(&hash)
-        .execute(&mut db)
-        .await
-      {
-        eprintln!("Ran into error when trying to insert a new user into the database {e}");
+      if let Err(reason) = shared.password_policy.check(&username, &password) {
+        return Some(ServerTell::Error(convos::Error::PasswordRejected {
+          reason,
+        }));
       }
 
-      ServerTell::Success(convos::Success::SignUp)
+      let uid = match accounts::uid_of(&mut db, &username).await {
+        Ok(uid) => uid,
+        Err(e) => return Some(server_error(format_args!("fetch user {username}"), e)),
+      };
+
+      // guessing codes counts the same as guessing passwords
+      if let Err(e) = check_lockout(&mut db, uid, address).await {
+        return Some(ServerTell::Error(e));
+      }
+
+      let recovered = match uid {
+        Some(uid) => match recovery::redeem(&mut db, uid, &code, &password).await {
+          Ok(true) => Ok(uid),
+          Ok(false) => Err(convos::Error::InvalidRecoveryCode),
+          Err(e) => return Some(server_error(format_args!("recover account {uid}"), e)),
+        },
+        None => Err(convos::Error::InvalidUsername),
+      };
+
+      let uid = match recovered {
+        Ok(uid) => uid,
+        Err(error) => {
+          record_failure(&mut db, uid, address).await;
+          return Some(ServerTell::Error(error));
+        }
+      };
+
+      sign_in(&mut db, connection, sessions, msg.con_id, uid, username).await;
+      // whoever lost the account may still be signed in elsewhere
+      sessions.read().unwrap().kick_others(uid, msg.con_id);
+      ServerTell::Success(convos::Success::Recovered)
     }
 
-    convos::ClientQuestion::SignIn { id, password } => todo!(),
-    convos::ClientQuestion::WhoIsName { name } => todo!(),
+    // the read worker already rejects these, but never trust a stale state
+    convos::ClientQuestion::UpdateProfile { .. }
+    | convos::ClientQuestion::WatchPresence { .. }
+    | convos::ClientQuestion::UnwatchPresence { .. }
+    | convos::ClientQuestion::SetAway { .. }
+    | convos::ClientQuestion::FailedSignIns
+    | convos::ClientQuestion::ListSessions
+    | convos::ClientQuestion::ChangePassword { .. }
+    | convos::ClientQuestion::Rename { .. }
+    | convos::ClientQuestion::DeleteAccount { .. }
+    | convos::ClientQuestion::NewRecoveryCodes { .. }
+    | convos::ClientQuestion::EnableTwoFactor { .. }
+    | convos::ClientQuestion::ConfirmTwoFactor { .. }
+    | convos::ClientQuestion::DisableTwoFactor { .. }
+    | convos::ClientQuestion::CreateInvite { .. }
+    | convos::ClientQuestion::KickSession { .. }
+    | convos::ClientQuestion::JoinRoom { .. }
+    | convos::ClientQuestion::LeaveRoom { .. }
+    | convos::ClientQuestion::Say { .. }
+    | convos::ClientQuestion::EditMessage { .. }
+    | convos::ClientQuestion::DeleteMessage { .. }
+    | convos::ClientQuestion::History { .. }
+    | convos::ClientQuestion::Thread { .. }
+    | convos::ClientQuestion::Mentions
+    | convos::ClientQuestion::MarkRead { .. }
+    | convos::ClientQuestion::Unread
+    | convos::ClientQuestion::Search { .. }
+    | convos::ClientQuestion::BeginUpload { .. }
+    | convos::ClientQuestion::UploadChunk { .. }
+    | convos::ClientQuestion::FinishUpload { .. }
+    | convos::ClientQuestion::Download { .. }
+    | convos::ClientQuestion::Moderate { .. }
+    | convos::ClientQuestion::SetRole { .. }
+    | convos::ClientQuestion::AuditLog { .. }
+    | convos::ClientQuestion::AllowGuests { .. }
+    | convos::ClientQuestion::React { .. }
+    | convos::ClientQuestion::Unreact { .. }
+    | convos::ClientQuestion::TypingStarted { .. }
+    | convos::ClientQuestion::TypingStopped { .. } => ServerTell::Error(convos::Error::NotLoggedIn),
+  };
+
+  Some(tell)
+}
+
+// the few questions a guest may ask get answered as if it were signed in, under its guest uid,
+// but only ever about rooms, and only rooms that let guests in
+async fn guest_message_worker(
+  mut db: PoolConnection<Postgres>,
+  connection: &ConnectionHandle,
+  shared: &Shared,
+  msg: ClientQuestion,
+) -> Option<ServerTell> {
+  let (uid, name) = match shared.sessions.read().unwrap().get(msg.con_id) {
+    Some(session) if session.guest => (session.uid, session.name.clone()),
+    _ => return None,
+  };
+
+  match &msg.data {
+    convos::ClientQuestion::JoinRoom { name: room } => {
+      match rooms::allows_guests(&mut db, room).await {
+        Ok(true) => {}
+        Ok(false) => return Some(ServerTell::Error(convos::Error::GuestsNotAllowed)),
+        Err(e) => return Some(server_error(format_args!("find room {room}"), e)),
+      }
+
+      // what it says there needs an author
+      if let Err(e) = accounts::add_guest(&mut db, uid, &name).await {
+        return Some(server_error(format_args!("add guest {name}"), e));
+      }
+    }
+
+    convos::ClientQuestion::Say {
+      target: convos::Target::Direct(_),
+      ..
+    }
+    | convos::ClientQuestion::History {
+      target: convos::Target::Direct(_),
+      ..
+    }
+    | convos::ClientQuestion::TypingStarted {
+      target: convos::Target::Direct(_),
+    } => return Some(ServerTell::Error(convos::Error::NotPermitted)),
+
+    _ => {}
   }
+
+  signed_in_message_worker(db, connection, shared, ClientQuestion { uid, ..msg }).await
 }
 
 async fn signed_in_message_worker(
-  db: PoolConnection<Postgres>,
+  mut db: PoolConnection<Postgres>,
   connection: &ConnectionHandle,
+  shared: &Shared,
   msg: ClientQuestion,
-) -> ServerTell {
-  match msg.data {
-    convos::ClientQuestion::WhoIsID { id } => todo!(),
-    convos::ClientQuestion::WhoIsName { name } => todo!(),
-    convos::ClientQuestion::WhoAmI => todo!(),
-    convos::ClientQuestion::SignUp { username, password } => todo!(),
-    convos::ClientQuestion::SignIn { id, password } => todo!(),
+) -> Option<ServerTell> {
+  let sessions = &shared.sessions;
+
+  let tell = match msg.data {
+    convos::ClientQuestion::WhoIsID { id } => who_is_id(&mut db, id).await,
+    convos::ClientQuestion::WhoIsName { name } => who_is_name(&mut db, name).await,
+    convos::ClientQuestion::WhoAmI => who_is_id(&mut db, msg.uid).await,
+    convos::ClientQuestion::Directory { query, fuzzy } => {
+      return directory(&mut db, query, fuzzy).await
+    }
+    convos::ClientQuestion::NumConnected => num_connected(sessions),
+
+    convos::ClientQuestion::UpdateProfile { changes } => {
+      match profiles::update(&mut db, msg.uid, changes).await {
+        Ok(()) => who_is_id(&mut db, msg.uid).await,
+        Err(e) => ServerTell::Error(e),
+      }
+    }
+    convos::ClientQuestion::PresenceOf { id } => presence_of(sessions, id),
+
+    convos::ClientQuestion::WatchPresence { id } => {
+      sessions
+        .write()
+        .unwrap()
+        .watchers
+        .watch(id, msg.con_id, connection.to_connection.clone());
+      presence_of(sessions, id)
+    }
+
+    convos::ClientQuestion::UnwatchPresence { id } => {
+      sessions.write().unwrap().watchers.unwatch(id, msg.con_id);
+      ServerTell::Success(convos::Success::Unwatched)
+    }
+
+    convos::ClientQuestion::SetAway { away } => {
+      let change = sessions.write().unwrap().set_away(msg.con_id, away);
+      if let Some(change) = change {
+        change.deliver().await;
+      }
+
+      presence_of(sessions, msg.uid)
+    }
+
+    convos::ClientQuestion::FailedSignIns => match lockout::unreported(&mut db, msg.uid).await {
+      Ok(attempts) => ServerTell::FailedSignIns { attempts },
+      Err(e) => return Some(server_error(format_args!("fetch failed sign-ins"), e)),
+    },
+
+    convos::ClientQuestion::ListSessions => ServerTell::Sessions {
+      sessions: sessions.read().unwrap().session_infos(msg.uid, msg.con_id),
+    },
+
+    convos::ClientQuestion::ChangePassword { old, new } => {
+      if !shared.authenticator.manages_accounts() {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      if let Err(e) = reauthenticate(&mut db, shared, msg.con_id, msg.uid, &old).await {
+        return Some(ServerTell::Error(e));
+      }
+
+      let username = sessions.read().unwrap().name_of(msg.con_id);
+      if let Err(reason) = shared.password_policy.check(&username, &new) {
+        return Some(ServerTell::Error(convos::Error::PasswordRejected {
+          reason,
+        }));
+      }
+
+      match accounts::set_password(&mut db, msg.uid, &new).await {
+        Ok(()) => {
+          // a stolen password shouldn't keep its sessions around
+          sessions.read().unwrap().kick_others(msg.uid, msg.con_id);
+          ServerTell::Success(convos::Success::PasswordChanged)
+        }
+        Err(e) => server_error(format_args!("change the password of {}", msg.uid), e),
+      }
+    }
+
+    // accounts kept elsewhere are known by their name there
+    convos::ClientQuestion::Rename { username } => {
+      if !shared.authenticator.manages_accounts() {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      let username = policy::normalize(&username);
+      if let Err(reason) = policy::check_username(&username) {
+        return Some(ServerTell::Error(convos::Error::UsernameRejected {
+          reason,
+        }));
+      }
+
+      if let Err(e) = accounts::rename(&mut db, msg.uid, &username).await {
+        return Some(ServerTell::Error(e));
+      }
+
+      sessions.write().unwrap().rename(msg.uid, &username);
+      ServerTell::Success(convos::Success::Renamed)
+    }
+
+    convos::ClientQuestion::DeleteAccount { password, confirm } => {
+      let username = sessions.read().unwrap().name_of(msg.con_id);
+      if policy::normalize(&confirm) != username {
+        return Some(ServerTell::Error(convos::Error::ConfirmationMismatch));
+      }
+
+      if let Err(e) = reauthenticate(&mut db, shared, msg.con_id, msg.uid, &password).await {
+        return Some(ServerTell::Error(e));
+      }
+
+      if let Err(e) = accounts::delete(&mut db, msg.uid).await {
+        return Some(server_error(format_args!("delete account {}", msg.uid), e));
+      }
+
+      // every other device is disconnected, this one is left connected but anonymous
+      sessions.read().unwrap().kick_others(msg.uid, msg.con_id);
+
+      connection
+        .transition
+        .send(Transition::SignedOut)
+        .await
+        .unwrap();
+
+      let change = sessions.write().unwrap().sign_out(msg.con_id);
+      if let Some(change) = change {
+        change.deliver().await;
+      }
+
+      ServerTell::Success(convos::Success::AccountDeleted)
+    }
+
+    convos::ClientQuestion::NewRecoveryCodes { password } => {
+      if !shared.authenticator.manages_accounts() {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      if let Err(e) = reauthenticate(&mut db, shared, msg.con_id, msg.uid, &password).await {
+        return Some(ServerTell::Error(e));
+      }
+
+      match recovery::generate(&mut db, msg.uid).await {
+        Ok(codes) => ServerTell::RecoveryCodes { codes },
+        Err(e) => server_error(format_args!("generate recovery codes for {}", msg.uid), e),
+      }
+    }
+
+    convos::ClientQuestion::EnableTwoFactor { password } => {
+      if let Err(e) = reauthenticate(&mut db, shared, msg.con_id, msg.uid, &password).await {
+        return Some(ServerTell::Error(e));
+      }
+
+      let username = sessions.read().unwrap().name_of(msg.con_id);
+      match totp::enrol(&mut db, msg.uid, &username).await {
+        Ok(Some(uri)) => ServerTell::TwoFactorEnrolment { uri },
+        Ok(None) => ServerTell::Error(convos::Error::TwoFactorAlreadyEnabled),
+        Err(e) => server_error(
+          format_args!("enrol {} in two-factor authentication", msg.uid),
+          e,
+        ),
+      }
+    }
+
+    convos::ClientQuestion::ConfirmTwoFactor { code } => {
+      match shared.totp.confirm(&mut db, msg.uid, &code).await {
+        Ok(true) => ServerTell::Success(convos::Success::TwoFactorEnabled),
+        Ok(false) => ServerTell::Error(convos::Error::InvalidTwoFactorCode),
+        Err(e) => server_error(
+          format_args!("confirm two-factor authentication of {}", msg.uid),
+          e,
+        ),
+      }
+    }
+
+    convos::ClientQuestion::DisableTwoFactor { password } => {
+      if let Err(e) = reauthenticate(&mut db, shared, msg.con_id, msg.uid, &password).await {
+        return Some(ServerTell::Error(e));
+      }
+
+      match totp::disable(&mut db, msg.uid).await {
+        Ok(()) => ServerTell::Success(convos::Success::TwoFactorDisabled),
+        Err(e) => server_error(
+          format_args!("disable two-factor authentication of {}", msg.uid),
+          e,
+        ),
+      }
+    }
+
+    convos::ClientQuestion::CreateInvite { uses, expires_in } => {
+      if !roles::has_permission(&mut db, msg.uid, None, convos::Permission::Invite).await {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      // an invite for nobody is an invite for one
+      let uses = uses.map(|uses| uses.max(1));
+      match invites::create(&mut db, msg.uid, uses, expires_in).await {
+        Ok((code, expires_at)) => ServerTell::Invite {
+          code,
+          uses,
+          expires_at,
+        },
+        Err(e) => return Some(server_error(format_args!("create an invite"), e)),
+      }
+    }
+
+    convos::ClientQuestion::KickSession { id } => {
+      // only ever let a user kick their own devices
+      let kill = match sessions.read().unwrap().get(id) {
+        Some(session) if session.uid == msg.uid => session.handle.kill.clone(),
+        _ => return Some(ServerTell::Error(convos::Error::InvalidSession)),
+      };
+
+      // the read worker reports the connection closed once it dies,
+      // which cleans up the session like any other disconnect
+      let _ = kill.send(());
+
+      ServerTell::Success(convos::Success::Kicked)
+    }
+
+    convos::ClientQuestion::JoinRoom { name } => {
+      match rooms::find(&mut db, &name).await {
+        Ok(Some(room)) if moderation::is_banned(&mut db, msg.uid, room).await => {
+          return Some(ServerTell::Error(convos::Error::Banned));
+        }
+        Ok(_) => {}
+        Err(e) => return Some(server_error(format_args!("find room {name}"), e)),
+      }
+
+      match rooms::join(&mut db, msg.uid, &name).await {
+        Ok(room) => ServerTell::Joined { room, name },
+        Err(e) => return Some(server_error(format_args!("join room {name}"), e)),
+      }
+    }
+
+    convos::ClientQuestion::LeaveRoom { room } => {
+      match rooms::leave(&mut db, msg.uid, room).await {
+        Ok(true) => ServerTell::Success(convos::Success::Left),
+        Ok(false) => ServerTell::Error(convos::Error::NotInRoom),
+        Err(e) => return Some(server_error(format_args!("leave room {room}"), e)),
+      }
+    }
+
+    convos::ClientQuestion::Say {
+      target,
+      content,
+      reply_to,
+      attachments,
+    } => {
+      if let Err(e) = check_message_len(&content) {
+        return Some(ServerTell::Error(e));
+      }
+
+      let recipients = match rooms::recipients(&mut db, sessions, msg.uid, msg.con_id, target).await
+      {
+        Ok(recipients) => recipients,
+        Err(e) => return Some(ServerTell::Error(e)),
+      };
+
+      if let convos::Target::Room(room) = target {
+        if moderation::is_muted(&mut db, msg.uid, room).await {
+          return Some(ServerTell::Error(convos::Error::Muted));
+        }
+      }
+
+      // threads are only ever one level deep, replying to a reply continues its thread
+      let reply_to = match reply_to {
+        Some(id) => match messages::fetch(&mut db, id).await {
+          Ok(Some(parent)) if parent.target_for(msg.uid) == target => {
+            Some(parent.reply_to.unwrap_or(id))
+          }
+          Ok(_) => return Some(ServerTell::Error(convos::Error::InvalidMessage)),
+          Err(e) => return Some(server_error(format_args!("fetch message {id}"), e)),
+        },
+        None => None,
+      };
+
+      // only ever your own uploads, each of them once
+      let mut attachments = attachments;
+      attachments.sort_unstable();
+      attachments.dedup();
+      if attachments.len() > attachments::MAX_PER_MESSAGE {
+        return Some(ServerTell::Error(convos::Error::InvalidAttachment));
+      }
+      let attached = match attachments::owned(&mut db, msg.uid, &attachments).await {
+        Ok(attached) if attached.len() == attachments.len() => attached,
+        Ok(_) => return Some(ServerTell::Error(convos::Error::InvalidAttachment)),
+        Err(e) => {
+          return Some(server_error(
+            format_args!("fetch attachments {attachments:?}"),
+            e,
+          ))
+        }
+      };
+
+      // saying something is the end of typing it
+      let typing = shared.typing.write().unwrap().stop(target, msg.uid);
+      if let Some(told) = typing {
+        rooms::deliver(told, |target| ServerTell::TypingStopped {
+          target,
+          who: msg.uid,
+        })
+        .await;
+      }
+
+      let id =
+        match messages::store(&mut db, msg.uid, target, &content, reply_to, &attachments).await {
+          Ok(id) => id,
+          Err(e) => return Some(server_error(format_args!("store a message"), e)),
+        };
+
+      let name = sessions.read().unwrap().name_of(msg.con_id);
+      rooms::deliver(recipients, |target| ServerTell::Syndication {
+        id,
+        from: msg.uid,
+        name: name.clone(),
+        target,
+        content: content.clone(),
+        reply_to,
+        attachments: attached.clone(),
+      })
+      .await;
+
+      if let Err(e) =
+        mentions::notify(&mut db, sessions, id, msg.uid, &name, target, &content).await
+      {
+        eprintln!("Ran into error when trying to notify mentions in message {id}: {e}");
+      }
+
+      ServerTell::Syndication {
+        id,
+        from: msg.uid,
+        name,
+        target,
+        content,
+        reply_to,
+        attachments: attached,
+      }
+    }
+
+    convos::ClientQuestion::EditMessage { id, content } => {
+      if let Err(e) = check_message_len(&content) {
+        return Some(ServerTell::Error(e));
+      }
+
+      let message = match messages::fetch(&mut db, id).await {
+        Ok(Some(message)) if !message.deleted => message,
+        Ok(_) => return Some(ServerTell::Error(convos::Error::InvalidMessage)),
+        Err(e) => return Some(server_error(format_args!("fetch message {id}"), e)),
+      };
+
+      // only ever the author, not even moderators get to put words in someone's mouth
+      if message.author != msg.uid {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      if let Err(e) = messages::edit(&mut db, id, &content).await {
+        return Some(server_error(format_args!("edit message {id}"), e));
+      }
+
+      let audience = match rooms::audience(&mut db, message.author, message.target).await {
+        Ok(audience) => audience,
+        Err(e) => return Some(ServerTell::Error(e)),
+      };
+      rooms::deliver(rooms::reach(sessions, audience, msg.con_id), |target| {
+        ServerTell::Edited {
+          id,
+          target,
+          content: content.clone(),
+        }
+      })
+      .await;
+
+      ServerTell::Edited {
+        id,
+        target: message.target,
+        content,
+      }
+    }
+
+    convos::ClientQuestion::DeleteMessage { id } => {
+      let message = match messages::fetch(&mut db, id).await {
+        Ok(Some(message)) if !message.deleted => message,
+        Ok(_) => return Some(ServerTell::Error(convos::Error::InvalidMessage)),
+        Err(e) => return Some(server_error(format_args!("fetch message {id}"), e)),
+      };
+
+      // anyone else's needs the permission where it was said, server-wide for dms
+      let room = match message.target {
+        convos::Target::Room(room) => Some(room),
+        convos::Target::Direct(_) => None,
+      };
+      let moderated = message.author != msg.uid;
+      if moderated
+        && !roles::has_permission(&mut db, msg.uid, room, convos::Permission::DeleteMessages).await
+      {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      let moderator = moderated.then_some(msg.uid);
+      if let Err(e) = messages::delete(&mut db, id, moderator).await {
+        return Some(server_error(format_args!("delete message {id}"), e));
+      }
+
+      // everyone sees the deletion the way they saw the message, from the author's side
+      let audience = match rooms::audience(&mut db, message.author, message.target).await {
+        Ok(audience) => audience,
+        Err(e) => return Some(ServerTell::Error(e)),
+      };
+      let reply_to = message.reply_to;
+      rooms::deliver(rooms::reach(sessions, audience, msg.con_id), |target| {
+        ServerTell::Deleted {
+          id,
+          target,
+          reply_to,
+        }
+      })
+      .await;
+
+      ServerTell::Deleted {
+        id,
+        target: message.target,
+        reply_to,
+      }
+    }
+
+    convos::ClientQuestion::History { target, before } => {
+      if let convos::Target::Room(room) = target {
+        if !rooms::is_member(&mut db, msg.uid, room).await {
+          return Some(ServerTell::Error(convos::Error::NotInRoom));
+        }
+      }
+
+      match messages::history(&mut db, msg.uid, target, before).await {
+        Ok(messages) => ServerTell::History { target, messages },
+        Err(e) => return Some(server_error(format_args!("fetch history of {target:?}"), e)),
+      }
+    }
+
+    convos::ClientQuestion::Thread { id } => {
+      let root = match messages::fetch(&mut db, id).await {
+        Ok(Some(root)) => root,
+        Ok(None) => return Some(ServerTell::Error(convos::Error::InvalidMessage)),
+        Err(e) => return Some(server_error(format_args!("fetch message {id}"), e)),
+      };
+
+      if !messages::can_see(&mut db, msg.uid, &root).await {
+        return Some(ServerTell::Error(convos::Error::InvalidMessage));
+      }
+
+      // asking after a reply gets the whole thread it is in
+      let id = root.reply_to.unwrap_or(id);
+      match messages::thread(&mut db, msg.uid, id).await {
+        Ok((root_message, replies)) => ServerTell::Thread {
+          target: root.target_for(msg.uid),
+          root: root_message,
+          replies,
+        },
+        Err(e) => return Some(server_error(format_args!("fetch thread {id}"), e)),
+      }
+    }
+
+    convos::ClientQuestion::Mentions => match mentions::undelivered(&mut db, msg.uid).await {
+      Ok(mentions) => ServerTell::Mentions { mentions },
+      Err(e) => {
+        return Some(server_error(
+          format_args!("fetch mentions of {}", msg.uid),
+          e,
+        ))
+      }
+    },
+
+    convos::ClientQuestion::MarkRead { room, id } => {
+      if !rooms::is_member(&mut db, msg.uid, room).await {
+        return Some(ServerTell::Error(convos::Error::NotInRoom));
+      }
+
+      match messages::fetch(&mut db, id).await {
+        Ok(Some(message)) if message.target == convos::Target::Room(room) => {}
+        Ok(_) => return Some(ServerTell::Error(convos::Error::InvalidMessage)),
+        Err(e) => return Some(server_error(format_args!("fetch message {id}"), e)),
+      }
+
+      match unread::mark_read(&mut db, msg.uid, room, id).await {
+        // the user's other devices catch up too
+        Ok(true) => {
+          let audience = vec![(msg.uid, convos::Target::Room(room))];
+          rooms::deliver(rooms::reach(sessions, audience, msg.con_id), |_| {
+            ServerTell::ReadMarker { room, id }
+          })
+          .await;
+        }
+        // an older message than the one already read, nothing moves
+        Ok(false) => return None,
+        Err(e) => return Some(server_error(format_args!("mark {room} read"), e)),
+      }
+
+      ServerTell::ReadMarker { room, id }
+    }
+
+    convos::ClientQuestion::Unread => match unread::counts(&mut db, msg.uid).await {
+      Ok(rooms) => ServerTell::Unread { rooms },
+      Err(e) => {
+        return Some(server_error(
+          format_args!("count unread messages of {}", msg.uid),
+          e,
+        ))
+      }
+    },
+
+    convos::ClientQuestion::Search {
+      query,
+      filter,
+      page,
+    } => {
+      if query.trim().is_empty() || query.len() > search::MAX_QUERY_LENGTH {
+        return Some(ServerTell::Error(convos::Error::InvalidQuery));
+      }
+
+      match search::search(&mut db, msg.uid, &query, &filter, page).await {
+        Ok((results, more)) => ServerTell::SearchResults {
+          query,
+          page,
+          results,
+          more,
+        },
+        Err(e) => return Some(server_error(format_args!("search for {query:?}"), e)),
+      }
+    }
+
+    convos::ClientQuestion::Moderate {
+      room,
+      id,
+      action,
+      reason,
+    } => return moderate(&mut db, sessions, msg.uid, room, id, action, reason).await,
+
+    convos::ClientQuestion::SetRole { room, id, role } => {
+      // nobody hands themselves a role, or takes away the one that lets them hand them out
+      if id == msg.uid
+        || !roles::has_permission(&mut db, msg.uid, room, convos::Permission::ManageRoom).await
+      {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      // and never over anyone at or above their own rank
+      match roles::may_assign(&mut db, msg.uid, id, room, role.as_deref()).await {
+        Ok(true) => {}
+        Ok(false) => return Some(ServerTell::Error(convos::Error::NotPermitted)),
+        Err(e) => return Some(server_error(format_args!("rank the role of {id}"), e)),
+      }
+
+      match roles::set(&mut db, room, id, role.as_deref()).await {
+        Ok(true) => {}
+        Ok(false) => return Some(ServerTell::Error(convos::Error::InvalidRole)),
+        Err(e) => return Some(server_error(format_args!("set the role of {id}"), e)),
+      }
+
+      let logged = moderation::record(
+        &mut db,
+        msg.uid,
+        "set_role",
+        room,
+        Some(id),
+        None,
+        Some(role.as_deref().unwrap_or("none")),
+      )
+      .await;
+      if let Err(e) = logged {
+        eprintln!("Ran into error when trying to log setting the role of {id}: {e}");
+      }
+
+      ServerTell::RoleSet { room, id, role }
+    }
+
+    convos::ClientQuestion::AuditLog { room } => {
+      if !roles::has_permission(&mut db, msg.uid, room, convos::Permission::ManageRoom).await {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      match moderation::log(&mut db, room).await {
+        Ok(entries) => ServerTell::AuditLog { entries },
+        Err(e) => return Some(server_error(format_args!("fetch the moderation log"), e)),
+      }
+    }
+
+    convos::ClientQuestion::AllowGuests { room, allowed } => {
+      if !roles::has_permission(&mut db, msg.uid, Some(room), convos::Permission::ManageRoom).await
+      {
+        return Some(ServerTell::Error(convos::Error::NotPermitted));
+      }
+
+      match rooms::allow_guests(&mut db, room, allowed).await {
+        Ok(true) => {}
+        Ok(false) => return Some(ServerTell::Error(convos::Error::NotInRoom)),
+        Err(e) => {
+          return Some(server_error(
+            format_args!("change guest access to room {room}"),
+            e,
+          ))
+        }
+      }
+
+      let action = match allowed {
+        true => "allow_guests",
+        false => "disallow_guests",
+      };
+      let logged = moderation::record(&mut db, msg.uid, action, Some(room), None, None, None).await;
+      if let Err(e) = logged {
+        eprintln!("Ran into error when trying to log guest access to room {room}: {e}");
+      }
+
+      ServerTell::Success(convos::Success::GuestAccessChanged)
+    }
+
+    convos::ClientQuestion::BeginUpload { name, size, hash } => {
+      if size > attachments::MAX_ATTACHMENT_SIZE {
+        return Some(ServerTell::Error(convos::Error::AttachmentTooLarge));
+      }
+      if name.is_empty() || !attachments::is_valid_hash(&hash) {
+        return Some(ServerTell::Error(convos::Error::InvalidUpload));
+      }
+      if name.chars().count() > convos::MAX_ATTACHMENT_NAME_LEN {
+        return Some(ServerTell::Error(convos::Error::AttachmentNameTooLong {
+          max: convos::MAX_ATTACHMENT_NAME_LEN as u64,
+        }));
+      }
+
+      let id = ids::next();
+      let upload = match Upload::create(id, name, size, hash).await {
+        Ok(upload) => upload,
+        Err(e) => return Some(server_error(format_args!("start upload {id}"), e)),
+      };
+
+      // uploads still going count too, or starting several at once would get around the quota
+      match attachments::reserve(&mut db, &shared.uploads, id, msg.con_id, msg.uid, upload).await {
+        Ok(true) => ServerTell::UploadReady { upload: id },
+        Ok(false) => ServerTell::Error(convos::Error::QuotaExceeded),
+        Err(e) => server_error(format_args!("reserve quota of {}", msg.uid), e),
+      }
+    }
+
+    convos::ClientQuestion::UploadChunk {
+      upload: id,
+      offset,
+      data,
+    } => {
+      let upload = shared.uploads.read().unwrap().get(id, msg.con_id);
+      let (Some(upload), Ok(data)) = (upload, BASE64.decode(data)) else {
+        return Some(ServerTell::Error(convos::Error::InvalidUpload));
+      };
+
+      let mut upload = upload.lock().await;
+
+      // no upload reaches anywhere near the end of a u64, a chunk that does is nonsense
+      let Some(end) = offset.checked_add(data.len() as u64) else {
+        return Some(ServerTell::Error(convos::Error::InvalidUpload));
+      };
+
+      // a chunk sent again, say after being rate limited, only needs acknowledging
+      if end <= upload.received {
+        return Some(ServerTell::ChunkReceived {
+          upload: id,
+          received: upload.received,
+        });
+      }
+
+      if data.len() > convos::CHUNK_SIZE || !upload.accepts(offset, data.len()) {
+        return Some(ServerTell::Error(convos::Error::InvalidUpload));
+      }
+
+      if let Err(e) = upload.write(&data).await {
+        return Some(server_error(format_args!("write to upload {id}"), e));
+      }
+
+      ServerTell::ChunkReceived {
+        upload: id,
+        received: upload.received,
+      }
+    }
+
+    convos::ClientQuestion::FinishUpload { upload: id } => {
+      let upload = shared.uploads.write().unwrap().take(id, msg.con_id);
+      let Some(upload) = upload else {
+        return Some(ServerTell::Error(convos::Error::InvalidUpload));
+      };
+
+      let mut upload = upload.lock().await;
+      let tell = finish_upload(&mut db, &shared.uploads, id, msg.uid, &mut upload).await;
+      // recording it released it already, anything short of that still has to
+      shared.uploads.write().unwrap().release(id);
+      tell?
+    }
+
+    convos::ClientQuestion::Download { id, offset } => {
+      let attachment = match attachments::visible(&mut db, msg.uid, id).await {
+        Ok(Some(attachment)) if offset <= attachment.size => attachment,
+        Ok(_) => return Some(ServerTell::Error(convos::Error::InvalidAttachment)),
+        Err(e) => return Some(server_error(format_args!("fetch attachment {id}"), e)),
+      };
+
+      match attachments::read_chunk(&attachment.hash, offset, convos::CHUNK_SIZE).await {
+        Ok(data) => ServerTell::AttachmentChunk {
+          id,
+          offset,
+          size: attachment.size,
+          data: BASE64.encode(data),
+        },
+        Err(e) => return Some(server_error(format_args!("read attachment {id}"), e)),
+      }
+    }
+
+    convos::ClientQuestion::React { id, emoji } => {
+      return set_reaction(&mut db, sessions, msg.uid, msg.con_id, id, emoji, true).await
+    }
+    convos::ClientQuestion::Unreact { id, emoji } => {
+      return set_reaction(&mut db, sessions, msg.uid, msg.con_id, id, emoji, false).await
+    }
+
+    convos::ClientQuestion::TypingStarted { target } => {
+      // already typing there keeps the indicator up however often it is said,
+      // only starting one again is held to the rate limit, nobody needs to hear it that often
+      {
+        let mut typing = shared.typing.write().unwrap();
+        if typing.refresh(target, msg.uid) || !typing.allow(msg.con_id) {
+          return None;
+        }
+      }
+
+      let Ok(recipients) = rooms::recipients(&mut db, sessions, msg.uid, msg.con_id, target).await
+      else {
+        return None;
+      };
+
+      shared
+        .typing
+        .write()
+        .unwrap()
+        .start(target, msg.uid, recipients.clone());
+
+      let name = sessions.read().unwrap().name_of(msg.con_id);
+      rooms::deliver(recipients, |target| ServerTell::TypingStarted {
+        target,
+        who: msg.uid,
+        name: name.clone(),
+      })
+      .await;
+
+      return None;
+    }
+
+    convos::ClientQuestion::TypingStopped { target } => {
+      let typing = shared.typing.write().unwrap().stop(target, msg.uid);
+      if let Some(told) = typing {
+        rooms::deliver(told, |target| ServerTell::TypingStopped {
+          target,
+          who: msg.uid,
+        })
+        .await;
+      }
+
+      return None;
+    }
+
+    // the read worker already rejects these, but never trust a stale state
+    convos::ClientQuestion::SignUp { .. }
+    | convos::ClientQuestion::SignIn { .. }
+    | convos::ClientQuestion::Recover { .. }
+    | convos::ClientQuestion::SecondFactor { .. }
+    | convos::ClientQuestion::Upgrade { .. } => ServerTell::Error(convos::Error::AlreadyLoggedIn),
+  };
+
+  Some(tell)
+}
+
+// adds or removes the asker's reaction and tells everyone who can see the message
+async fn set_reaction(
+  db: &mut PoolConnection<Postgres>,
+  sessions: &RwLock<Sessions>,
+  uid: Uid,
+  con_id: ConID,
+  id: u64,
+  emoji: String,
+  reacted: bool,
+) -> Option<ServerTell> {
+  // a real emoji, skin tones and all, spelled the one way so the same emoji counts once
+  let Some(emoji) = emojis::get(&emoji).map(|emoji| emoji.as_str().to_owned()) else {
+    return Some(ServerTell::Error(convos::Error::InvalidEmoji));
+  };
+
+  let message = match messages::fetch(db, id).await {
+    Ok(Some(message)) if !message.deleted => message,
+    Ok(_) => return Some(ServerTell::Error(convos::Error::InvalidMessage)),
+    Err(e) => return Some(server_error(format_args!("fetch message {id}"), e)),
+  };
+
+  // reacting to something you can't see is as good as it not existing
+  if !messages::can_see(db, uid, &message).await {
+    return Some(ServerTell::Error(convos::Error::InvalidMessage));
+  }
+
+  let target = message.target_for(uid);
+
+  let changed = match messages::set_reaction(db, id, uid, &emoji, reacted).await {
+    Ok(Some(changed)) => changed,
+    Ok(None) => return Some(ServerTell::Error(convos::Error::TooManyReactions)),
+    Err(e) => return Some(server_error(format_args!("react to message {id}"), e)),
+  };
+
+  let reactors = match messages::reactors(db, id, &emoji).await {
+    Ok(reactors) => reactors,
+    Err(e) => {
+      return Some(server_error(
+        format_args!("fetch reactions to message {id}"),
+        e,
+      ))
+    }
+  };
+
+  let reaction = |mine| convos::Reaction {
+    emoji: emoji.clone(),
+    count: reactors.len() as u64,
+    mine,
+  };
+
+  if changed {
+    // everyone sees the reaction the way they saw the message, from the author's side
+    let audience = match rooms::audience(db, message.author, message.target).await {
+      Ok(audience) => audience,
+      Err(e) => return Some(ServerTell::Error(e)),
+    };
+
+    // not rooms::deliver, whether it is theirs differs per recipient
+    for recipient in rooms::reach(sessions, audience, con_id) {
+      let _ = recipient
+        .to
+        .send(ServerTell::Reacted {
+          id,
+          target: recipient.target,
+          reaction: reaction(reactors.contains(&recipient.uid)),
+        })
+        .await;
+    }
+  }
+
+  Some(ServerTell::Reacted {
+    id,
+    target,
+    reaction: reaction(reacted),
+  })
+}
+
+// how long a right password is good for while the second factor is fetched
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+async fn sign_in(
+  db: &mut PoolConnection<Postgres>,
+  connection: &ConnectionHandle,
+  sessions: &RwLock<Sessions>,
+  con_id: ConID,
+  uid: Uid,
+  name: String,
+) {
+  // the read worker has to learn the uid before the client learns it is signed in,
+  // otherwise the next question could still be stamped as anonymous
+  connection
+    .transition
+    .send(Transition::SignedIn(uid))
+    .await
+    .unwrap();
+
+  let (change, left_behind) = {
+    let mut sessions = sessions.write().unwrap();
+    let left_behind = sessions
+      .get(con_id)
+      .filter(|session| session.guest && session.uid != uid)
+      .map(|session| session.uid);
+    (sessions.sign_in(con_id, uid, name), left_behind)
+  };
+  if let Some(change) = change {
+    change.deliver().await;
+  }
+
+  // signing in to an account that isn't the guest's leaves the guest with nobody to be
+  if let Some(guest) = left_behind {
+    if let Err(e) = accounts::retire_guest(db, guest).await {
+      eprintln!("Ran into error when trying to retire guest {guest}: {e}");
+    }
   }
 }
 
+// whether signing up with these is allowed at all, handing back the normalized username
+// and the invite, if it is needed
+fn check_sign_up(
+  shared: &Shared,
+  username: &str,
+  password: &str,
+  invite: Option<String>,
+) -> Result<(String, Option<String>), convos::Error> {
+  // accounts kept elsewhere are signed up for there
+  if !shared.authenticator.manages_accounts() {
+    return Err(convos::Error::NotPermitted);
+  }
+
+  let invite = match (shared.registration, invite) {
+    (Registration::Open, _) => None,
+    (Registration::Closed, _) => return Err(convos::Error::RegistrationClosed),
+    (Registration::InviteOnly, None) => return Err(convos::Error::InviteRequired),
+    (Registration::InviteOnly, Some(invite)) => Some(invite),
+  };
+
+  let username = policy::normalize(username);
+  if let Err(reason) = policy::check_username(&username) {
+    return Err(convos::Error::UsernameRejected { reason });
+  }
+  if let Err(reason) = shared.password_policy.check(&username, password) {
+    return Err(convos::Error::PasswordRejected { reason });
+  }
+
+  Ok((username, invite))
+}
+
+async fn send_recovery_codes(
+  db: &mut PoolConnection<Postgres>,
+  connection: &ConnectionHandle,
+  uid: Uid,
+) {
+  // the account is there either way, new codes can always be asked for later
+  match recovery::generate(db, uid).await {
+    Ok(codes) => {
+      let _ = connection
+        .to_connection
+        .send(ServerTell::RecoveryCodes { codes })
+        .await;
+    }
+    Err(e) => eprintln!("Ran into error when trying to generate recovery codes for {uid}: {e}"),
+  }
+}
+
+async fn check_lockout(
+  db: &mut PoolConnection<Postgres>,
+  uid: Option<Uid>,
+  address: IpAddr,
+) -> Result<(), convos::Error> {
+  match lockout::locked_for(db, uid, address).await {
+    Ok(wait) if wait.is_zero() => Ok(()),
+    Ok(wait) => Err(convos::Error::LockedOut {
+      retry_after: wait.as_secs() + 1,
+    }),
+    Err(e) => {
+      eprintln!("Ran into error when trying to check sign-in failures of {address}: {e}");
+      Err(convos::Error::ServerError)
+    }
+  }
+}
+
+async fn record_failure(db: &mut PoolConnection<Postgres>, uid: Option<Uid>, address: IpAddr) {
+  if let Err(e) = lockout::record(db, uid, address).await {
+    eprintln!("Ran into error when trying to record a failed sign-in from {address}: {e}");
+  }
+}
+
+// checks the password of someone already signed in before anything drastic,
+// counting towards the same lockout as signing in does
+async fn reauthenticate(
+  db: &mut PoolConnection<Postgres>,
+  shared: &Shared,
+  con_id: ConID,
+  uid: Uid,
+  password: &str,
+) -> Result<(), convos::Error> {
+  let (address, username) = match shared.sessions.read().unwrap().get(con_id) {
+    Some(session) => (session.address.ip(), session.name.clone()),
+    None => return Err(convos::Error::NotConnected),
+  };
+
+  check_lockout(db, Some(uid), address).await?;
+
+  match shared
+    .authenticator
+    .authenticate(db, &username, password)
+    .await
+  {
+    Ok(Outcome::Accepted) => Ok(()),
+    Ok(_) => {
+      record_failure(db, Some(uid), address).await;
+      Err(convos::Error::InvalidPassword)
+    }
+    Err(e) => {
+      eprintln!("Ran into error when trying to check the password of {uid}: {e}");
+      Err(convos::Error::ServerError)
+    }
+  }
+}
+
+// kicks, bans or mutes someone in a room and lets every device of theirs know
+async fn moderate(
+  db: &mut PoolConnection<Postgres>,
+  sessions: &RwLock<Sessions>,
+  uid: Uid,
+  room: u64,
+  id: Uid,
+  action: convos::Moderation,
+  reason: Option<String>,
+) -> Option<ServerTell> {
+  // moderators can't be moderated by their peers, and nobody moderates themselves
+  let permission = action.permission();
+  if id == uid
+    || !roles::has_permission(db, uid, Some(room), permission).await
+    || roles::has_permission(db, id, Some(room), permission).await
+  {
+    return Some(ServerTell::Error(convos::Error::NotPermitted));
+  }
+
+  if reason
+    .as_ref()
+    .is_some_and(|reason| reason.chars().count() > convos::MAX_REASON_LEN)
+  {
+    return Some(ServerTell::Error(convos::Error::ReasonTooLong {
+      max: convos::MAX_REASON_LEN as u64,
+    }));
+  }
+
+  match moderation::apply(db, uid, room, id, action, reason.as_deref()).await {
+    Ok(true) => {}
+    Ok(false) => {
+      return Some(ServerTell::Error(match action {
+        convos::Moderation::Kick => convos::Error::NotInRoom,
+        _ => convos::Error::InvalidUID,
+      }))
+    }
+    Err(e) => {
+      return Some(server_error(
+        format_args!("moderate {id} in room {room}"),
+        e,
+      ))
+    }
+  }
+
+  let tell = || ServerTell::Moderated {
+    room,
+    action,
+    by: uid,
+    reason: reason.clone(),
+  };
+
+  let told: Vec<_> = sessions
+    .read()
+    .unwrap()
+    .of_user(id)
+    .map(|(_, session)| session.handle.to_connection.clone())
+    .collect();
+  for to in told {
+    let _ = to.send(tell()).await;
+  }
+
+  Some(ServerTell::Success(convos::Success::Moderated))
+}
+
 async fn startup_tasks() {}
 
 async fn inner_main() {
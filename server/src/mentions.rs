@@ -0,0 +1,150 @@
+use std::sync::RwLock;
+
+use convos::{Mention, ServerTell, Target};
+use sqlx::{pool::PoolConnection, Connection, Postgres, Row};
+
+use crate::{connection::Uid, policy, rooms, sessions::Sessions};
+
+// the most mentions handed over in answer to a single Mentions question
+const MENTIONS_LIMIT: i64 = 50;
+
+// like history batches, stop once the batch encodes to well short of the frame size
+const MENTIONS_BUDGET: usize = 32 * 1024;
+
+// everyone a message mentions by name who is able to see it, other than its author
+async fn resolve(
+  db: &mut PoolConnection<Postgres>,
+  author: Uid,
+  target: Target,
+  content: &str,
+) -> sqlx::Result<Vec<Uid>> {
+  // looked up the same way WhoIsName looks names up
+  let names: Vec<String> = convos::mentions(content)
+    .into_iter()
+    .map(policy::normalize)
+    .collect();
+  if names.is_empty() {
+    return Ok(vec![]);
+  }
+
+  let (room, recipient) = match target {
+    Target::Room(room) => (Some(room as i64), None),
+    Target::Direct(to) => (None, Some(to as i64)),
+  };
+
+  Ok(
+    sqlx::query(
+      "select u.uid from users u
+        where u.name = any($1) and u.uid != $2
+          and ($3::bigint is null
+            or exists (select 1 from room_members where room=$3 and uid=u.uid))
+          and ($4::bigint is null or u.uid = $4)",
+    )
+    .bind(&names)
+    .bind(author as i64)
+    .bind(room)
+    .bind(recipient)
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(|row| row.get::<i64, _>("uid") as Uid)
+    .collect(),
+  )
+}
+
+// tells everyone mentioned in a freshly sent message about it,
+// keeping the mention for later for anyone who isn't connected
+pub async fn notify(
+  db: &mut PoolConnection<Postgres>,
+  sessions: &RwLock<Sessions>,
+  id: u64,
+  author: Uid,
+  name: &str,
+  target: Target,
+  content: &str,
+) -> sqlx::Result<()> {
+  for uid in resolve(db, author, target, content).await? {
+    // the mentioned user sees a dm as coming from the author
+    let target = match target {
+      Target::Direct(_) => Target::Direct(author),
+      target => target,
+    };
+
+    // the author is never mentioned, so there is no connection of theirs to leave out
+    let recipients = rooms::reach(sessions, vec![(uid, target)], 0);
+
+    sqlx::query("insert into mentions values ($1, $2, $3) on conflict do nothing")
+      .bind(id as i64)
+      .bind(uid as i64)
+      .bind(!recipients.is_empty())
+      .execute(&mut *db)
+      .await?;
+
+    rooms::deliver(recipients, |target| ServerTell::Mentioned {
+      mention: Mention {
+        id,
+        target,
+        from: author,
+        name: name.to_owned(),
+        content: content.to_owned(),
+      },
+    })
+    .await;
+  }
+
+  Ok(())
+}
+
+// the mentions of `uid` nobody has been told about yet, oldest first,
+// which count as told once they are returned
+pub async fn undelivered(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+) -> sqlx::Result<Vec<Mention>> {
+  let mut tx = db.begin().await?;
+
+  let rows = sqlx::query(
+    "select m.id, m.author, u.name, m.room, m.content
+      from mentions n join messages m on m.id = n.message join users u on u.uid = m.author
+      where n.uid=$1 and not n.delivered and m.deleted_at is null
+      order by m.sent_at
+      limit $2",
+  )
+  .bind(uid as i64)
+  .bind(MENTIONS_LIMIT)
+  .fetch_all(&mut tx)
+  .await?;
+
+  let mentions = rows
+    .iter()
+    .map(|row| {
+      let from = row.get::<i64, _>("author") as u64;
+      Mention {
+        id: row.get::<i64, _>("id") as u64,
+        target: match row.get::<Option<i64>, _>("room") {
+          Some(room) => Target::Room(room as u64),
+          None => Target::Direct(from),
+        },
+        from,
+        name: row.get("name"),
+        content: row.get("content"),
+      }
+    })
+    .collect::<Vec<Mention>>();
+
+  // never none of them, or one too big for a batch would hold up every one after it
+  let mut mentions = convos::within_budget(mentions, MENTIONS_BUDGET);
+  if let Some(first) = mentions.first_mut() {
+    convos::shorten_to_budget(first, MENTIONS_BUDGET, |mention| &mut mention.content);
+  }
+
+  let ids: Vec<i64> = mentions.iter().map(|mention| mention.id as i64).collect();
+  sqlx::query("update mentions set delivered = true where uid=$1 and message = any($2)")
+    .bind(uid as i64)
+    .bind(&ids)
+    .execute(&mut tx)
+    .await?;
+
+  tx.commit().await?;
+  Ok(mentions)
+}
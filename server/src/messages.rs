@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+
+use convos::{Message, Reaction, Target};
+use sqlx::{pool::PoolConnection, postgres::PgRow, Connection, Postgres, Row};
+
+use crate::{attachments, connection::Uid, ids, moderation, rooms};
+
+// the most messages a single history batch will carry
+const HISTORY_LIMIT: i64 = 50;
+
+// frames cap out at 64KiB, so stop filling a history batch once it encodes to about half that,
+// which leaves plenty for whatever the batch is wrapped in
+const HISTORY_BUDGET: usize = 32 * 1024;
+
+// the most different emoji a single message can be reacted with
+const MAX_REACTIONS: i64 = 20;
+
+// the most replies a thread will come back with, the budget above applies too
+const THREAD_LIMIT: i64 = 200;
+
+// everything a Message is made from, for selecting from messages as m joined with users as u
+const MESSAGE_COLUMNS: &str = "m.id, m.author, u.name, m.content,
+  m.edited_at is not null as edited, m.deleted_at is not null as deleted,
+  m.reply_to, m.reply_count, extract(epoch from m.last_reply_at)::bigint as last_reply_at";
+
+// a message as it is stored, for deciding who may change it
+pub struct StoredMessage {
+  pub author: Uid,
+  // as the author sees it
+  pub target: Target,
+  pub deleted: bool,
+  // the root of the thread it is a reply in
+  pub reply_to: Option<u64>,
+}
+
+impl StoredMessage {
+  // where the message is, as `uid` sees it
+  pub fn target_for(&self, uid: Uid) -> Target {
+    match self.target {
+      Target::Direct(_) if uid != self.author => Target::Direct(self.author),
+      target => target,
+    }
+  }
+}
+
+pub async fn store(
+  db: &mut PoolConnection<Postgres>,
+  author: Uid,
+  target: Target,
+  content: &str,
+  reply_to: Option<u64>,
+  attachments: &[u64],
+) -> sqlx::Result<u64> {
+  let (room, recipient) = match target {
+    Target::Room(room) => (Some(room as i64), None),
+    Target::Direct(to) => (None, Some(to as i64)),
+  };
+
+  let mut tx = db.begin().await?;
+
+  let id = ids::next() as i64;
+  sqlx::query(
+    "insert into messages (id, author, room, recipient, content, reply_to)
+      values ($1, $2, $3, $4, $5, $6)",
+  )
+  .bind(id)
+  .bind(author as i64)
+  .bind(room)
+  .bind(recipient)
+  .bind(content)
+  .bind(reply_to.map(|root| root as i64))
+  .execute(&mut tx)
+  .await?;
+
+  for attachment in attachments {
+    sqlx::query("insert into message_attachments values ($1, $2) on conflict do nothing")
+      .bind(id)
+      .bind(*attachment as i64)
+      .execute(&mut tx)
+      .await?;
+  }
+
+  if let Some(root) = reply_to {
+    sqlx::query(
+      "update messages set reply_count = reply_count + 1, last_reply_at = now() where id=$1",
+    )
+    .bind(root as i64)
+    .execute(&mut tx)
+    .await?;
+  }
+
+  tx.commit().await?;
+  Ok(id as u64)
+}
+
+pub async fn fetch(
+  db: &mut PoolConnection<Postgres>,
+  id: u64,
+) -> sqlx::Result<Option<StoredMessage>> {
+  let Some(row) = sqlx::query(
+    "select author, room, recipient, deleted_at is not null as deleted, reply_to
+      from messages where id=$1",
+  )
+  .bind(id as i64)
+  .fetch_optional(db)
+  .await?
+  else {
+    return Ok(None);
+  };
+
+  let target = match row.get::<Option<i64>, _>("room") {
+    Some(room) => Target::Room(room as u64),
+    None => Target::Direct(row.get::<i64, _>("recipient") as u64),
+  };
+
+  Ok(Some(StoredMessage {
+    author: row.get::<i64, _>("author") as Uid,
+    target,
+    deleted: row.get("deleted"),
+    reply_to: row
+      .get::<Option<i64>, _>("reply_to")
+      .map(|root| root as u64),
+  }))
+}
+
+// replaces a message's content, keeping what it said before in the edit history
+pub async fn edit(db: &mut PoolConnection<Postgres>, id: u64, content: &str) -> sqlx::Result<()> {
+  let mut tx = db.begin().await?;
+
+  sqlx::query(
+    "insert into message_edits (message, content) select id, content from messages where id=$1",
+  )
+  .bind(id as i64)
+  .execute(&mut tx)
+  .await?;
+
+  sqlx::query("update messages set content=$2, edited_at=now() where id=$1")
+    .bind(id as i64)
+    .bind(content)
+    .execute(&mut tx)
+    .await?;
+
+  tx.commit().await
+}
+
+// blanks a message out, its last content goes into the edit history like any other edit,
+// and into the moderation log along with it when someone other than its author deletes it
+pub async fn delete(
+  db: &mut PoolConnection<Postgres>,
+  id: u64,
+  moderator: Option<Uid>,
+) -> sqlx::Result<()> {
+  let mut tx = db.begin().await?;
+
+  sqlx::query(
+    "insert into message_edits (message, content) select id, content from messages where id=$1",
+  )
+  .bind(id as i64)
+  .execute(&mut tx)
+  .await?;
+
+  // only the first time, deleting it again mustn't take another reply off its thread
+  let deleted = sqlx::query(
+    "update messages set content='', deleted_at=now() where id=$1 and deleted_at is null
+      returning reply_to, room, author",
+  )
+  .bind(id as i64)
+  .fetch_optional(&mut tx)
+  .await?;
+
+  if let (Some(actor), Some(row)) = (moderator, &deleted) {
+    moderation::record(
+      &mut tx,
+      actor,
+      "delete_message",
+      row.get::<Option<i64>, _>("room").map(|room| room as u64),
+      Some(row.get::<i64, _>("author") as Uid),
+      Some(id),
+      None,
+    )
+    .await?;
+  }
+
+  // a deleted reply no longer counts towards its thread, nor as its latest reply
+  if let Some(root) = deleted.and_then(|row| row.get::<Option<i64>, _>("reply_to")) {
+    sqlx::query(
+      "update messages set reply_count = greatest(reply_count - 1, 0),
+        last_reply_at = (select max(sent_at) from messages where reply_to=$1 and deleted_at is null)
+        where id=$1",
+    )
+    .bind(root)
+    .execute(&mut tx)
+    .await?;
+  }
+
+  tx.commit().await
+}
+
+// whether `uid` is allowed to see a message at all
+pub async fn can_see(db: &mut PoolConnection<Postgres>, uid: Uid, message: &StoredMessage) -> bool {
+  match message.target {
+    Target::Room(room) => rooms::is_member(db, uid, room).await,
+    Target::Direct(to) => uid == message.author || uid == to,
+  }
+}
+
+// the newest messages in `target` as `uid` sees it, older than `before` if given,
+// returned oldest first
+pub async fn history(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  target: Target,
+  before: Option<u64>,
+) -> sqlx::Result<Vec<Message>> {
+  let (room, other) = match target {
+    Target::Room(room) => (Some(room as i64), None),
+    Target::Direct(other) => (None, Some(other as i64)),
+  };
+
+  let rows = sqlx::query(&format!(
+    "select {MESSAGE_COLUMNS}
+      from messages m join users u on u.uid = m.author
+      where (m.room = $1
+          or (m.author = $2 and m.recipient = $3)
+          or (m.author = $3 and m.recipient = $2))
+        and m.reply_to is null
+        and ($4::bigint is null or m.sent_at < (select sent_at from messages where id = $4))
+      order by m.sent_at desc
+      limit $5"
+  ))
+  .bind(room)
+  .bind(uid as i64)
+  .bind(other)
+  .bind(before.map(|before| before as i64))
+  .bind(HISTORY_LIMIT)
+  .fetch_all(&mut *db)
+  .await?;
+
+  let mut messages: Vec<Message> = rows.iter().map(message_from_row).collect();
+  add_details(db, uid, &mut messages).await?;
+
+  let mut messages = within_budget(messages);
+  messages.reverse();
+  Ok(messages)
+}
+
+// a thread root and its replies, oldest first
+// the caller checks `uid` can see the root, and that it is a root at all
+pub async fn thread(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  root: u64,
+) -> sqlx::Result<(Message, Vec<Message>)> {
+  let row = sqlx::query(&format!(
+    "select {MESSAGE_COLUMNS} from messages m join users u on u.uid = m.author where m.id=$1"
+  ))
+  .bind(root as i64)
+  .fetch_one(&mut *db)
+  .await?;
+
+  let rows = sqlx::query(&format!(
+    "select {MESSAGE_COLUMNS}
+      from messages m join users u on u.uid = m.author
+      where m.reply_to=$1
+      order by m.sent_at
+      limit $2"
+  ))
+  .bind(root as i64)
+  .bind(THREAD_LIMIT)
+  .fetch_all(&mut *db)
+  .await?;
+
+  let mut messages = vec![message_from_row(&row)];
+  messages.extend(rows.iter().map(message_from_row));
+  add_details(db, uid, &mut messages).await?;
+
+  // the root always makes it in, it comes first
+  let mut messages = within_budget(messages);
+  let root = messages.remove(0);
+  Ok((root, messages))
+}
+
+fn message_from_row(row: &PgRow) -> Message {
+  Message {
+    id: row.get::<i64, _>("id") as u64,
+    from: row.get::<i64, _>("author") as u64,
+    name: row.get("name"),
+    content: row.get("content"),
+    edited: row.get("edited"),
+    deleted: row.get("deleted"),
+    reactions: vec![],
+    reply_to: row
+      .get::<Option<i64>, _>("reply_to")
+      .map(|root| root as u64),
+    replies: row.get::<i32, _>("reply_count") as u64,
+    last_reply_at: row
+      .get::<Option<i64>, _>("last_reply_at")
+      .map(|at| at as u64),
+    attachments: vec![],
+  }
+}
+
+// as many of the messages as fit in a frame, in the order given, and never none of them,
+// a first message too big for a frame of its own goes out with the end of it cut off
+fn within_budget(messages: Vec<Message>) -> Vec<Message> {
+  let mut messages = convos::within_budget(messages, HISTORY_BUDGET);
+  if let Some(first) = messages.first_mut() {
+    convos::shorten_to_budget(first, HISTORY_BUDGET, |message| &mut message.content);
+  }
+  messages
+}
+
+// reactions and attachments live in tables of their own, this fills them in
+async fn add_details(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  messages: &mut [Message],
+) -> sqlx::Result<()> {
+  let ids: Vec<u64> = messages.iter().map(|message| message.id).collect();
+  let mut reactions = reactions(db, uid, &ids).await?;
+  let mut attachments = attachments::of_messages(db, &ids).await?;
+  for message in messages {
+    message.reactions = reactions.remove(&message.id).unwrap_or_default();
+    message.attachments = attachments.remove(&message.id).unwrap_or_default();
+  }
+
+  Ok(())
+}
+
+// the reactions on each of the given messages, as `uid` sees them
+async fn reactions(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  ids: &[u64],
+) -> sqlx::Result<HashMap<u64, Vec<Reaction>>> {
+  let ids: Vec<i64> = ids.iter().map(|id| *id as i64).collect();
+
+  let rows = sqlx::query(
+    "select message, emoji, count(*) as count, bool_or(uid = $2) as mine
+      from reactions where message = any($1)
+      group by message, emoji order by min(emoji)",
+  )
+  .bind(&ids)
+  .bind(uid as i64)
+  .fetch_all(db)
+  .await?;
+
+  let mut reactions: HashMap<u64, Vec<Reaction>> = HashMap::new();
+  for row in rows {
+    reactions
+      .entry(row.get::<i64, _>("message") as u64)
+      .or_default()
+      .push(Reaction {
+        emoji: row.get("emoji"),
+        count: row.get::<i64, _>("count") as u64,
+        mine: row.get("mine"),
+      });
+  }
+
+  Ok(reactions)
+}
+
+// adds or removes one user's reaction, returning false if that changed nothing
+// whether anything changed, or None if the message already has as many different emoji
+// on it as it may, and this would be a new one
+pub async fn set_reaction(
+  db: &mut PoolConnection<Postgres>,
+  id: u64,
+  uid: Uid,
+  emoji: &str,
+  reacted: bool,
+) -> sqlx::Result<Option<bool>> {
+  if !reacted {
+    let result = sqlx::query("delete from reactions where message=$1 and uid=$2 and emoji=$3")
+      .bind(id as i64)
+      .bind(uid as i64)
+      .bind(emoji)
+      .execute(db)
+      .await?;
+
+    return Ok(Some(result.rows_affected() != 0));
+  }
+
+  let mut tx = db.begin().await?;
+
+  // reactions to the same message wait on each other, so two new emoji can't both squeeze in
+  sqlx::query("select 1 from messages where id=$1 for update")
+    .bind(id as i64)
+    .execute(&mut tx)
+    .await?;
+
+  let row = sqlx::query(
+    "select exists (select 1 from reactions where message=$1 and emoji=$2) as known,
+      (select count(distinct emoji) from reactions where message=$1) as emoji",
+  )
+  .bind(id as i64)
+  .bind(emoji)
+  .fetch_one(&mut tx)
+  .await?;
+
+  if !row.get::<bool, _>("known") && row.get::<i64, _>("emoji") >= MAX_REACTIONS {
+    return Ok(None);
+  }
+
+  let result = sqlx::query("insert into reactions values ($1, $2, $3) on conflict do nothing")
+    .bind(id as i64)
+    .bind(uid as i64)
+    .bind(emoji)
+    .execute(&mut tx)
+    .await?;
+
+  tx.commit().await?;
+  Ok(Some(result.rows_affected() != 0))
+}
+
+// everyone who reacted to a message with a given emoji
+pub async fn reactors(
+  db: &mut PoolConnection<Postgres>,
+  id: u64,
+  emoji: &str,
+) -> sqlx::Result<Vec<Uid>> {
+  Ok(
+    sqlx::query("select uid from reactions where message=$1 and emoji=$2")
+      .bind(id as i64)
+      .bind(emoji)
+      .fetch_all(db)
+      .await?
+      .iter()
+      .map(|row| row.get::<i64, _>("uid") as Uid)
+      .collect(),
+  )
+}
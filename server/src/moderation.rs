@@ -0,0 +1,202 @@
+use convos::{AuditEntry, Moderation};
+use sqlx::{pool::PoolConnection, Connection, PgConnection, Postgres, Row};
+
+use crate::connection::Uid;
+
+// how many log entries AuditLog returns
+const LOG_LIMIT: i64 = 100;
+
+// and like history batches, no more than encode to well short of a frame
+const LOG_BUDGET: usize = 32 * 1024;
+
+fn action_name(action: Moderation) -> &'static str {
+  match action {
+    Moderation::Kick => "kick",
+    Moderation::Ban { .. } => "ban",
+    Moderation::Unban => "unban",
+    Moderation::Mute { .. } => "mute",
+    Moderation::Unmute => "unmute",
+  }
+}
+
+// writes an action to the moderation log, `action` is whatever name fits it
+pub async fn record(
+  db: &mut PgConnection,
+  actor: Uid,
+  action: &str,
+  room: Option<u64>,
+  target: Option<Uid>,
+  message: Option<u64>,
+  reason: Option<&str>,
+) -> sqlx::Result<()> {
+  sqlx::query(
+    "insert into moderation_log (actor, action, room, target, message, reason)
+      values ($1, $2, $3, $4, $5, $6)",
+  )
+  .bind(actor as i64)
+  .bind(action)
+  .bind(room.map(|room| room as i64))
+  .bind(target.map(|target| target as i64))
+  .bind(message.map(|message| message as i64))
+  .bind(reason)
+  .execute(db)
+  .await?;
+
+  Ok(())
+}
+
+// does `action` to `target` in `room` and logs it, all or nothing
+// returns false if there was nothing to do, like kicking someone who is not in the room
+pub async fn apply(
+  db: &mut PoolConnection<Postgres>,
+  actor: Uid,
+  room: u64,
+  target: Uid,
+  action: Moderation,
+  reason: Option<&str>,
+) -> sqlx::Result<bool> {
+  let mut tx = db.begin().await?;
+
+  // durations are capped to something postgres can still add to now()
+  let until = |duration: Option<u64>| duration.map(|secs| secs.min(i32::MAX as u64) as f64);
+
+  let changed = match action {
+    Moderation::Kick => sqlx::query("delete from room_members where room=$1 and uid=$2")
+      .bind(room as i64)
+      .bind(target as i64)
+      .execute(&mut tx)
+      .await?
+      .rows_affected(),
+
+    Moderation::Ban { duration } => {
+      sqlx::query(
+        "insert into room_bans values ($1, $2, now() + make_interval(secs => $3))
+          on conflict (room, uid) do update set expires_at = excluded.expires_at",
+      )
+      .bind(room as i64)
+      .bind(target as i64)
+      .bind(until(duration))
+      .execute(&mut tx)
+      .await?;
+
+      sqlx::query("delete from room_members where room=$1 and uid=$2")
+        .bind(room as i64)
+        .bind(target as i64)
+        .execute(&mut tx)
+        .await?;
+
+      1
+    }
+
+    Moderation::Mute { duration } => sqlx::query(
+      "insert into room_mutes values ($1, $2, now() + make_interval(secs => $3))
+        on conflict (room, uid) do update set expires_at = excluded.expires_at",
+    )
+    .bind(room as i64)
+    .bind(target as i64)
+    .bind(until(duration))
+    .execute(&mut tx)
+    .await?
+    .rows_affected(),
+
+    Moderation::Unban => sqlx::query("delete from room_bans where room=$1 and uid=$2")
+      .bind(room as i64)
+      .bind(target as i64)
+      .execute(&mut tx)
+      .await?
+      .rows_affected(),
+
+    Moderation::Unmute => sqlx::query("delete from room_mutes where room=$1 and uid=$2")
+      .bind(room as i64)
+      .bind(target as i64)
+      .execute(&mut tx)
+      .await?
+      .rows_affected(),
+  };
+
+  if changed == 0 {
+    return Ok(false);
+  }
+
+  record(
+    &mut tx,
+    actor,
+    action_name(action),
+    Some(room),
+    Some(target),
+    None,
+    reason,
+  )
+  .await?;
+
+  tx.commit().await?;
+  Ok(true)
+}
+
+pub async fn is_banned(db: &mut PoolConnection<Postgres>, uid: Uid, room: u64) -> bool {
+  sqlx::query(
+    "select 1 from room_bans
+      where room=$1 and uid=$2 and (expires_at is null or expires_at > now())",
+  )
+  .bind(room as i64)
+  .bind(uid as i64)
+  .fetch_optional(db)
+  .await
+  .is_ok_and(|row| row.is_some())
+}
+
+pub async fn is_muted(db: &mut PoolConnection<Postgres>, uid: Uid, room: u64) -> bool {
+  sqlx::query(
+    "select 1 from room_mutes
+      where room=$1 and uid=$2 and (expires_at is null or expires_at > now())",
+  )
+  .bind(room as i64)
+  .bind(uid as i64)
+  .fetch_optional(db)
+  .await
+  .is_ok_and(|row| row.is_some())
+}
+
+// the newest entries of the moderation log, of one room or of everything
+pub async fn log(
+  db: &mut PoolConnection<Postgres>,
+  room: Option<u64>,
+) -> sqlx::Result<Vec<AuditEntry>> {
+  let entries = sqlx::query(
+    "select actor, action, room, target, message, reason,
+          extract(epoch from at)::bigint as at
+        from moderation_log
+        where $1::bigint is null or room = $1
+        order by id desc
+        limit $2",
+  )
+  .bind(room.map(|room| room as i64))
+  .bind(LOG_LIMIT)
+  .fetch_all(db)
+  .await?
+  .iter()
+  .map(|row| AuditEntry {
+    actor: row.get::<i64, _>("actor") as u64,
+    action: row.get("action"),
+    room: row.get::<Option<i64>, _>("room").map(|room| room as u64),
+    target: row
+      .get::<Option<i64>, _>("target")
+      .map(|target| target as u64),
+    message: row
+      .get::<Option<i64>, _>("message")
+      .map(|message| message as u64),
+    reason: row.get("reason"),
+    at: row.get::<i64, _>("at") as u64,
+  })
+  .collect::<Vec<_>>();
+
+  // reasons from before they were capped can be long enough to need cutting short
+  let mut entries = convos::within_budget(entries, LOG_BUDGET);
+  if let Some(first) = entries.first_mut() {
+    convos::shorten_to_budget(first, LOG_BUDGET, |entry| {
+      entry.reason.get_or_insert_with(String::new)
+    });
+  }
+
+  Ok(entries)
+}
@@ -0,0 +1,297 @@
+use std::{collections::HashSet, env, path::PathBuf};
+
+use convos::{PasswordProblem, UsernameProblem};
+use directories::ProjectDirs;
+use sqlx::{pool::PoolConnection, PgPool, Postgres, Row};
+use tokio::fs;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::confusable_detection::skeleton;
+
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 32;
+
+// anything that reads as the server itself, as everyone, or as a guest
+const RESERVED_NAMES: &[&str] = &[
+  "admin",
+  "administrator",
+  "anonymous",
+  "everyone",
+  "guest",
+  "here",
+  "mod",
+  "moderator",
+  "root",
+  "server",
+  "system",
+];
+
+const DEFAULT_MIN_PASSWORD_LENGTH: usize = 8;
+// hashing is cheap, but not that cheap
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+// the form every username is stored and looked up in,
+// so that fullwidth and other compatibility forms can't pose as another name
+pub fn normalize(username: &str) -> String {
+  username.nfkc().collect()
+}
+
+// what a name looks like, two names with the same skeleton are too easily mistaken for each other
+pub fn skeleton_of(username: &str) -> String {
+  skeleton(&username.to_lowercase()).collect()
+}
+
+fn is_name_char(c: char) -> bool {
+  c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+// checks an already normalized username, everything but whether it collides with another account
+pub fn check_username(username: &str) -> Result<(), UsernameProblem> {
+  let length = username.chars().count();
+  if length < MIN_USERNAME_LENGTH {
+    return Err(UsernameProblem::TooShort {
+      min: MIN_USERNAME_LENGTH as u64,
+    });
+  }
+  if length > MAX_USERNAME_LENGTH {
+    return Err(UsernameProblem::TooLong {
+      max: MAX_USERNAME_LENGTH as u64,
+    });
+  }
+
+  if let Some(character) = username.chars().find(|c| !is_name_char(*c)) {
+    return Err(UsernameProblem::InvalidCharacter { character });
+  }
+
+  // a name has to survive being @mentioned at the end of a sentence
+  let edges = [username.chars().next(), username.chars().last()];
+  if !edges.into_iter().flatten().all(char::is_alphanumeric) {
+    return Err(UsernameProblem::BadEdge);
+  }
+
+  let skeleton = skeleton_of(username);
+  let reserved = RESERVED_NAMES
+    .iter()
+    .any(|name| skeleton_of(name) == skeleton);
+  // guests get names like guest-1234 handed out to them
+  let guest = username
+    .to_lowercase()
+    .strip_prefix("guest-")
+    .is_some_and(|rest| rest.chars().all(|c| c.is_ascii_digit()));
+  if reserved || guest {
+    return Err(UsernameProblem::Reserved);
+  }
+
+  Ok(())
+}
+
+// the name of whichever account already goes by something that looks like `username`
+pub async fn lookalike(
+  db: &mut PoolConnection<Postgres>,
+  username: &str,
+) -> sqlx::Result<Option<String>> {
+  Ok(
+    sqlx::query("select name from users where skeleton=$1")
+      .bind(skeleton_of(username))
+      .fetch_optional(db)
+      .await?
+      .map(|row| row.get("name")),
+  )
+}
+
+// accounts from before skeletons were kept have theirs worked out here
+pub async fn backfill_skeletons(db: &PgPool) -> sqlx::Result<()> {
+  let users = sqlx::query("select uid, name from users where skeleton is null and not guest")
+    .fetch_all(db)
+    .await?;
+
+  for user in users {
+    let name: String = user.get("name");
+    let result = sqlx::query("update users set skeleton=$1 where uid=$2")
+      .bind(skeleton_of(&name))
+      .bind(user.get::<i64, _>("uid"))
+      .execute(db)
+      .await;
+
+    // lookalikes from before skeletons were checked go without, only one of them can have it
+    match result {
+      Err(e) if crate::accounts::violated_constraint(&e) == Some("users_skeleton") => {}
+      result => {
+        result?;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+#[derive(Default)]
+pub struct PasswordPolicy {
+  min_length: usize,
+  // lowercased, so case games don't get around it
+  breached: HashSet<String>,
+}
+
+impl PasswordPolicy {
+  // YACS2_MIN_PASSWORD_LENGTH sets the minimum length, and the breached password list,
+  // one password per line, is read from YACS2_BREACHED_PASSWORDS
+  // or breached-passwords.txt in the config directory
+  pub async fn load() -> Self {
+    let min_length = env::var("YACS2_MIN_PASSWORD_LENGTH")
+      .ok()
+      .and_then(|min| min.parse().ok())
+      .unwrap_or(DEFAULT_MIN_PASSWORD_LENGTH);
+
+    let path = env::var_os("YACS2_BREACHED_PASSWORDS")
+      .map(PathBuf::from)
+      .or_else(|| {
+        ProjectDirs::from("", "", "yacs2")
+          .map(|dirs| dirs.config_dir().join("breached-passwords.txt"))
+      })
+      .unwrap_or_else(|| PathBuf::from("breached-passwords.txt"));
+
+    let breached = match fs::read_to_string(&path).await {
+      Ok(list) => list
+        .lines()
+        .map(|password| password.trim().to_lowercase())
+        .filter(|password| !password.is_empty())
+        .collect(),
+      Err(e) => {
+        eprintln!(
+          "Not checking for breached passwords, could not read {}: {e}",
+          path.display()
+        );
+        HashSet::new()
+      }
+    };
+
+    Self {
+      min_length,
+      breached,
+    }
+  }
+
+  pub fn check(&self, username: &str, password: &str) -> Result<(), PasswordProblem> {
+    let length = password.chars().count();
+    if length < self.min_length {
+      return Err(PasswordProblem::TooShort {
+        min: self.min_length as u64,
+      });
+    }
+    if length > MAX_PASSWORD_LENGTH {
+      return Err(PasswordProblem::TooLong {
+        max: MAX_PASSWORD_LENGTH as u64,
+      });
+    }
+
+    let lowercase = password.to_lowercase();
+    if lowercase == username.to_lowercase() {
+      return Err(PasswordProblem::SameAsUsername);
+    }
+    if self.breached.contains(&lowercase) {
+      return Err(PasswordProblem::Breached);
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn accepts_ordinary_names() {
+    for name in [
+      "bob",
+      "alice_b",
+      "x-ray.9",
+      "Zoë",
+      "名前です",
+      &"a".repeat(32),
+    ] {
+      assert!(check_username(name).is_ok(), "{name}");
+    }
+  }
+
+  #[test]
+  fn length_counts_characters() {
+    assert!(matches!(
+      check_username("ab"),
+      Err(UsernameProblem::TooShort { min: 3 })
+    ));
+    assert!(matches!(
+      check_username(&"a".repeat(33)),
+      Err(UsernameProblem::TooLong { max: 32 })
+    ));
+    // six bytes, but three characters
+    assert!(check_username("ééé").is_ok());
+  }
+
+  #[test]
+  fn rejects_characters_outside_names() {
+    for (name, bad) in [
+      ("al ice", ' '),
+      ("al@ice", '@'),
+      ("bob\n", '\n'),
+      ("a:b", ':'),
+    ] {
+      assert!(
+        matches!(
+          check_username(name),
+          Err(UsernameProblem::InvalidCharacter { character }) if character == bad
+        ),
+        "{name:?}"
+      );
+    }
+  }
+
+  #[test]
+  fn names_start_and_end_with_a_letter_or_digit() {
+    for name in ["_alice", "alice.", "-bob-", ".x."] {
+      assert!(
+        matches!(check_username(name), Err(UsernameProblem::BadEdge)),
+        "{name}"
+      );
+    }
+  }
+
+  #[test]
+  fn rejects_reserved_names_and_their_lookalikes() {
+    // the first a of the last one is cyrillic
+    for name in ["admin", "Admin", "EVERYONE", "\u{430}dmin"] {
+      assert!(
+        matches!(check_username(name), Err(UsernameProblem::Reserved)),
+        "{name}"
+      );
+    }
+  }
+
+  #[test]
+  fn reserves_guest_names() {
+    for name in ["guest", "guest-1", "Guest-1234"] {
+      assert!(
+        matches!(check_username(name), Err(UsernameProblem::Reserved)),
+        "{name}"
+      );
+    }
+    assert!(check_username("guest-star").is_ok());
+    assert!(check_username("guests").is_ok());
+  }
+
+  #[test]
+  fn skeletons_ignore_case_and_confusables() {
+    assert_eq!(skeleton_of("Alice"), skeleton_of("alice"));
+    // cyrillic a and o
+    assert_eq!(skeleton_of("p\u{430}yp\u{430}l"), skeleton_of("paypal"));
+    assert_eq!(skeleton_of("g\u{43e}\u{43e}gle"), skeleton_of("google"));
+    assert_eq!(skeleton_of("rnoney"), skeleton_of("money"));
+    assert_ne!(skeleton_of("alice"), skeleton_of("alicia"));
+  }
+
+  #[test]
+  fn normalizes_compatibility_forms() {
+    assert_eq!(normalize("ａｌｉｃｅ"), "alice");
+    assert_eq!(normalize("ﬁsh"), "fish");
+    assert_eq!(normalize("bob"), "bob");
+  }
+}
@@ -0,0 +1,83 @@
+use std::collections::{HashMap, HashSet};
+
+use convos::{Presence, ServerTell};
+use tokio::sync::mpsc::Sender;
+
+use crate::connection::{ConID, Uid};
+
+// connections that want to hear about other users' presence changing
+#[derive(Default)]
+pub struct PresenceWatchers {
+  watchers: HashMap<Uid, HashMap<ConID, Sender<ServerTell>>>,
+  // the reverse of watchers, so they can be cleaned up when a connection goes away
+  watching: HashMap<ConID, HashSet<Uid>>,
+}
+
+// a user's presence changed, and who needs to be told
+pub struct PresenceChange {
+  id: Uid,
+  presence: Presence,
+  watchers: Vec<Sender<ServerTell>>,
+}
+
+impl PresenceChange {
+  pub async fn deliver(self) {
+    for watcher in self.watchers {
+      // the watcher may have disconnected in the meantime, that's fine
+      let _ = watcher
+        .send(ServerTell::Presence {
+          id: self.id,
+          presence: self.presence,
+        })
+        .await;
+    }
+  }
+}
+
+impl PresenceWatchers {
+  pub fn watch(&mut self, uid: Uid, con_id: ConID, to_connection: Sender<ServerTell>) {
+    self
+      .watchers
+      .entry(uid)
+      .or_default()
+      .insert(con_id, to_connection);
+    self.watching.entry(con_id).or_default().insert(uid);
+  }
+
+  pub fn unwatch(&mut self, uid: Uid, con_id: ConID) {
+    if let Some(watchers) = self.watchers.get_mut(&uid) {
+      watchers.remove(&con_id);
+      if watchers.is_empty() {
+        self.watchers.remove(&uid);
+      }
+    }
+
+    if let Some(watching) = self.watching.get_mut(&con_id) {
+      watching.remove(&uid);
+    }
+  }
+
+  // stop everything a connection was watching
+  pub fn disconnected(&mut self, con_id: ConID) {
+    for uid in self.watching.remove(&con_id).unwrap_or_default() {
+      self.unwatch(uid, con_id);
+    }
+  }
+
+  // reports a change in presence, if there was one
+  pub fn changed(&self, id: Uid, before: Presence, presence: Presence) -> Option<PresenceChange> {
+    if before == presence {
+      return None;
+    }
+
+    Some(PresenceChange {
+      id,
+      presence,
+      watchers: self
+        .watchers
+        .get(&id)
+        .map(|watchers| watchers.values().cloned().collect())
+        .unwrap_or_default(),
+    })
+  }
+}
@@ -0,0 +1,162 @@
+use convos::{DirectoryEntry, Profile, ProfileChange, DIRECTORY_LIMIT};
+use sqlx::{pool::PoolConnection, postgres::PgRow, Connection, Postgres, Row};
+
+use crate::{attachments, connection::Uid, policy};
+
+const COLUMNS: &str = "uid, name, guest, display_name, bio, pronouns, status, avatar,
+  extract(epoch from created_at)::bigint as created_at";
+
+// a user's uid and name, and their profile unless they're a guest
+fn who_from_row(row: &PgRow) -> (Uid, String, Option<Profile>) {
+  let profile = (!row.get::<bool, _>("guest")).then(|| Profile {
+    display_name: row.get("display_name"),
+    bio: row.get("bio"),
+    pronouns: row.get("pronouns"),
+    status: row.get("status"),
+    avatar: row.get::<Option<i64>, _>("avatar").map(|id| id as u64),
+    created_at: row.get::<i64, _>("created_at") as u64,
+  });
+
+  (row.get::<i64, _>("uid") as Uid, row.get("name"), profile)
+}
+
+pub async fn by_id(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+) -> sqlx::Result<Option<(Uid, String, Option<Profile>)>> {
+  Ok(
+    sqlx::query(&format!("select {COLUMNS} from users where uid=$1"))
+      .bind(uid as i64)
+      .fetch_optional(db)
+      .await?
+      .as_ref()
+      .map(who_from_row),
+  )
+}
+
+pub async fn by_name(
+  db: &mut PoolConnection<Postgres>,
+  name: &str,
+) -> sqlx::Result<Option<(Uid, String, Option<Profile>)>> {
+  Ok(
+    sqlx::query(&format!("select {COLUMNS} from users where name=$1"))
+      .bind(name)
+      .fetch_optional(db)
+      .await?
+      .as_ref()
+      .map(who_from_row),
+  )
+}
+
+// applies every change or none of them, blank text clears a field the same as None does
+pub async fn update(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  changes: Vec<ProfileChange>,
+) -> Result<(), convos::Error> {
+  for change in &changes {
+    match change {
+      ProfileChange::DisplayName(Some(text))
+      | ProfileChange::Bio(Some(text))
+      | ProfileChange::Pronouns(Some(text))
+      | ProfileChange::Status(Some(text)) => {
+        let max = change.max_len().unwrap_or(usize::MAX);
+        if text.trim().chars().count() > max {
+          return Err(convos::Error::ProfileTooLong {
+            field: change.field().to_owned(),
+            max: max as u64,
+          });
+        }
+      }
+      ProfileChange::Avatar(Some(id)) => match attachments::owned(db, uid, &[*id]).await {
+        Ok(owned) if !owned.is_empty() => {}
+        Ok(_) => return Err(convos::Error::InvalidAttachment),
+        Err(e) => {
+          eprintln!("Ran into error when trying to fetch attachment {id}: {e}");
+          return Err(convos::Error::ServerError);
+        }
+      },
+      _ => {}
+    }
+  }
+
+  let updated = async {
+    let mut tx = db.begin().await?;
+
+    for change in changes {
+      let (column, text) = match change {
+        ProfileChange::DisplayName(text) => ("display_name", text),
+        ProfileChange::Bio(text) => ("bio", text),
+        ProfileChange::Pronouns(text) => ("pronouns", text),
+        ProfileChange::Status(text) => ("status", text),
+        ProfileChange::Avatar(avatar) => {
+          sqlx::query("update users set avatar=$2 where uid=$1")
+            .bind(uid as i64)
+            .bind(avatar.map(|id| id as i64))
+            .execute(&mut tx)
+            .await?;
+          continue;
+        }
+      };
+
+      let text = text
+        .map(|text| text.trim().to_owned())
+        .filter(|text| !text.is_empty());
+      sqlx::query(&format!("update users set {column}=$2 where uid=$1"))
+        .bind(uid as i64)
+        .bind(text)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await
+  }
+  .await;
+
+  updated.map_err(|e| {
+    eprintln!("Ran into error when trying to update the profile of {uid}: {e}");
+    convos::Error::ServerError
+  })
+}
+
+// accounts whose usernames start with `query`, shortest first,
+// and if fuzzy, then the ones that look most like it
+pub async fn directory(
+  db: &mut PoolConnection<Postgres>,
+  query: &str,
+  fuzzy: bool,
+) -> sqlx::Result<Vec<DirectoryEntry>> {
+  let query = policy::normalize(query.trim());
+  if query.is_empty() {
+    return Ok(vec![]);
+  }
+
+  // usernames can have underscores in them, which like would take as a wildcard
+  let prefix = query
+    .replace('\\', "\\\\")
+    .replace('%', "\\%")
+    .replace('_', "\\_")
+    + "%";
+
+  Ok(
+    sqlx::query(
+      "select uid, name, display_name from users
+        where not guest and uid <> 0 and (name ilike $1 or ($3 and name % $2))
+        order by name ilike $1 desc, similarity(name, $2) desc, length(name), name
+        limit $4",
+    )
+    .bind(&prefix)
+    .bind(&query)
+    .bind(fuzzy)
+    .bind(DIRECTORY_LIMIT as i64)
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(|row| DirectoryEntry {
+      id: row.get::<i64, _>("uid") as u64,
+      name: row.get("name"),
+      display_name: row.get("display_name"),
+    })
+    .collect(),
+  )
+}
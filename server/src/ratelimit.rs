@@ -0,0 +1,508 @@
+use std::{
+  collections::HashMap,
+  env,
+  net::IpAddr,
+  time::{Duration, Instant},
+};
+
+use convos::ClientQuestion;
+
+use crate::connection::{ConID, Uid};
+
+// what a question draws on, each has its own budget so that
+// flooding one kind of question doesn't starve the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+  // anything that says something to other people or changes what they see
+  Chat,
+  // anything that only reads
+  Lookup,
+  // signing up, signing in and anything else that checks a password
+  Auth,
+  // upload and download chunks, which come in quick succession by design
+  Transfer,
+}
+
+impl Kind {
+  pub fn of(question: &ClientQuestion) -> Self {
+    match question {
+      ClientQuestion::SignUp { .. }
+      | ClientQuestion::SignIn { .. }
+      | ClientQuestion::Recover { .. }
+      | ClientQuestion::SecondFactor { .. }
+      | ClientQuestion::Upgrade { .. }
+      | ClientQuestion::ChangePassword { .. }
+      | ClientQuestion::DeleteAccount { .. }
+      | ClientQuestion::NewRecoveryCodes { .. }
+      | ClientQuestion::EnableTwoFactor { .. }
+      | ClientQuestion::ConfirmTwoFactor { .. }
+      | ClientQuestion::DisableTwoFactor { .. } => Kind::Auth,
+
+      ClientQuestion::JoinRoom { .. }
+      | ClientQuestion::LeaveRoom { .. }
+      | ClientQuestion::Say { .. }
+      | ClientQuestion::EditMessage { .. }
+      | ClientQuestion::DeleteMessage { .. }
+      | ClientQuestion::React { .. }
+      | ClientQuestion::Unreact { .. }
+      | ClientQuestion::Moderate { .. }
+      | ClientQuestion::SetRole { .. }
+      | ClientQuestion::Rename { .. }
+      | ClientQuestion::UpdateProfile { .. }
+      | ClientQuestion::CreateInvite { .. }
+      | ClientQuestion::AllowGuests { .. }
+      | ClientQuestion::KickSession { .. }
+      | ClientQuestion::BeginUpload { .. } => Kind::Chat,
+
+      ClientQuestion::UploadChunk { .. }
+      | ClientQuestion::FinishUpload { .. }
+      | ClientQuestion::Download { .. } => Kind::Transfer,
+
+      // typing has its own, stricter limit on top of this one
+      ClientQuestion::WhoIsID { .. }
+      | ClientQuestion::WhoIsName { .. }
+      | ClientQuestion::WhoAmI
+      | ClientQuestion::Directory { .. }
+      | ClientQuestion::NumConnected
+      | ClientQuestion::PresenceOf { .. }
+      | ClientQuestion::WatchPresence { .. }
+      | ClientQuestion::UnwatchPresence { .. }
+      | ClientQuestion::SetAway { .. }
+      | ClientQuestion::FailedSignIns
+      | ClientQuestion::ListSessions
+      | ClientQuestion::History { .. }
+      | ClientQuestion::Thread { .. }
+      | ClientQuestion::Mentions
+      | ClientQuestion::MarkRead { .. }
+      | ClientQuestion::Unread
+      | ClientQuestion::Search { .. }
+      | ClientQuestion::AuditLog { .. }
+      | ClientQuestion::TypingStarted { .. }
+      | ClientQuestion::TypingStopped { .. } => Kind::Lookup,
+    }
+  }
+}
+
+// a token bucket, holding up to `burst` questions and refilling `per_second` of them
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+  pub burst: f64,
+  pub per_second: f64,
+}
+
+impl Budget {
+  const fn new(burst: f64, per_second: f64) -> Self {
+    Self { burst, per_second }
+  }
+
+  // replaced by the one in the environment variable `name`, if it is set,
+  // written as the burst and the rate per second, like 10/1
+  fn load(&mut self, name: &str) {
+    let Ok(value) = env::var(name) else {
+      return;
+    };
+
+    let budget = value
+      .split_once('/')
+      .and_then(|(burst, per_second)| {
+        Some(Budget::new(
+          burst.trim().parse().ok()?,
+          per_second.trim().parse().ok()?,
+        ))
+      })
+      // a budget that can't hold a single question or never refills would shut everyone out
+      .filter(|budget| budget.burst >= 1.0 && budget.per_second > 0.0);
+
+    match budget {
+      Some(budget) => *self = budget,
+      None => panic!("{name} is {value}, which isn't a burst and a rate per second like 10/1"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Budgets {
+  pub chat: Budget,
+  pub lookup: Budget,
+  pub auth: Budget,
+  pub transfer: Budget,
+}
+
+impl Budgets {
+  fn of(&self, kind: Kind) -> Budget {
+    match kind {
+      Kind::Chat => self.chat,
+      Kind::Lookup => self.lookup,
+      Kind::Auth => self.auth,
+      Kind::Transfer => self.transfer,
+    }
+  }
+}
+
+// a question has to fit the budgets of its connection, its user and its address all at once
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+  pub connection: Budgets,
+  pub user: Budgets,
+  // generous, a whole household or office can sit behind one address
+  pub address: Budgets,
+  // every question turned away costs its address a strike, and once an address is out
+  // of strikes its connections are dropped, reconnecting doesn't start it over
+  pub strikes: Budget,
+}
+
+impl Default for RateLimits {
+  fn default() -> Self {
+    Self {
+      connection: Budgets {
+        chat: Budget::new(10.0, 1.0),
+        lookup: Budget::new(30.0, 5.0),
+        auth: Budget::new(5.0, 0.2),
+        transfer: Budget::new(128.0, 64.0),
+      },
+      user: Budgets {
+        chat: Budget::new(20.0, 2.0),
+        lookup: Budget::new(60.0, 10.0),
+        auth: Budget::new(5.0, 0.2),
+        transfer: Budget::new(256.0, 128.0),
+      },
+      address: Budgets {
+        chat: Budget::new(40.0, 4.0),
+        lookup: Budget::new(120.0, 20.0),
+        auth: Budget::new(10.0, 0.5),
+        transfer: Budget::new(512.0, 256.0),
+      },
+      strikes: Budget::new(20.0, 0.2),
+    }
+  }
+}
+
+impl RateLimits {
+  // the defaults, with any budget overridden by YACS2_RATE_<SCOPE>_<KIND>,
+  // like YACS2_RATE_USER_CHAT=20/2, and the strikes by YACS2_RATE_STRIKES
+  pub fn load() -> Self {
+    let mut limits = Self::default();
+
+    for (scope, budgets) in [
+      ("CONNECTION", &mut limits.connection),
+      ("USER", &mut limits.user),
+      ("ADDRESS", &mut limits.address),
+    ] {
+      for (kind, budget) in [
+        ("CHAT", &mut budgets.chat),
+        ("LOOKUP", &mut budgets.lookup),
+        ("AUTH", &mut budgets.auth),
+        ("TRANSFER", &mut budgets.transfer),
+      ] {
+        budget.load(&format!("YACS2_RATE_{scope}_{kind}"));
+      }
+    }
+    limits.strikes.load("YACS2_RATE_STRIKES");
+
+    limits
+  }
+
+  fn budget(&self, scope: Scope, kind: Kind) -> Budget {
+    match scope {
+      Scope::Connection(_) => self.connection.of(kind),
+      Scope::User(_) => self.user.of(kind),
+      Scope::Address(_) => self.address.of(kind),
+    }
+  }
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+impl Bucket {
+  fn full(budget: Budget, now: Instant) -> Self {
+    Self {
+      tokens: budget.burst,
+      updated: now,
+    }
+  }
+
+  fn refill(&mut self, budget: Budget, now: Instant) {
+    let elapsed = now.duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * budget.per_second).min(budget.burst);
+    self.updated = now;
+  }
+
+  // how long until there is a whole token to take
+  fn wait(&self, budget: Budget) -> Duration {
+    Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / budget.per_second)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Scope {
+  Connection(ConID),
+  User(Uid),
+  Address(IpAddr),
+}
+
+pub enum Verdict {
+  Allowed,
+  // turned away, worth asking again after the given time
+  Limited(Duration),
+  // turned away too often, the connection should be dropped
+  Disconnect,
+}
+
+// only ever touched by the server loop, so nothing in here is shared
+pub struct RateLimiter {
+  limits: RateLimits,
+  buckets: HashMap<(Scope, Kind), Bucket>,
+  strikes: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+  pub fn new(limits: RateLimits) -> Self {
+    Self {
+      limits,
+      buckets: HashMap::new(),
+      strikes: HashMap::new(),
+    }
+  }
+
+  // takes a token from every bucket the question falls under, or none at all if any is empty
+  // `uid` is 0 while anonymous, which only counts against the connection and address
+  pub fn check(&mut self, con_id: ConID, uid: Uid, address: IpAddr, kind: Kind) -> Verdict {
+    self.check_at(con_id, uid, address, kind, Instant::now())
+  }
+
+  fn check_at(
+    &mut self,
+    con_id: ConID,
+    uid: Uid,
+    address: IpAddr,
+    kind: Kind,
+    now: Instant,
+  ) -> Verdict {
+    let mut scopes = vec![Scope::Connection(con_id), Scope::Address(address)];
+    if uid != 0 {
+      scopes.push(Scope::User(uid));
+    }
+
+    let mut wait = Duration::ZERO;
+    for &scope in &scopes {
+      let budget = self.limits.budget(scope, kind);
+      let bucket = self
+        .buckets
+        .entry((scope, kind))
+        .or_insert_with(|| Bucket::full(budget, now));
+      bucket.refill(budget, now);
+      wait = wait.max(bucket.wait(budget));
+    }
+
+    if wait.is_zero() {
+      for scope in scopes {
+        if let Some(bucket) = self.buckets.get_mut(&(scope, kind)) {
+          bucket.tokens -= 1.0;
+        }
+      }
+      return Verdict::Allowed;
+    }
+
+    let budget = self.limits.strikes;
+    let strikes = self
+      .strikes
+      .entry(address)
+      .or_insert_with(|| Bucket::full(budget, now));
+    strikes.refill(budget, now);
+    if strikes.tokens < 1.0 {
+      return Verdict::Disconnect;
+    }
+    strikes.tokens -= 1.0;
+
+    Verdict::Limited(wait)
+  }
+
+  // the address keeps its strikes, those only run out with time
+  pub fn disconnected(&mut self, con_id: ConID) {
+    self
+      .buckets
+      .retain(|(scope, _), _| *scope != Scope::Connection(con_id));
+  }
+
+  // forgets every bucket that has filled back up, it would be made full again anyway
+  pub fn prune(&mut self) {
+    self.prune_at(Instant::now())
+  }
+
+  fn prune_at(&mut self, now: Instant) {
+    let limits = self.limits;
+
+    self.buckets.retain(|&(scope, kind), bucket| {
+      let budget = limits.budget(scope, kind);
+      bucket.refill(budget, now);
+      bucket.tokens < budget.burst
+    });
+    self.strikes.retain(|_, strikes| {
+      strikes.refill(limits.strikes, now);
+      strikes.tokens < limits.strikes.burst
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const HOME: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+  const AWAY: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+  fn budgets(budget: Budget) -> Budgets {
+    Budgets {
+      chat: budget,
+      lookup: budget,
+      auth: budget,
+      transfer: budget,
+    }
+  }
+
+  // 3 questions at once and one a second, for every connection, user and address,
+  // and 2 strikes, one back every 10 seconds
+  fn limiter() -> RateLimiter {
+    let budget = budgets(Budget::new(3.0, 1.0));
+    RateLimiter::new(RateLimits {
+      connection: budget,
+      user: budget,
+      address: budget,
+      strikes: Budget::new(2.0, 0.1),
+    })
+  }
+
+  fn secs(secs: f64) -> Duration {
+    Duration::from_secs_f64(secs)
+  }
+
+  #[test]
+  fn allows_a_burst_then_limits() {
+    let mut limiter = limiter();
+    let now = Instant::now();
+
+    for _ in 0..3 {
+      let verdict = limiter.check_at(1, 0, HOME, Kind::Chat, now);
+      assert!(matches!(verdict, Verdict::Allowed));
+    }
+    match limiter.check_at(1, 0, HOME, Kind::Chat, now) {
+      Verdict::Limited(wait) => assert_eq!(wait, secs(1.0)),
+      _ => panic!("a fourth question at once should be limited"),
+    }
+
+    // the other kinds have budgets of their own
+    let verdict = limiter.check_at(1, 0, HOME, Kind::Lookup, now);
+    assert!(matches!(verdict, Verdict::Allowed));
+  }
+
+  #[test]
+  fn refills_with_time_up_to_the_burst() {
+    let mut limiter = limiter();
+    let now = Instant::now();
+
+    for _ in 0..3 {
+      limiter.check_at(1, 0, HOME, Kind::Chat, now);
+    }
+    match limiter.check_at(1, 0, HOME, Kind::Chat, now + secs(0.5)) {
+      Verdict::Limited(wait) => assert_eq!(wait, secs(0.5)),
+      _ => panic!("half a token is not enough"),
+    }
+    let verdict = limiter.check_at(1, 0, HOME, Kind::Chat, now + secs(1.0));
+    assert!(matches!(verdict, Verdict::Allowed));
+
+    // a long quiet spell only ever fills the bucket back up to the burst
+    let later = now + secs(60.0);
+    for _ in 0..3 {
+      let verdict = limiter.check_at(1, 0, HOME, Kind::Chat, later);
+      assert!(matches!(verdict, Verdict::Allowed));
+    }
+    let verdict = limiter.check_at(1, 0, HOME, Kind::Chat, later);
+    assert!(matches!(verdict, Verdict::Limited(_)));
+  }
+
+  #[test]
+  fn a_question_needs_every_budget() {
+    let mut limiter = limiter();
+    let now = Instant::now();
+
+    // the user's budget is spent across two connections
+    for con_id in [1, 1, 2] {
+      let verdict = limiter.check_at(con_id, 7, HOME, Kind::Chat, now);
+      assert!(matches!(verdict, Verdict::Allowed));
+    }
+    let verdict = limiter.check_at(3, 7, AWAY, Kind::Chat, now);
+    assert!(matches!(verdict, Verdict::Limited(_)));
+
+    // while somebody else on another address is unaffected
+    let verdict = limiter.check_at(3, 8, AWAY, Kind::Chat, now);
+    assert!(matches!(verdict, Verdict::Allowed));
+  }
+
+  #[test]
+  fn strikes_escalate_to_a_disconnect() {
+    let mut limiter = limiter();
+    let now = Instant::now();
+
+    for _ in 0..3 {
+      limiter.check_at(1, 0, HOME, Kind::Chat, now);
+    }
+    for _ in 0..2 {
+      let verdict = limiter.check_at(1, 0, HOME, Kind::Chat, now);
+      assert!(matches!(verdict, Verdict::Limited(_)));
+    }
+    let verdict = limiter.check_at(1, 0, HOME, Kind::Chat, now);
+    assert!(matches!(verdict, Verdict::Disconnect));
+  }
+
+  #[test]
+  fn strikes_outlive_the_connection() {
+    let mut limiter = limiter();
+    let now = Instant::now();
+
+    for _ in 0..6 {
+      limiter.check_at(1, 0, HOME, Kind::Chat, now);
+    }
+    limiter.disconnected(1);
+
+    // a new connection from the same address still shares its address budget,
+    // and is dropped again on its first question turned away
+    let verdict = limiter.check_at(2, 0, HOME, Kind::Chat, now);
+    assert!(matches!(verdict, Verdict::Disconnect));
+
+    // another address has strikes of its own
+    for _ in 0..3 {
+      limiter.check_at(3, 0, AWAY, Kind::Chat, now);
+    }
+    let verdict = limiter.check_at(3, 0, AWAY, Kind::Chat, now);
+    assert!(matches!(verdict, Verdict::Limited(_)));
+  }
+
+  #[test]
+  fn strikes_come_back_with_time() {
+    let mut limiter = limiter();
+    let now = Instant::now();
+
+    for _ in 0..6 {
+      limiter.check_at(1, 0, HOME, Kind::Chat, now);
+    }
+
+    // one strike back after 10 seconds, but the budget is full again by then too
+    let later = now + secs(10.0);
+    for _ in 0..3 {
+      let verdict = limiter.check_at(1, 0, HOME, Kind::Chat, later);
+      assert!(matches!(verdict, Verdict::Allowed));
+    }
+    let verdict = limiter.check_at(1, 0, HOME, Kind::Chat, later);
+    assert!(matches!(verdict, Verdict::Limited(_)));
+    let verdict = limiter.check_at(1, 0, HOME, Kind::Chat, later);
+    assert!(matches!(verdict, Verdict::Disconnect));
+
+    // and all of them once they have filled back up, which pruning then forgets
+    limiter.prune_at(now + secs(60.0));
+    assert!(limiter.strikes.is_empty());
+    assert!(limiter.buckets.is_empty());
+  }
+}
@@ -0,0 +1,103 @@
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha512};
+use sqlx::{pool::PoolConnection, Connection, Postgres};
+
+use crate::{accounts, connection::Uid};
+
+// how many codes an account gets at a time, each one works once
+const CODES: usize = 10;
+
+// characters per half of a code, written as two halves with a dash between
+const HALF: usize = 5;
+
+// codes are random enough that they need no salt, and the lockout covers guessing
+fn hash_code(code: &str) -> Vec<u8> {
+  let code: String = code
+    .chars()
+    .filter(char::is_ascii_alphanumeric)
+    .map(|c| c.to_ascii_lowercase())
+    .collect();
+
+  Sha512::digest(code).to_vec()
+}
+
+fn new_code() -> String {
+  let mut rng = rand::thread_rng();
+  let mut half = || {
+    (&mut rng)
+      .sample_iter(&Alphanumeric)
+      .take(HALF)
+      .map(|c| char::from(c).to_ascii_lowercase())
+      .collect::<String>()
+  };
+
+  format!("{}-{}", half(), half())
+}
+
+// replaces whatever codes an account had with a fresh set, handing back the only plain copy
+pub async fn generate(db: &mut PoolConnection<Postgres>, uid: Uid) -> sqlx::Result<Vec<String>> {
+  let codes: Vec<String> = (0..CODES).map(|_| new_code()).collect();
+
+  let mut tx = db.begin().await?;
+  sqlx::query("delete from recovery_codes where uid=$1")
+    .bind(uid as i64)
+    .execute(&mut tx)
+    .await?;
+
+  for code in &codes {
+    sqlx::query("insert into recovery_codes (uid, hash) values ($1, $2) on conflict do nothing")
+      .bind(uid as i64)
+      .bind(hash_code(code))
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  Ok(codes)
+}
+
+// uses up one of an account's codes in place of a second factor
+pub async fn consume(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  code: &str,
+) -> sqlx::Result<bool> {
+  let consumed = sqlx::query("delete from recovery_codes where uid=$1 and hash=$2")
+    .bind(uid as i64)
+    .bind(hash_code(code))
+    .execute(db)
+    .await?;
+
+  Ok(consumed.rows_affected() == 1)
+}
+
+// uses up one of an account's codes to give it a new password,
+// false if it had no such code (anymore), in which case the password stays as it was
+pub async fn redeem(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  code: &str,
+  password: &str,
+) -> sqlx::Result<bool> {
+  let mut tx = db.begin().await?;
+
+  let redeemed = sqlx::query("delete from recovery_codes where uid=$1 and hash=$2")
+    .bind(uid as i64)
+    .bind(hash_code(code))
+    .execute(&mut tx)
+    .await?;
+  if redeemed.rows_affected() != 1 {
+    return Ok(false);
+  }
+
+  let salt = accounts::new_salt();
+  sqlx::query("update users set salt=$2, hash=$3 where uid=$1")
+    .bind(uid as i64)
+    .bind(&salt)
+    .bind(accounts::hash_password(password, &salt))
+    .execute(&mut tx)
+    .await?;
+
+  tx.commit().await?;
+  Ok(true)
+}
@@ -0,0 +1,131 @@
+use convos::Permission;
+use sqlx::{pool::PoolConnection, Postgres, Row};
+
+use crate::connection::Uid;
+
+fn name(permission: Permission) -> &'static str {
+  match permission {
+    Permission::Kick => "kick",
+    Permission::Ban => "ban",
+    Permission::Mute => "mute",
+    Permission::DeleteMessages => "delete_messages",
+    Permission::ManageRoom => "manage_room",
+    Permission::Invite => "invite",
+  }
+}
+
+// whether `uid` may do something in `room`, or everywhere if no room is given
+// a server-wide role counts in every room on top of whatever role is held in the room itself
+pub async fn has_permission(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  room: Option<u64>,
+  permission: Permission,
+) -> bool {
+  sqlx::query(
+    "select 1 from roles r
+      where $3 = any(r.permissions)
+        and (r.name in (select role from server_roles where uid = $1)
+          or r.name in (select role from room_roles where uid = $1 and room = $2))",
+  )
+  .bind(uid as i64)
+  .bind(room.map(|room| room as i64))
+  .bind(name(permission))
+  .fetch_optional(db)
+  .await
+  .is_ok_and(|row| row.is_some())
+}
+
+// how far up `uid` stands in `room`, or server-wide if no room is given, 0 without any role
+// a server-wide role outranks the same role held in a room
+async fn standing(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  room: Option<u64>,
+) -> sqlx::Result<i32> {
+  Ok(
+    sqlx::query(
+      "select coalesce(max(held.rank * 2 + held.server_wide), 0) as standing from (
+        select r.rank, 1 as server_wide from server_roles s join roles r on r.name = s.role
+          where s.uid = $1
+        union all
+        select r.rank, 0 from room_roles m join roles r on r.name = m.role
+          where m.uid = $1 and m.room = $2) held",
+    )
+    .bind(uid as i64)
+    .bind(room.map(|room| room as i64))
+    .fetch_one(db)
+    .await?
+    .get("standing"),
+  )
+}
+
+// whether `actor` may give `target` `role` in `room`, or take its role away if none is given,
+// which takes outranking whoever it is and not handing out a role above its own
+pub async fn may_assign(
+  db: &mut PoolConnection<Postgres>,
+  actor: Uid,
+  target: Uid,
+  room: Option<u64>,
+  role: Option<&str>,
+) -> sqlx::Result<bool> {
+  let actor = standing(db, actor, room).await?;
+  let target = standing(db, target, room).await?;
+
+  let given = match role {
+    Some(role) => sqlx::query("select rank from roles where name=$1")
+      .bind(role)
+      .fetch_optional(&mut *db)
+      .await?
+      .map_or(0, |row| {
+        row.get::<i32, _>("rank") * 2 + room.is_none() as i32
+      }),
+    None => 0,
+  };
+
+  Ok(actor > target && actor >= given)
+}
+
+// gives `uid` a role in `room`, or server-wide, or takes it away if no role is given
+// returns false if there is no such role
+pub async fn set(
+  db: &mut PoolConnection<Postgres>,
+  room: Option<u64>,
+  uid: Uid,
+  role: Option<&str>,
+) -> sqlx::Result<bool> {
+  if let Some(role) = role {
+    let exists = sqlx::query("select 1 from roles where name=$1")
+      .bind(role)
+      .fetch_optional(&mut *db)
+      .await?
+      .is_some();
+
+    if !exists {
+      return Ok(false);
+    }
+  }
+
+  let query = match (room, role) {
+    (Some(room), Some(role)) => sqlx::query(
+      "insert into room_roles values ($1, $2, $3)
+        on conflict (room, uid) do update set role = excluded.role",
+    )
+    .bind(room as i64)
+    .bind(uid as i64)
+    .bind(role),
+    (Some(room), None) => sqlx::query("delete from room_roles where room=$1 and uid=$2")
+      .bind(room as i64)
+      .bind(uid as i64),
+    (None, Some(role)) => sqlx::query(
+      "insert into server_roles values ($1, $2)
+        on conflict (uid) do update set role = excluded.role",
+    )
+    .bind(uid as i64)
+    .bind(role),
+    (None, None) => sqlx::query("delete from server_roles where uid=$1").bind(uid as i64),
+  };
+  query.execute(db).await?;
+
+  Ok(true)
+}
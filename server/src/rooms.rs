@@ -0,0 +1,205 @@
+use std::sync::RwLock;
+
+use convos::{ServerTell, Target};
+use sqlx::{pool::PoolConnection, Postgres, Row};
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+  connection::{ConID, Uid},
+  ids, roles,
+  sessions::Sessions,
+};
+
+// somewhere a tell about a target has to go,
+// along with the target as that recipient sees it
+#[derive(Clone)]
+pub struct Recipient {
+  pub uid: Uid,
+  pub to: Sender<ServerTell>,
+  pub target: Target,
+}
+
+pub async fn deliver(recipients: Vec<Recipient>, tell: impl Fn(Target) -> ServerTell) {
+  for recipient in recipients {
+    // the recipient may have disconnected in the meantime, that's fine
+    let _ = recipient.to.send(tell(recipient.target)).await;
+  }
+}
+
+pub async fn find(db: &mut PoolConnection<Postgres>, name: &str) -> sqlx::Result<Option<u64>> {
+  Ok(
+    sqlx::query("select id from rooms where name=$1")
+      .bind(name)
+      .fetch_optional(db)
+      .await?
+      .map(|row| row.get::<i64, _>("id") as u64),
+  )
+}
+
+// joins the room with the given name, creating it first if need be,
+// whoever creates a room is its admin
+pub async fn join(db: &mut PoolConnection<Postgres>, uid: Uid, name: &str) -> sqlx::Result<u64> {
+  let created = sqlx::query("insert into rooms values ($1, $2) on conflict (name) do nothing")
+    .bind(ids::next() as i64)
+    .bind(name)
+    .execute(&mut *db)
+    .await?
+    .rows_affected()
+    != 0;
+
+  let room: i64 = sqlx::query("select id from rooms where name=$1")
+    .bind(name)
+    .fetch_one(&mut *db)
+    .await?
+    .get("id");
+
+  if created {
+    roles::set(db, Some(room as u64), uid, Some("admin")).await?;
+  }
+
+  sqlx::query("insert into room_members values ($1, $2) on conflict do nothing")
+    .bind(room)
+    .bind(uid as i64)
+    .execute(&mut *db)
+    .await?;
+
+  Ok(room as u64)
+}
+
+// false for rooms that don't exist, which guests can't create
+pub async fn allows_guests(db: &mut PoolConnection<Postgres>, name: &str) -> sqlx::Result<bool> {
+  Ok(
+    sqlx::query("select allow_guests from rooms where name=$1")
+      .bind(name)
+      .fetch_optional(db)
+      .await?
+      .is_some_and(|row| row.get("allow_guests")),
+  )
+}
+
+// returns false if there is no such room
+pub async fn allow_guests(
+  db: &mut PoolConnection<Postgres>,
+  room: u64,
+  allowed: bool,
+) -> sqlx::Result<bool> {
+  let result = sqlx::query("update rooms set allow_guests=$2 where id=$1")
+    .bind(room as i64)
+    .bind(allowed)
+    .execute(db)
+    .await?;
+
+  Ok(result.rows_affected() != 0)
+}
+
+// returns false if the user was not in the room to begin with
+pub async fn leave(db: &mut PoolConnection<Postgres>, uid: Uid, room: u64) -> sqlx::Result<bool> {
+  let result = sqlx::query("delete from room_members where room=$1 and uid=$2")
+    .bind(room as i64)
+    .bind(uid as i64)
+    .execute(db)
+    .await?;
+
+  Ok(result.rows_affected() != 0)
+}
+
+pub async fn is_member(db: &mut PoolConnection<Postgres>, uid: Uid, room: u64) -> bool {
+  sqlx::query("select 1 from room_members where room=$1 and uid=$2")
+    .bind(room as i64)
+    .bind(uid as i64)
+    .fetch_optional(db)
+    .await
+    .is_ok_and(|row| row.is_some())
+}
+
+async fn members(db: &mut PoolConnection<Postgres>, room: u64) -> sqlx::Result<Vec<Uid>> {
+  Ok(
+    sqlx::query("select uid from room_members where room=$1")
+      .bind(room as i64)
+      .fetch_all(db)
+      .await?
+      .iter()
+      .map(|row| row.get::<i64, _>("uid") as Uid)
+      .collect(),
+  )
+}
+
+// everyone who needs to hear about something `uid` said or did in `target`,
+// other than the connection that did it
+pub async fn recipients(
+  db: &mut PoolConnection<Postgres>,
+  sessions: &RwLock<Sessions>,
+  uid: Uid,
+  con_id: ConID,
+  target: Target,
+) -> Result<Vec<Recipient>, convos::Error> {
+  if let Target::Room(room) = target {
+    if !is_member(db, uid, room).await {
+      return Err(convos::Error::NotInRoom);
+    }
+  }
+
+  let audience = audience(db, uid, target).await?;
+  Ok(reach(sessions, audience, con_id))
+}
+
+// every user involved in `target` as `uid` sees it, and how each of them sees it
+// this does not check that `uid` belongs there, callers do that however suits them
+pub async fn audience(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  target: Target,
+) -> Result<Vec<(Uid, Target)>, convos::Error> {
+  match target {
+    Target::Room(room) => Ok(
+      members(db, room)
+        .await
+        .map_err(|_| convos::Error::NotInRoom)?
+        .into_iter()
+        .map(|member| (member, target))
+        .collect(),
+    ),
+
+    Target::Direct(to) => {
+      let exists = sqlx::query("select 1 from users where uid=$1")
+        .bind(to as i64)
+        .fetch_optional(&mut *db)
+        .await
+        .is_ok_and(|row| row.is_some());
+
+      if !exists {
+        return Err(convos::Error::InvalidUID);
+      }
+
+      // the other party sees it as coming from us, our other devices see it as going to them
+      let mut audience = vec![(uid, Target::Direct(to))];
+      if to != uid {
+        audience.push((to, Target::Direct(uid)));
+      }
+      Ok(audience)
+    }
+  }
+}
+
+// every connection of every user in the audience, other than `except`
+pub fn reach(
+  sessions: &RwLock<Sessions>,
+  audience: Vec<(Uid, Target)>,
+  except: ConID,
+) -> Vec<Recipient> {
+  let sessions = sessions.read().unwrap();
+
+  audience
+    .into_iter()
+    .flat_map(|(member, target)| {
+      sessions
+        .of_user(member)
+        .filter(|(id, _)| *id != except)
+        .map(move |(_, session)| Recipient {
+          uid: member,
+          to: session.handle.to_connection.clone(),
+          target,
+        })
+    })
+    .collect()
+}
@@ -0,0 +1,104 @@
+use convos::{Fragment, SearchFilter, SearchResult, Target};
+use sqlx::{pool::PoolConnection, Postgres, Row};
+
+use crate::connection::Uid;
+
+// results per page
+const PAGE_SIZE: i64 = 20;
+
+// anything longer than this is not a search, it's a paste
+pub const MAX_QUERY_LENGTH: usize = 256;
+
+// what ts_headline wraps matches in, nobody types these into a message
+const START: char = '\u{1}';
+const STOP: char = '\u{2}';
+
+// a page of the messages `uid` can see that match the query, along with whether there are more
+pub async fn search(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  query: &str,
+  filter: &SearchFilter,
+  page: u32,
+) -> sqlx::Result<(Vec<SearchResult>, bool)> {
+  let rows = sqlx::query(
+    "select m.id, m.author, u.name, m.room, m.recipient,
+        extract(epoch from m.sent_at)::bigint as sent_at,
+        ts_headline('english', m.content, q, $9) as snippet
+      from messages m join users u on u.uid = m.author,
+        websearch_to_tsquery('english', $1) q
+      where m.search @@ q and m.deleted_at is null
+        and ((m.room is not null
+            and m.room in (select room from room_members where uid = $2))
+          or (m.room is null and (m.author = $2 or m.recipient = $2)))
+        and ($3::bigint is null or m.room = $3)
+        and ($4::bigint is null or m.author = $4)
+        and ($5::bigint is null or m.sent_at >= to_timestamp($5))
+        and ($6::bigint is null or m.sent_at < to_timestamp($6))
+      order by ts_rank(m.search, q) desc, m.sent_at desc
+      limit $7 offset $8",
+  )
+  .bind(query)
+  .bind(uid as i64)
+  .bind(filter.room.map(|room| room as i64))
+  .bind(filter.author.map(|author| author as i64))
+  .bind(filter.after.map(|after| after as i64))
+  .bind(filter.before.map(|before| before as i64))
+  // one extra to find out if there is another page
+  .bind(PAGE_SIZE + 1)
+  .bind(page as i64 * PAGE_SIZE)
+  .bind(format!(
+    "StartSel=\"{START}\", StopSel=\"{STOP}\", MaxFragments=2, MinWords=5, MaxWords=20"
+  ))
+  .fetch_all(db)
+  .await?;
+
+  let more = rows.len() as i64 > PAGE_SIZE;
+  let results = rows
+    .iter()
+    .take(PAGE_SIZE as usize)
+    .map(|row| {
+      let author = row.get::<i64, _>("author") as u64;
+      let target = match row.get::<Option<i64>, _>("room") {
+        Some(room) => Target::Room(room as u64),
+        None if author == uid => Target::Direct(row.get::<i64, _>("recipient") as u64),
+        None => Target::Direct(author),
+      };
+
+      SearchResult {
+        id: row.get::<i64, _>("id") as u64,
+        target,
+        from: author,
+        name: row.get("name"),
+        sent_at: row.get::<i64, _>("sent_at") as u64,
+        snippet: fragments(row.get("snippet")),
+      }
+    })
+    .collect();
+
+  Ok((results, more))
+}
+
+// splits a headline up at the markers around each match
+fn fragments(headline: &str) -> Vec<Fragment> {
+  let mut fragments = vec![];
+
+  for (i, part) in headline.split(START).enumerate() {
+    // everything before the first start marker is plain, after that each part opens with a match
+    let (matched, rest) = match part.split_once(STOP) {
+      Some((matched, rest)) if i != 0 => (matched, rest),
+      _ => ("", part),
+    };
+
+    for (text, highlighted) in [(matched, true), (rest, false)] {
+      if !text.is_empty() {
+        fragments.push(Fragment {
+          text: text.to_owned(),
+          highlighted,
+        });
+      }
+    }
+  }
+
+  fragments
+}
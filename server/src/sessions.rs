@@ -0,0 +1,251 @@
+use std::{
+  collections::{HashMap, HashSet},
+  net::SocketAddr,
+  time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use convos::{Presence, SessionInfo};
+use rand::Rng;
+
+use crate::{
+  connection::{ConID, ConnectionHandle, Uid},
+  ids,
+  listener::Permit,
+  presence::{PresenceChange, PresenceWatchers},
+};
+
+// a single live connection, along with who is signed in on it
+pub struct Session {
+  pub handle: ConnectionHandle,
+  // a throwaway uid and name while anonymous, see `guest`
+  pub uid: Uid,
+  pub name: String,
+  // not signed in, the uid and name are only this connection's, for as long as it lasts
+  pub guest: bool,
+  pub address: SocketAddr,
+  pub connected_at: SystemTime,
+  pub away: bool,
+  // a sign-in on this connection still waiting on its second factor
+  pub challenge: Option<Challenge>,
+  // holds the connection's place under its address's cap until the session is gone
+  _permit: Permit,
+}
+
+// who got the password right, and when, so the challenge can run out
+#[derive(Clone)]
+pub struct Challenge {
+  pub uid: Uid,
+  pub name: String,
+  pub issued: Instant,
+}
+
+impl Session {
+  pub fn new(handle: ConnectionHandle, address: SocketAddr, permit: Permit) -> Self {
+    Self {
+      handle,
+      uid: 0,
+      name: String::new(),
+      guest: true,
+      address,
+      connected_at: SystemTime::now(),
+      away: false,
+      challenge: None,
+      _permit: permit,
+    }
+  }
+}
+
+// every connection to the server, indexed both by connection and by user,
+// so anything addressed to a user reaches every one of their devices
+// this is shared between the server loop and the message workers,
+// so nothing in here may await, anything to deliver is handed back to the caller
+#[derive(Default)]
+pub struct Sessions {
+  connections: HashMap<ConID, Session>,
+  by_uid: HashMap<Uid, HashSet<ConID>>,
+  pub watchers: PresenceWatchers,
+}
+
+impl Sessions {
+  pub fn get(&self, con_id: ConID) -> Option<&Session> {
+    self.connections.get(&con_id)
+  }
+
+  pub fn get_mut(&mut self, con_id: ConID) -> Option<&mut Session> {
+    self.connections.get_mut(&con_id)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Session> {
+    self.connections.values()
+  }
+
+  // every connection starts out as a guest
+  pub fn insert(&mut self, con_id: ConID, session: Session) {
+    self.connections.insert(con_id, session);
+    self.make_guest(con_id);
+  }
+
+  // gives a connection a guest uid and a guest name nobody connected has
+  fn make_guest(&mut self, con_id: ConID) {
+    let mut rng = rand::thread_rng();
+    // four digits while they go around, more once there are that many guests
+    let mut digits: u32 = 4;
+    let name = loop {
+      let taken = |name: &str| self.connections.values().any(|s| s.name == name);
+      let width = digits as usize;
+      let found = (0..8)
+        .map(|_| format!("guest-{:0width$}", rng.gen_range(0..10u64.pow(digits))))
+        .find(|name| !taken(name));
+      match found {
+        Some(name) => break name,
+        None => digits += 1,
+      }
+    };
+
+    let uid = ids::next();
+    if let Some(session) = self.connections.get_mut(&con_id) {
+      session.uid = uid;
+      session.name = name;
+      session.guest = true;
+      session.away = false;
+      self.by_uid.entry(uid).or_default().insert(con_id);
+    }
+  }
+
+  pub fn remove(&mut self, con_id: ConID) -> Option<(Session, Option<PresenceChange>)> {
+    self.watchers.disconnected(con_id);
+
+    let session = self.connections.get(&con_id)?;
+    let uid = session.uid;
+
+    let change = self.update_presence(uid, |sessions| sessions.forget(uid, con_id));
+
+    let session = self.connections.remove(&con_id)?;
+    Some((session, change))
+  }
+
+  pub fn name_of(&self, con_id: ConID) -> String {
+    self
+      .connections
+      .get(&con_id)
+      .map(|session| session.name.clone())
+      .unwrap_or_default()
+  }
+
+  pub fn num_connected(&self) -> u64 {
+    self.connections.len() as u64
+  }
+
+  // the connections a user is signed in on
+  pub fn of_user(&self, uid: Uid) -> impl Iterator<Item = (ConID, &Session)> {
+    self
+      .by_uid
+      .get(&uid)
+      .into_iter()
+      .flatten()
+      .filter_map(|con_id| Some((*con_id, self.connections.get(con_id)?)))
+  }
+
+  // closes every connection of a user but one, the same way a kick does;
+  // each read worker reports its connection closed, which cleans it up
+  pub fn kick_others(&self, uid: Uid, keep: ConID) {
+    for (_, session) in self.of_user(uid).filter(|(con_id, _)| *con_id != keep) {
+      let _ = session.handle.kill.send(());
+    }
+  }
+
+  pub fn session_infos(&self, uid: Uid, current: ConID) -> Vec<SessionInfo> {
+    self
+      .of_user(uid)
+      .map(|(con_id, session)| SessionInfo {
+        id: con_id,
+        address: session.address.to_string(),
+        connected_at: session
+          .connected_at
+          .duration_since(UNIX_EPOCH)
+          .unwrap_or_default()
+          .as_secs(),
+        away: session.away,
+        current: con_id == current,
+      })
+      .collect()
+  }
+
+  pub fn presence(&self, uid: Uid) -> Presence {
+    let mut sessions = self.of_user(uid).peekable();
+
+    if sessions.peek().is_none() {
+      Presence::Offline
+    } else if sessions.all(|(_, session)| session.away) {
+      Presence::Away
+    } else {
+      Presence::Online
+    }
+  }
+
+  // the guest the connection was is left behind, unless it is the one signing in
+  pub fn sign_in(&mut self, con_id: ConID, uid: Uid, name: String) -> Option<PresenceChange> {
+    let before = self.connections.get(&con_id)?.uid;
+    if before != uid {
+      self.forget(before, con_id);
+    }
+
+    self.update_presence(uid, |sessions| {
+      if let Some(session) = sessions.connections.get_mut(&con_id) {
+        session.uid = uid;
+        session.name = name;
+        session.guest = false;
+        session.challenge = None;
+        sessions.by_uid.entry(uid).or_default().insert(con_id);
+      }
+    })
+  }
+
+  // leaves the connection open, but as a new guest
+  pub fn sign_out(&mut self, con_id: ConID) -> Option<PresenceChange> {
+    let uid = self.connections.get(&con_id)?.uid;
+    self.watchers.disconnected(con_id);
+
+    let change = self.update_presence(uid, |sessions| sessions.forget(uid, con_id));
+    self.make_guest(con_id);
+    change
+  }
+
+  fn forget(&mut self, uid: Uid, con_id: ConID) {
+    if let Some(cons) = self.by_uid.get_mut(&uid) {
+      cons.remove(&con_id);
+      if cons.is_empty() {
+        self.by_uid.remove(&uid);
+      }
+    }
+  }
+
+  pub fn rename(&mut self, uid: Uid, name: &str) {
+    for con_id in self.by_uid.get(&uid).into_iter().flatten() {
+      if let Some(session) = self.connections.get_mut(con_id) {
+        session.name = name.to_owned();
+      }
+    }
+  }
+
+  pub fn set_away(&mut self, con_id: ConID, away: bool) -> Option<PresenceChange> {
+    let uid = self.connections.get(&con_id)?.uid;
+
+    self.update_presence(uid, |sessions| {
+      if let Some(session) = sessions.connections.get_mut(&con_id) {
+        session.away = away;
+      }
+    })
+  }
+
+  // applies a change to a user's sessions, reporting it if their presence changed
+  fn update_presence(
+    &mut self,
+    uid: Uid,
+    change: impl FnOnce(&mut Self),
+  ) -> Option<PresenceChange> {
+    let before = self.presence(uid);
+    change(self);
+    self.watchers.changed(uid, before, self.presence(uid))
+  }
+}
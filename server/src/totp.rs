@@ -0,0 +1,307 @@
+use std::{
+  env,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sqlx::{pool::PoolConnection, Postgres, Row};
+
+use crate::connection::Uid;
+
+// what authenticator apps assume when the uri doesn't say otherwise, RFC 6238 uses the same
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_LENGTH: usize = 20;
+const ISSUER: &str = "yacs2";
+
+// how many steps either side of now a code may be from, for clocks that are a little off
+const DEFAULT_SKEW: u64 = 1;
+
+#[derive(Default)]
+pub struct TotpPolicy {
+  skew: u64,
+}
+
+impl TotpPolicy {
+  // YACS2_TOTP_SKEW sets how many 30 second steps of clock skew are tolerated
+  pub fn load() -> Self {
+    let skew = env::var("YACS2_TOTP_SKEW")
+      .ok()
+      .and_then(|skew| skew.parse().ok())
+      .unwrap_or(DEFAULT_SKEW);
+
+    Self { skew }
+  }
+
+  // the step a code matches, if any, among the ones in reach of `now`
+  // (in seconds since the unix epoch) and after `last_step`
+  fn matching_step(&self, secret: &[u8], code: &str, now: u64, last_step: u64) -> Option<u64> {
+    let code: u32 = code.trim().parse().ok()?;
+    let now = now / STEP;
+
+    (now.saturating_sub(self.skew)..=now + self.skew)
+      .filter(|&step| step > last_step)
+      .find(|&step| hotp(secret, step) == code)
+  }
+
+  // checks a code against an account's confirmed secret, using up its step
+  pub async fn verify(
+    &self,
+    db: &mut PoolConnection<Postgres>,
+    uid: Uid,
+    code: &str,
+  ) -> sqlx::Result<bool> {
+    self.accept(db, uid, code, true).await
+  }
+
+  // checks the first code from a new secret, which turns it on for signing in
+  pub async fn confirm(
+    &self,
+    db: &mut PoolConnection<Postgres>,
+    uid: Uid,
+    code: &str,
+  ) -> sqlx::Result<bool> {
+    self.accept(db, uid, code, false).await
+  }
+
+  async fn accept(
+    &self,
+    db: &mut PoolConnection<Postgres>,
+    uid: Uid,
+    code: &str,
+    confirmed: bool,
+  ) -> sqlx::Result<bool> {
+    let row =
+      sqlx::query("select secret, last_step from totp_secrets where uid=$1 and confirmed=$2")
+        .bind(uid as i64)
+        .bind(confirmed)
+        .fetch_optional(&mut *db)
+        .await?;
+    let Some(row) = row else {
+      return Ok(false);
+    };
+
+    let secret: Vec<u8> = row.get("secret");
+    let last_step = row.get::<i64, _>("last_step") as u64;
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs();
+    let Some(step) = self.matching_step(&secret, code, now, last_step) else {
+      return Ok(false);
+    };
+
+    // only one of two racing sign-ins with the same code gets to use it
+    let accepted = sqlx::query(
+      "update totp_secrets set last_step=$2, confirmed=true where uid=$1 and last_step<$2",
+    )
+    .bind(uid as i64)
+    .bind(step as i64)
+    .execute(db)
+    .await?;
+
+    Ok(accepted.rows_affected() == 1)
+  }
+}
+
+// RFC 4226, which RFC 6238 is with the time step as the counter
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+  let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac takes keys of any length");
+  mac.update(&counter.to_be_bytes());
+  let hash = mac.finalize().into_bytes();
+
+  let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+  let truncated = u32::from_be_bytes([
+    hash[offset] & 0x7f,
+    hash[offset + 1],
+    hash[offset + 2],
+    hash[offset + 3],
+  ]);
+
+  truncated % 10u32.pow(DIGITS)
+}
+
+// anything but the unreserved characters is percent encoded
+fn escape(s: &str) -> String {
+  s.bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        (b as char).to_string()
+      }
+      b => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+pub async fn enabled(db: &mut PoolConnection<Postgres>, uid: Uid) -> sqlx::Result<bool> {
+  let row = sqlx::query("select 1 from totp_secrets where uid=$1 and confirmed")
+    .bind(uid as i64)
+    .fetch_optional(db)
+    .await?;
+
+  Ok(row.is_some())
+}
+
+// makes a new, unconfirmed secret for an account, replacing any earlier unconfirmed one,
+// and hands back the uri authenticator apps take it in as, None if it already has one confirmed
+pub async fn enrol(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  username: &str,
+) -> sqlx::Result<Option<String>> {
+  let secret: [u8; SECRET_LENGTH] = rand::random();
+
+  let enrolled = sqlx::query(
+    "insert into totp_secrets (uid, secret) values ($1, $2)
+      on conflict (uid) do update set secret=excluded.secret, last_step=0
+      where not totp_secrets.confirmed",
+  )
+  .bind(uid as i64)
+  .bind(&secret[..])
+  .execute(db)
+  .await?;
+
+  if enrolled.rows_affected() == 0 {
+    return Ok(None);
+  }
+
+  Ok(Some(format!(
+    "otpauth://totp/{issuer}:{name}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+    issuer = ISSUER,
+    name = escape(username),
+    secret = BASE32_NOPAD.encode(&secret),
+  )))
+}
+
+pub async fn disable(db: &mut PoolConnection<Postgres>, uid: Uid) -> sqlx::Result<()> {
+  sqlx::query("delete from totp_secrets where uid=$1")
+    .bind(uid as i64)
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // the secret both RFC 4226 and RFC 6238 use for their SHA1 test vectors
+  const SECRET: &[u8] = b"12345678901234567890";
+
+  fn code(secret: &[u8], step: u64) -> String {
+    format!("{:06}", hotp(secret, step))
+  }
+
+  #[test]
+  fn hotp_matches_rfc_4226() {
+    let expected = [
+      755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+    ];
+    for (counter, expected) in expected.into_iter().enumerate() {
+      assert_eq!(hotp(SECRET, counter as u64), expected, "counter {counter}");
+    }
+  }
+
+  #[test]
+  fn totp_matches_rfc_6238() {
+    // the last six of the eight digit codes in the RFC
+    let expected = [
+      (59, 287082),
+      (1111111109, 81804),
+      (1111111111, 50471),
+      (1234567890, 5924),
+      (2000000000, 279037),
+      (20000000000, 353130),
+    ];
+    for (time, expected) in expected {
+      assert_eq!(hotp(SECRET, time / STEP), expected, "time {time}");
+    }
+  }
+
+  #[test]
+  fn matches_the_step_of_now() {
+    let policy = TotpPolicy { skew: 0 };
+    let now = 1111111111;
+    let step = now / STEP;
+
+    assert_eq!(
+      policy.matching_step(SECRET, &code(SECRET, step), now, 0),
+      Some(step)
+    );
+    // the first and last second of the same step
+    assert_eq!(
+      policy.matching_step(SECRET, &code(SECRET, step), step * STEP, 0),
+      Some(step)
+    );
+    assert_eq!(
+      policy.matching_step(SECRET, &code(SECRET, step), step * STEP + STEP - 1, 0),
+      Some(step)
+    );
+    assert_eq!(
+      policy.matching_step(SECRET, &code(SECRET, step + 1), now, 0),
+      None
+    );
+  }
+
+  #[test]
+  fn tolerates_skew_either_side() {
+    let policy = TotpPolicy { skew: 1 };
+    let now = 1234567890;
+    let step = now / STEP;
+
+    for near in [step - 1, step, step + 1] {
+      assert_eq!(
+        policy.matching_step(SECRET, &code(SECRET, near), now, 0),
+        Some(near)
+      );
+    }
+    for far in [step - 2, step + 2] {
+      assert_eq!(
+        policy.matching_step(SECRET, &code(SECRET, far), now, 0),
+        None
+      );
+    }
+  }
+
+  #[test]
+  fn skew_stops_at_the_epoch() {
+    let policy = TotpPolicy { skew: 3 };
+    assert_eq!(
+      policy.matching_step(SECRET, &code(SECRET, 1), 0, 0),
+      Some(1)
+    );
+  }
+
+  #[test]
+  fn a_used_step_is_not_accepted_again() {
+    let policy = TotpPolicy { skew: 1 };
+    let now = 2000000000;
+    let step = now / STEP;
+
+    assert_eq!(
+      policy.matching_step(SECRET, &code(SECRET, step), now, step),
+      None
+    );
+    assert_eq!(
+      policy.matching_step(SECRET, &code(SECRET, step - 1), now, step - 1),
+      None
+    );
+    assert_eq!(
+      policy.matching_step(SECRET, &code(SECRET, step + 1), now, step),
+      Some(step + 1)
+    );
+  }
+
+  #[test]
+  fn codes_are_read_leniently_but_must_be_numbers() {
+    let policy = TotpPolicy { skew: 0 };
+    let now = 59;
+
+    assert_eq!(policy.matching_step(SECRET, " 287082\n", now, 0), Some(1));
+    assert_eq!(policy.matching_step(SECRET, "28708a", now, 0), None);
+    assert_eq!(policy.matching_step(SECRET, "", now, 0), None);
+  }
+}
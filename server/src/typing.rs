@@ -0,0 +1,97 @@
+use std::{
+  collections::HashMap,
+  time::{Duration, Instant},
+};
+
+use convos::Target;
+
+use crate::{
+  connection::{ConID, Uid},
+  rooms::Recipient,
+};
+
+// how long a typing indicator stays up without being refreshed,
+// so a client that crashes mid-sentence doesn't leave it on forever
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+// typing questions from one connection any closer together than this are dropped
+const TYPING_RATE_LIMIT: Duration = Duration::from_millis(500);
+
+struct Indicator {
+  expires: Instant,
+  // everyone who was told it started, so they can be told when it stops
+  told: Vec<Recipient>,
+}
+
+// who is typing where, keyed by the target as the typer addressed it
+// shared the same way as the sessions, so nothing in here may await
+#[derive(Default)]
+pub struct TypingTracker {
+  active: HashMap<(Target, Uid), Indicator>,
+  last_question: HashMap<ConID, Instant>,
+}
+
+impl TypingTracker {
+  // whether a connection may send another typing question yet
+  pub fn allow(&mut self, con_id: ConID) -> bool {
+    let now = Instant::now();
+
+    match self.last_question.get(&con_id) {
+      Some(last) if now.duration_since(*last) < TYPING_RATE_LIMIT => false,
+      _ => {
+        self.last_question.insert(con_id, now);
+        true
+      }
+    }
+  }
+
+  // pushes back the expiry of an indicator that is already up,
+  // returning false if there is none and it needs to be started
+  pub fn refresh(&mut self, target: Target, uid: Uid) -> bool {
+    match self.active.get_mut(&(target, uid)) {
+      Some(indicator) => {
+        indicator.expires = Instant::now() + TYPING_TIMEOUT;
+        true
+      }
+      None => false,
+    }
+  }
+
+  pub fn start(&mut self, target: Target, uid: Uid, told: Vec<Recipient>) {
+    self.active.insert(
+      (target, uid),
+      Indicator {
+        expires: Instant::now() + TYPING_TIMEOUT,
+        told,
+      },
+    );
+  }
+
+  // returns who needs to be told it stopped, if it was up at all
+  pub fn stop(&mut self, target: Target, uid: Uid) -> Option<Vec<Recipient>> {
+    self
+      .active
+      .remove(&(target, uid))
+      .map(|indicator| indicator.told)
+  }
+
+  // takes down every indicator that was not refreshed in time
+  pub fn expire(&mut self) -> Vec<(Uid, Vec<Recipient>)> {
+    let now = Instant::now();
+    let expired: Vec<_> = self
+      .active
+      .iter()
+      .filter(|(_, indicator)| indicator.expires <= now)
+      .map(|(key, _)| *key)
+      .collect();
+
+    expired
+      .into_iter()
+      .filter_map(|(target, uid)| Some((uid, self.stop(target, uid)?)))
+      .collect()
+  }
+
+  pub fn disconnected(&mut self, con_id: ConID) {
+    self.last_question.remove(&con_id);
+  }
+}
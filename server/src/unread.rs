@@ -0,0 +1,61 @@
+use convos::UnreadCount;
+use sqlx::{pool::PoolConnection, Postgres, Row};
+
+use crate::connection::Uid;
+
+// moves a member's read marker up to the given message, which has to be in the room,
+// returning false if it was already at or past it
+pub async fn mark_read(
+  db: &mut PoolConnection<Postgres>,
+  uid: Uid,
+  room: u64,
+  id: u64,
+) -> sqlx::Result<bool> {
+  let result = sqlx::query(
+    "insert into read_markers (room, uid, message) values ($1, $2, $3)
+      on conflict (room, uid) do update set message = excluded.message
+      where (select sent_at from messages where id = excluded.message)
+        > (select sent_at from messages where id = read_markers.message)",
+  )
+  .bind(room as i64)
+  .bind(uid as i64)
+  .bind(id as i64)
+  .execute(db)
+  .await?;
+
+  Ok(result.rows_affected() != 0)
+}
+
+// how far behind `uid` is in every room they are in
+pub async fn counts(db: &mut PoolConnection<Postgres>, uid: Uid) -> sqlx::Result<Vec<UnreadCount>> {
+  Ok(
+    sqlx::query(
+      "select r.id, r.name, rm.message as last_read,
+          count(m.id) as unread, count(n.message) as mentions
+        from room_members mem
+        join rooms r on r.id = mem.room
+        left join read_markers rm on rm.room = mem.room and rm.uid = mem.uid
+        left join messages m on m.room = mem.room
+          and m.author != mem.uid
+          and m.deleted_at is null
+          and (rm.message is null
+            or m.sent_at > (select sent_at from messages where id = rm.message))
+        left join mentions n on n.message = m.id and n.uid = mem.uid
+        where mem.uid = $1
+        group by r.id, r.name, rm.message
+        order by r.name",
+    )
+    .bind(uid as i64)
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(|row| UnreadCount {
+      room: row.get::<i64, _>("id") as u64,
+      name: row.get("name"),
+      last_read: row.get::<Option<i64>, _>("last_read").map(|id| id as u64),
+      unread: row.get::<i64, _>("unread") as u64,
+      mentions: row.get::<i64, _>("mentions") as u64,
+    })
+    .collect(),
+  )
+}